@@ -0,0 +1,465 @@
+extern crate mpi;
+extern crate tree;
+
+use std::collections::HashMap;
+
+use mpi::collective::SystemOperation;
+use mpi::environment::Universe;
+use mpi::request::WaitGuard;
+use mpi::topology::Color;
+use mpi::traits::*;
+
+use tree::comm::{
+    all_gather_keys, all_gatherv, all_reduce_min_max, barrier_timed, gatherv_points,
+    partition_surface_area, partition_surface_area_by, receive_fragmented, reduce_count,
+    reduce_keys_to_root, run_guarded, scan_exclusive, send_fragmented, split_by_block, TAG_LEAVES,
+    TAG_POINTS,
+};
+use tree::data::random;
+use tree::error::TreeError;
+use tree::morton::quad::{find_children as find_children2, Key2, Keys2};
+use tree::morton::{
+    encode_points, find_children, find_deepest_last_descendent, z_index, Key, Keys, Leaf, Point,
+    Points,
+};
+use tree::tree::Tree;
+
+use crate::fixtures::build_test_tree;
+
+// Test global min/max reduction over keys
+pub fn test_all_reduce_min_max(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 3;
+    let npoints: u64 = 1000;
+
+    let mut points = random(npoints);
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == 0 {
+        println!(
+            "Test global Key min/max with {} points across {} processes",
+            npoints, size
+        );
+    }
+
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+    let keys: Keys = points.iter().map(|p| p.key).collect();
+
+    let (min, max) = all_reduce_min_max(&keys, &world).unwrap();
+
+    // Test that the global extrema bound the local keys
+    let local_min = *keys.iter().min().unwrap();
+    let local_max = *keys.iter().max().unwrap();
+    assert!(min <= local_min);
+    assert!(max >= local_max);
+
+    // Test that the global extrema are attained on some process
+    let attains_min: u32 = (local_min == min) as u32;
+    let attains_max: u32 = (local_max == max) as u32;
+    let mut nmin: u32 = 0;
+    let mut nmax: u32 = 0;
+    world.all_reduce_into(&attains_min, &mut nmin, SystemOperation::sum());
+    world.all_reduce_into(&attains_max, &mut nmax, SystemOperation::sum());
+    assert!(nmin >= 1);
+    assert!(nmax >= 1);
+
+    // Test that processes without keys are ignored, and that it fails if no process has keys
+    let some: &[Key] = if rank == 0 { &keys[..] } else { &[] };
+    let mut expected = [local_min, local_max];
+    world.process_at_rank(0).broadcast_into(&mut expected[..]);
+    assert_eq!(
+        all_reduce_min_max(some, &world),
+        Ok((expected[0], expected[1]))
+    );
+    assert_eq!(
+        all_reduce_min_max(&[], &world),
+        Err(TreeError::EmptyPartition)
+    );
+}
+
+// Test splitting the communicator by coarse block ownership
+pub fn test_split_by_block(universe: &Universe) {
+    let world = universe.world();
+    let size = world.size();
+
+    let octree = build_test_tree(universe, "splitting communicator by block");
+
+    let split = split_by_block(&octree, &world).unwrap().unwrap();
+
+    // The coarse block containing the least leaf at this process.
+    let blocks = all_gather_keys(octree.coarse_blocks(), &world);
+    let min = octree.tree.values().flatten().map(|l| l.key).min().unwrap();
+    let coarse = *blocks.iter().filter(|&b| *b <= min).max().unwrap();
+
+    // Test that every process in the sub-communicator shares the same coarse block
+    let mut sub_blocks = vec![Key::default(); split.size() as usize];
+    split.all_gather_into(&coarse, &mut sub_blocks[..]);
+    for block in sub_blocks.iter() {
+        assert_eq!(*block, coarse);
+    }
+
+    // Test that every process with this coarse block is in the sub-communicator
+    let mut world_blocks = vec![Key::default(); size as usize];
+    world.all_gather_into(&coarse, &mut world_blocks[..]);
+    let nshared = world_blocks.iter().filter(|&b| *b == coarse).count();
+    assert_eq!(nshared, split.size() as usize);
+}
+
+// Test gathering a variable number of keys from each process
+pub fn test_all_gather_keys(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    if rank == 0 {
+        println!("Test variable length key gather across {} processes", size);
+    }
+
+    // Each process contributes rank + 1 keys.
+    let local: Keys = (0..(rank + 1))
+        .map(|i| Key(rank as u64, i as u64, 0, 3))
+        .collect();
+
+    let received = all_gather_keys(&local, &world);
+
+    let expected: Keys = (0..size)
+        .flat_map(|r| (0..(r + 1)).map(move |i| Key(r as u64, i as u64, 0, 3)))
+        .collect();
+
+    assert_eq!(received, expected);
+}
+
+// Test gathering quadtree keys, in rank order
+pub fn test_all_gatherv_quad_keys(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let depth: u64 = 3;
+
+    if rank == 0 {
+        println!("Test quadtree key gather across {} processes", size);
+    }
+
+    // Each process contributes the children of a distinct key, in Morton order.
+    let parent = Key2((rank as u64 % 8) * 2, 0, 2);
+    let local: Keys2 = find_children2(&parent, &depth);
+
+    let received: Keys2 = all_gatherv(&local, &world);
+
+    let expected: Keys2 = (0..size)
+        .flat_map(|r| find_children2(&Key2((r as u64 % 8) * 2, 0, 2), &depth))
+        .collect();
+
+    assert_eq!(received, expected);
+}
+
+// Test that a panic is converted into an error
+pub fn test_run_guarded(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+
+    if rank == 0 {
+        println!("Test guarded panics");
+    }
+
+    let result: Result<(), TreeError> = run_guarded(|| panic!("guarded panic"), &world);
+
+    match result {
+        Err(TreeError::Panic { rank: r, msg }) => {
+            assert_eq!(r, rank);
+            assert!(msg.contains("guarded panic"));
+        }
+        _ => panic!("Expected panic to be converted to an error"),
+    }
+
+    // Test that successful work is passed through
+    let result = run_guarded(|| Ok(rank), &world);
+    assert_eq!(result, Ok(rank));
+}
+
+// Test that initializing MPI again returns an error rather than panicking
+pub fn test_init_twice(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+
+    if rank == 0 {
+        println!("Test repeated initialization");
+    }
+
+    match tree::init() {
+        Err(TreeError::MpiInit { msg }) => assert!(msg.contains("already initialized")),
+        _ => panic!("Expected repeated initialization to return an error"),
+    }
+
+    // Test that the existing universe is still usable
+    let mut sum = 0;
+    world.all_reduce_into(&1, &mut sum, SystemOperation::sum());
+    assert_eq!(sum, world.size());
+}
+
+// Test that a barrier with nothing to wait for reports negligible time
+pub fn test_barrier_timed(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+
+    // Each process waits at a barrier on its own
+    let single = world.split_by_color(Color::with_value(rank)).unwrap();
+    assert_eq!(single.size(), 1);
+
+    let waited = barrier_timed(&single);
+    assert!(waited.as_millis() < 10);
+}
+
+// Test that tagged messages to the same process can't be received in the wrong order
+pub fn test_message_tags(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let next_rank = if rank + 1 < size { rank + 1 } else { 0 };
+    let prev_rank = if rank > 0 { rank - 1 } else { size - 1 };
+
+    let points = vec![Point::with_index(0., 0., 0., rank as usize); 3];
+
+    let leaf = Leaf {
+        key: Key(rank as u64, 0, 0, 0),
+        block: Key(0, 0, 0, 0),
+        npoints: rank as usize,
+    };
+    let leaves = vec![leaf; 2];
+
+    mpi::request::scope(|scope| {
+        // Points are sent before leaves, but received after them.
+        let previous_process = world.process_at_rank(prev_rank);
+        let _points = WaitGuard::from(previous_process.immediate_send_with_tag(
+            scope,
+            &points[..],
+            TAG_POINTS,
+        ));
+        let _leaves = WaitGuard::from(previous_process.immediate_send_with_tag(
+            scope,
+            &leaves[..],
+            TAG_LEAVES,
+        ));
+
+        let next_process = world.process_at_rank(next_rank);
+        let (received_leaves, _) = next_process.receive_vec_with_tag::<Leaf>(TAG_LEAVES);
+        let (received_points, _) = next_process.receive_vec_with_tag::<Point>(TAG_POINTS);
+
+        assert_eq!(received_leaves.len(), 2);
+        assert!(received_leaves
+            .iter()
+            .all(|l| l.npoints == next_rank as usize));
+        assert_eq!(received_points.len(), 3);
+        assert!(received_points
+            .iter()
+            .all(|p| p.global_idx == next_rank as usize));
+    });
+}
+
+// Test that a contiguous Morton partition has a smaller surface area than a random one
+pub fn test_partition_surface_area(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 3;
+
+    // Uniform tree at the maximum depth, partitioned into contiguous Morton intervals
+    let mut keys: Keys = vec![Key(0, 0, 0, 0)];
+    for _ in 0..depth {
+        keys = keys.iter().flat_map(|k| find_children(k, &depth)).collect();
+    }
+    keys.sort();
+
+    let chunk = (keys.len() + size as usize - 1) / size as usize;
+    let start = (rank as usize * chunk).min(keys.len());
+    let end = ((rank as usize + 1) * chunk).min(keys.len());
+
+    let mut tree: Tree = HashMap::new();
+    for &key in keys[start..end].iter() {
+        tree.entry(key).or_default().push(Leaf {
+            key,
+            block: key,
+            npoints: 1,
+        });
+    }
+
+    let splitters: Keys = (0..size as usize)
+        .map(|r| keys[(r * chunk).min(keys.len() - 1)])
+        .collect();
+
+    let morton = partition_surface_area(&tree, &splitters, &depth, &world);
+    let random = partition_surface_area_by(
+        &tree,
+        |k| (z_index(k, &depth).wrapping_mul(2654435761) % (size as u128)) as i32,
+        &depth,
+        &world,
+    );
+
+    if size > 1 {
+        assert!(morton < random);
+    } else {
+        assert_eq!(morton, 0);
+        assert_eq!(random, 0);
+    }
+}
+
+// Test gathering a variable number of points to the root process
+pub fn test_gatherv_points(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let root = size - 1;
+
+    // Each rank holds a different number of points, tagged by their rank
+    let local: Vec<Point> = (0..(rank + 1))
+        .map(|i| Point::with_index(rank as f64, 0., 0., i as usize))
+        .collect();
+
+    let gathered = gatherv_points(&local, &world, root);
+
+    if rank == root {
+        let gathered = gathered.unwrap();
+
+        // Test that every point is gathered
+        let total = (size * (size + 1) / 2) as usize;
+        assert_eq!(gathered.len(), total);
+
+        // Test that points follow rank order
+        assert!(gathered.windows(2).all(|w| w[0].x <= w[1].x));
+        for r in 0..size {
+            let count = gathered.iter().filter(|p| p.x == r as f64).count();
+            assert_eq!(count, (r + 1) as usize);
+        }
+    } else {
+        assert!(gathered.is_none());
+    }
+}
+
+// Test that the gathered blocktree is globally sorted, free of duplicates, and complete
+pub fn test_reduce_keys_to_root(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let root = size - 1;
+    let depth: u64 = 3;
+
+    // A uniform level 2 blocktree, dealt out in reverse so that keys arrive out of order, with
+    // each process also holding the first block of the next process.
+    let mut blocktree: Keys = find_children(&Key(0, 0, 0, 0), &depth)
+        .iter()
+        .flat_map(|block| find_children(block, &depth))
+        .collect();
+    blocktree.sort();
+    blocktree.reverse();
+
+    let nblocks = blocktree.len();
+    let start = (rank as usize) * nblocks / (size as usize);
+    let end = ((rank as usize + 1) * nblocks / (size as usize) + 1).min(nblocks);
+    let local = &blocktree[start..end];
+
+    let gathered = reduce_keys_to_root(local, &world, root);
+
+    if rank == root {
+        let blocks = gathered.unwrap();
+
+        assert!(blocks.windows(2).all(|w| w[0] < w[1]));
+
+        // Test that the blocks don't overlap, and together cover the domain
+        assert!(blocks
+            .windows(2)
+            .all(|w| find_deepest_last_descendent(&w[0], &depth) < w[1]));
+        let volume: u64 = blocks.iter().map(|b| 1 << (3 * (depth - b.3))).sum();
+        assert_eq!(volume, 1 << (3 * depth));
+    } else {
+        assert!(gathered.is_none());
+    }
+}
+
+// Test that counts beyond the range of a u32 are reduced without overflow
+pub fn test_reduce_count(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let root = 0;
+
+    // A single rank holds more leaves than fit in a u32
+    let local: u64 = if rank == root { u32::MAX as u64 + 1 } else { 0 };
+
+    let total = reduce_count(local, &world, root);
+
+    if rank == root {
+        assert_eq!(total, Some(u32::MAX as u64 + 1));
+    } else {
+        assert_eq!(total, None);
+    }
+}
+
+// Test that the exclusive scan sums the values at lower ranks only
+pub fn test_scan_exclusive(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    if rank == 0 {
+        println!("Test exclusive scan across {} processes", size);
+    }
+
+    // Each process contributes rank + 1, so the lower ranks sum to rank * (rank + 1) / 2.
+    let local = (rank + 1) as u64;
+    let expected: u64 = (0..rank).map(|r| (r + 1) as u64).sum();
+
+    assert_eq!(scan_exclusive(local, &world), expected);
+    assert_eq!(scan_exclusive(local as usize, &world), expected as usize);
+}
+
+// Test that a vector larger than the message limit round trips between two processes in fragments
+pub fn test_fragmented_round_trip(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    if rank == 0 {
+        println!("Test fragmented messages");
+    }
+
+    if size < 2 {
+        return;
+    }
+
+    // A tiny limit which doesn't divide the length, so that the last fragment is partial.
+    let max_elements = 7;
+    let points: Points = (0..1000)
+        .map(|i| Point::with_index(i as f64, 0.5, -1., i))
+        .collect();
+
+    if rank == 0 {
+        let partner = world.process_at_rank(1);
+        send_fragmented(&partner, &points[..], TAG_POINTS, max_elements);
+        let received: Points = receive_fragmented(&partner, TAG_POINTS, max_elements);
+
+        assert_eq!(received.len(), points.len());
+        assert!(received
+            .iter()
+            .zip(points.iter())
+            .all(|(r, p)| r.approx_eq(p, 0.) & (r.global_idx == p.global_idx)));
+    } else if rank == 1 {
+        let partner = world.process_at_rank(0);
+        let received: Points = receive_fragmented(&partner, TAG_POINTS, max_elements);
+        send_fragmented(&partner, &received[..], TAG_POINTS, max_elements);
+    }
+
+    // Test that an empty buffer is sent as its length alone
+    if rank == 0 {
+        let partner = world.process_at_rank(1);
+        send_fragmented::<Point, _>(&partner, &[], TAG_POINTS, max_elements);
+    } else if rank == 1 {
+        let partner = world.process_at_rank(0);
+        let received: Points = receive_fragmented(&partner, TAG_POINTS, max_elements);
+        assert!(received.is_empty());
+    }
+}
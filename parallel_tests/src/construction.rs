@@ -0,0 +1,666 @@
+extern crate mpi;
+extern crate tree;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+
+use mpi::collective::SystemOperation;
+use mpi::environment::Universe;
+use mpi::topology::{Color, Rank};
+use mpi::traits::*;
+
+use tree::comm::{gatherv, scan_exclusive};
+use tree::data::{random, read_raw_f64, read_raw_f64_chunked};
+use tree::error::TreeError;
+use tree::morton::{
+    encode_points, find_children, find_deepest_last_descendent, keys_to_leaves, Key, Keys, Leaves,
+    Point, Points,
+};
+use tree::tree::{
+    block_partition, build_streaming, complete_blocktree, linearise_parallel, unbalanced_tree,
+    unique_leaves, Octree, SortAlgorithm, Weight, Weights,
+};
+
+use crate::fixtures::{build_test_tree, build_test_tree_with, TestTree, DEPTH, NCRIT, NPOINTS};
+
+// Test that the distributed tree contains the same leaves as a sequential build
+pub fn test_distributed_matches_sequential(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let root_rank = 0;
+
+    let depth: u64 = 3;
+    let ncrit: usize = 150;
+    let npoints: u64 = 1000;
+
+    let mut points = random(npoints);
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == root_rank {
+        println!(
+            "Test distributed tree against sequential tree with {} points across {} processes",
+            npoints, size
+        );
+    }
+
+    // Gather all input points before they are redistributed by the construction.
+    let mut all_points = gatherv(&points, &world, root_rank).unwrap_or_default();
+
+    let (octree, _) = unbalanced_tree(&depth, &ncrit, &world, &mut points, x0, r0).unwrap();
+
+    let local_leaves: Leaves = octree.tree.values().flatten().cloned().collect();
+    let mut distributed = gatherv(&local_leaves, &world, root_rank).unwrap_or_default();
+
+    if rank == root_rank {
+        // Sequential build over the same points.
+        encode_points(&mut all_points, &depth, &depth, &x0, &r0);
+        let leaves = keys_to_leaves(&mut all_points);
+        let sequential = unique_leaves(leaves, &all_points, &ncrit, true).unwrap();
+
+        distributed.sort();
+
+        assert_eq!(distributed.len(), sequential.len());
+        for (d, s) in distributed.iter().zip(sequential.iter()) {
+            assert_eq!(d.key, s.key);
+            assert_eq!(d.npoints, s.npoints);
+        }
+    }
+}
+
+// Test that building from points streamed in small chunks matches building in memory
+pub fn test_build_streaming(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let root_rank = 0;
+
+    let depth: u64 = 3;
+    let ncrit: usize = 150;
+    let npoints: u64 = 1000 * (size as u64);
+    let chunk_size = 37;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+    let path = std::env::temp_dir().join("distributed_trees_streaming.raw");
+
+    if rank == root_rank {
+        println!(
+            "Test streaming build with {} points across {} processes",
+            npoints, size
+        );
+
+        let mut file = File::create(&path).unwrap();
+        for p in random(npoints).iter() {
+            for c in [p.x, p.y, p.z].iter() {
+                file.write_all(&c.to_le_bytes()).unwrap();
+            }
+        }
+    }
+    world.barrier();
+
+    let mut points = read_raw_f64(&path, &world).unwrap();
+    let (in_memory, _) = unbalanced_tree(&depth, &ncrit, &world, &mut points, x0, r0).unwrap();
+
+    let chunks = read_raw_f64_chunked(&path, chunk_size, &world).unwrap();
+    let (streamed, _) = build_streaming(chunks, &depth, &ncrit, x0, r0, &world).unwrap();
+
+    let leaves = |octree: &Octree| -> Leaves { octree.tree.values().flatten().cloned().collect() };
+    let indices =
+        |octree: &Octree| -> Vec<usize> { octree.points.iter().map(|p| p.global_idx).collect() };
+
+    let mut expected = gatherv(&leaves(&in_memory), &world, root_rank).unwrap_or_default();
+    let mut result = gatherv(&leaves(&streamed), &world, root_rank).unwrap_or_default();
+    let mut expected_indices = gatherv(&indices(&in_memory), &world, root_rank).unwrap_or_default();
+    let mut result_indices = gatherv(&indices(&streamed), &world, root_rank).unwrap_or_default();
+
+    if rank == root_rank {
+        expected.sort();
+        result.sort();
+
+        assert_eq!(result.len(), expected.len());
+        for (r, e) in result.iter().zip(expected.iter()) {
+            assert_eq!(r.key, e.key);
+            assert_eq!(r.npoints, e.npoints);
+        }
+
+        // Test that every point is retained exactly once
+        expected_indices.sort_unstable();
+        result_indices.sort_unstable();
+        assert_eq!(result_indices, expected_indices);
+        assert_eq!(result_indices.len(), npoints as usize);
+    }
+}
+
+// Test that the blocktree is complete when processes hold uneven numbers of seeds, including a
+// single seed, or none at all
+pub fn test_complete_blocktree_uneven_seeds(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let root_rank = 0;
+    let depth: u64 = 3;
+
+    if rank == root_rank {
+        println!("Test blocktree completion with uneven seeds");
+    }
+
+    // Every fifth level 2 octant is a seed, rank 1 holds one, rank 2 holds none, and later ranks
+    // hold two each, with rank 0 holding the remainder.
+    let mut global: Keys = find_children(&Key(0, 0, 0, 0), &depth)
+        .iter()
+        .flat_map(|block| find_children(block, &depth))
+        .collect();
+    global.sort();
+    let global: Keys = global.into_iter().step_by(5).collect();
+
+    let count = |r: Rank| -> usize {
+        match r {
+            1 => 1,
+            2 => 0,
+            _ => 2,
+        }
+    };
+    let rest: usize = (1..size).map(count).sum();
+    let mut counts: Vec<usize> = vec![global.len().saturating_sub(rest).max(1)];
+    counts.extend((1..size).map(count));
+
+    let start: usize = counts[..rank as usize].iter().sum();
+    let end = (start + counts[rank as usize]).min(global.len());
+    let mut seeds: Keys = global[start.min(end)..end].to_vec();
+    let local_seeds = seeds.clone();
+
+    let local = complete_blocktree(&mut seeds, &depth, rank, size, &world).unwrap();
+
+    // Test that the seeds at each later process begin its blocks, and that processes without
+    // seeds hold no blocks
+    if rank > 0 {
+        assert_eq!(local.first(), local_seeds.first());
+    }
+
+    let blocks = gatherv(&local, &world, root_rank).unwrap_or_default();
+
+    if rank == root_rank {
+        // Test that the concatenated blocks are sorted, don't overlap, and cover the domain
+        assert!(blocks.windows(2).all(|w| w[0] < w[1]));
+        assert!(blocks
+            .windows(2)
+            .all(|w| find_deepest_last_descendent(&w[0], &depth) < w[1]));
+        let volume: u64 = blocks.iter().map(|b| 1 << (3 * (depth - b.3))).sum();
+        assert_eq!(volume, 1 << (3 * depth));
+
+        assert!(global.iter().all(|seed| blocks.contains(seed)));
+    }
+}
+
+// Test that no points are lost during construction
+pub fn test_n_points_global(universe: &Universe) {
+    let world = universe.world();
+    let size = world.size();
+
+    let octree = build_test_tree(universe, "global point count");
+
+    assert_eq!(
+        octree.n_points_global(&world),
+        (NPOINTS as usize) * (size as usize)
+    );
+}
+
+// Test that trees built with histogram sort keep every point
+pub fn test_unbalanced_tree_sorted_by(universe: &Universe) {
+    let world = universe.world();
+    let size = world.size();
+
+    let setup = TestTree {
+        sort: SortAlgorithm::HistogramSort,
+        ..TestTree::default()
+    };
+    let octree = build_test_tree_with(universe, "construction with histogram sort", setup);
+
+    assert_eq!(
+        octree.n_points_global(&world),
+        (NPOINTS as usize) * (size as usize)
+    );
+}
+
+// Test that global leaf indices are contiguous, unique and follow the global Morton order
+pub fn test_global_leaf_index(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let root_rank = 0;
+
+    let octree = build_test_tree(universe, "global leaf indices");
+    let index = octree.global_leaf_index(&world);
+    assert_eq!(index.len(), octree.n_leaves());
+
+    let mut local: Vec<(Key, usize)> = index.into_iter().collect();
+    local.sort();
+    let keys: Keys = local.iter().map(|&(key, _)| key).collect();
+    let indices: Vec<usize> = local.iter().map(|&(_, i)| i).collect();
+
+    let keys = gatherv(&keys, &world, root_rank).unwrap_or_default();
+    let indices = gatherv(&indices, &world, root_rank).unwrap_or_default();
+
+    if rank == root_rank {
+        // Gathered in rank order, the indices count up from zero, and the keys are sorted
+        assert_eq!(indices, (0..indices.len()).collect::<Vec<usize>>());
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    }
+}
+
+// Test that the coarse blocktree is retained, sorted, and contains the block of every leaf
+pub fn test_coarse_blocks(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let root_rank = 0;
+
+    let octree = build_test_tree(universe, "coarse blocks");
+    let coarse: HashSet<Key> = octree.coarse_blocks().iter().copied().collect();
+
+    // Blocks are split from the coarse blocks, so are either one of them or a descendent
+    for leaf in octree.iter_leaves() {
+        assert!(
+            coarse.contains(&leaf.block)
+                | coarse
+                    .iter()
+                    .any(|c| leaf.block.is_descendant_of(c, &DEPTH))
+        );
+    }
+
+    // Gathered in rank order, the coarse blocks are sorted without duplicates
+    let blocks = gatherv(octree.coarse_blocks(), &world, root_rank).unwrap_or_default();
+    if rank == root_rank {
+        assert!(!blocks.is_empty());
+        assert!(blocks.windows(2).all(|w| w[0] < w[1]));
+    }
+}
+
+// Test that the global bounding box of the points is tighter than the domain cube
+pub fn test_points_bounding_box_global(universe: &Universe) {
+    let world = universe.world();
+
+    let points: Points = random(NPOINTS)
+        .iter()
+        .map(|p| Point::new(0.2 + 0.6 * p.x, 0.2 + 0.6 * p.y, 0.2 + 0.6 * p.z))
+        .collect();
+    let setup = TestTree {
+        points,
+        ..TestTree::default()
+    };
+    let octree = build_test_tree_with(universe, "global bounding box of points", setup);
+
+    let (lower, upper) = octree.points_bounding_box_global(&world).unwrap();
+    for c in [lower.x, lower.y, lower.z, upper.x, upper.y, upper.z].iter() {
+        assert!((0.2..=0.8).contains(c));
+    }
+
+    // Test that the global box contains the local box
+    let (local_lower, local_upper) = octree.points_bounding_box().unwrap();
+    assert!((lower.x <= local_lower.x) & (lower.y <= local_lower.y) & (lower.z <= local_lower.z));
+    assert!((local_upper.x <= upper.x) & (local_upper.y <= upper.y) & (local_upper.z <= upper.z));
+}
+
+// Test that the caller can continue to communicate after constructing a tree
+pub fn test_collectives_after_construction(universe: &Universe) {
+    let world = universe.world();
+
+    let octree = build_test_tree(universe, "collectives after construction");
+
+    world.barrier();
+
+    let mut nleaves: u64 = 0;
+    world.all_reduce_into(
+        &(octree.iter_leaves().count() as u64),
+        &mut nleaves,
+        SystemOperation::sum(),
+    );
+    assert!(nleaves > 0);
+}
+
+// Test that the Morton intervals owned by each process partition the curve
+pub fn test_morton_ranges(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let root_rank = 0;
+
+    let octree = build_test_tree(universe, "Morton ranges");
+
+    let (min, max) = octree.morton_range().unwrap();
+    assert!(min <= max);
+
+    // Test that ranges follow rank order without overlapping, skipping processes without leaves
+    // once the tree is restricted to half of the processes
+    let nactive = (size / 2).max(1);
+    let color = if rank < nactive {
+        Color::with_value(0)
+    } else {
+        Color::undefined()
+    };
+    let new_world = world.split_by_color(color);
+    let restricted = octree.restrict_to(&world, new_world.as_ref());
+
+    for tree in [&octree, &restricted].iter() {
+        if let Some(ranges) = tree.gather_morton_ranges(&world, root_rank) {
+            assert_eq!(ranges.len(), size as usize);
+
+            let owned: Vec<(Key, Key)> = ranges.iter().flatten().cloned().collect();
+            for pair in owned.windows(2) {
+                // The end of each range precedes the start of the next
+                assert!(pair[0].1 < pair[1].0);
+            }
+            for range in owned.iter() {
+                assert!(range.0 <= range.1);
+            }
+        }
+    }
+
+    // Test that processes outside of the sub-communicator own no range
+    if new_world.is_none() {
+        assert_eq!(restricted.morton_range(), None);
+    }
+}
+
+// Test that restricting a tree to half of the processes loses no leaves
+pub fn test_restrict_to(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let nactive = (size / 2).max(1);
+
+    let octree = build_test_tree(
+        universe,
+        &format!("restricting a tree to {} processes", nactive),
+    );
+
+    let color = if rank < nactive {
+        Color::with_value(0)
+    } else {
+        Color::undefined()
+    };
+    let new_world = world.split_by_color(color);
+    let restricted = octree.restrict_to(&world, new_world.as_ref());
+
+    // Test that processes outside of the sub-communicator are left empty
+    if new_world.is_none() {
+        assert!(restricted.tree.is_empty());
+        assert!(restricted.indices.is_empty());
+    }
+
+    // Test that no leaves, points or point indices are lost
+    let count = |tree: &Octree| {
+        [
+            tree.iter_leaves().count() as u64,
+            tree.n_points() as u64,
+            tree.indices.values().map(|i| i.len()).sum::<usize>() as u64,
+        ]
+    };
+    let mut before = [0u64; 3];
+    let mut after = [0u64; 3];
+    world.all_reduce_into(&count(&octree)[..], &mut before[..], SystemOperation::sum());
+    world.all_reduce_into(
+        &count(&restricted)[..],
+        &mut after[..],
+        SystemOperation::sum(),
+    );
+    assert_eq!(before, after);
+
+    // Test that leaves stay in their blocks, and processes still own increasing Morton intervals
+    for (block, leaves) in restricted.tree.iter() {
+        assert!(leaves.iter().all(|l| l.block == *block));
+    }
+
+    if let Some(new_world) = new_world {
+        let (min, max) = restricted.morton_range().unwrap();
+        let mut ranges = vec![Key::default(); 2 * (new_world.size() as usize)];
+        new_world.all_gather_into(&[min, max][..], &mut ranges[..]);
+        for pair in ranges.windows(2) {
+            assert!(pair[0] <= pair[1]);
+        }
+    }
+}
+
+// Test that a single call to block partition balances a skewed distribution of blocks
+pub fn test_block_partition(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 16;
+
+    // Rank r holds 4(r + 1)^2 consecutive blocks of unit weight, so most blocks must move several
+    // ranks towards the root.
+    let nblocks = 4 * ((rank + 1) * (rank + 1)) as u64;
+    let offset = scan_exclusive(nblocks, &world);
+    let mut blocks: Keys = (offset..offset + nblocks)
+        .map(|i| Key(0, 0, i, depth))
+        .collect();
+    let weights: Weights = vec![Weight(1); nblocks as usize];
+
+    let mut total: u64 = 0;
+    world.all_reduce_into(&nblocks, &mut total, SystemOperation::sum());
+
+    if rank == 0 {
+        println!(
+            "Test block partition of {} skewed blocks across {} processes",
+            total, size
+        );
+    }
+
+    let destinations = block_partition(weights, &mut blocks, size, &world);
+    assert_eq!(destinations.len(), nblocks as usize);
+
+    // Test that every rank's weight is within one of the balanced weight
+    let target = total as f64 / size as f64;
+    let weight = blocks.len() as f64;
+    assert!((weight - target).abs() <= 1.);
+
+    // Test that blocks remain sorted, and none are lost
+    let mut sorted = blocks.clone();
+    sorted.sort();
+    assert_eq!(sorted, blocks);
+
+    let mut received: u64 = 0;
+    world.all_reduce_into(
+        &(blocks.len() as u64),
+        &mut received,
+        SystemOperation::sum(),
+    );
+    assert_eq!(received, total);
+}
+
+// Test that partitioning blocks between a single process returns at once, leaving them in place.
+pub fn test_block_partition_single_rank(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+
+    // Each process partitions its blocks within a communicator of its own
+    let single = world.split_by_color(Color::with_value(rank)).unwrap();
+
+    let depth: u64 = 16;
+    let mut blocks: Keys = (0..8).map(|i| Key(0, 0, i, depth)).collect();
+    let expected = blocks.clone();
+    let weights: Weights = vec![Weight(1); blocks.len()];
+
+    if rank == 0 {
+        println!(
+            "Test block partition of {} blocks on a single process",
+            blocks.len()
+        );
+    }
+
+    let destinations = block_partition(weights, &mut blocks, single.size(), &single);
+
+    assert!(destinations.is_empty());
+    assert_eq!(blocks, expected);
+}
+
+// Test that the summary statistics of a tree are consistent with each other
+pub fn test_stats(universe: &Universe) {
+    let world = universe.world();
+    let size = world.size();
+
+    let depth: u64 = 4;
+    let setup = TestTree {
+        depth,
+        ..TestTree::default()
+    };
+    let octree = build_test_tree_with(universe, "tree statistics", setup);
+    let stats = octree.stats(&world);
+
+    // Test that the point count is the sum of the points in each leaf
+    let local: u64 = octree.iter_leaves().map(|l| l.npoints as u64).sum();
+    let mut total: u64 = 0;
+    world.all_reduce_into(&local, &mut total, SystemOperation::sum());
+    assert_eq!(stats.npoints, total);
+    assert_eq!(stats.npoints, NPOINTS * (size as u64));
+
+    let nleaves = octree.iter_leaves().count() as u64;
+    let mut total_leaves: u64 = 0;
+    world.all_reduce_into(&nleaves, &mut total_leaves, SystemOperation::sum());
+    assert_eq!(stats.nleaves, total_leaves);
+
+    // Test that each mean lies within its range, and is consistent with the totals
+    assert!(stats.min_level as f64 <= stats.mean_level);
+    assert!(stats.mean_level <= stats.max_level as f64);
+    assert!(stats.min_points as f64 <= stats.mean_points);
+    assert!(stats.mean_points <= stats.max_points as f64);
+
+    let mean_points = stats.npoints as f64 / stats.nleaves as f64;
+    assert!((stats.mean_points - mean_points).abs() < 1e-9);
+    assert!(stats.max_level <= depth);
+    assert!(stats.max_points <= NCRIT as u64);
+
+    // Test that the most leaves held by one process is at least the mean
+    assert!(stats.imbalance >= 1.);
+}
+
+// Test that overlaps between the octants of neighbouring processes are removed
+pub fn test_linearise_parallel(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 8;
+
+    // One coarse octant per process, at the coarsest level with enough of them
+    let mut octants: Keys = vec![Key(0, 0, 0, 0)];
+    while octants.len() < size as usize {
+        octants = octants
+            .iter()
+            .flat_map(|o| find_children(o, &depth))
+            .collect();
+    }
+    octants.sort();
+
+    if rank == 0 {
+        println!("Test parallel linearisation across {} processes", size);
+    }
+
+    // Each process holds its coarse octant and its children, overlapping locally, followed by
+    // the next process' coarse octant, overlapping across processes.
+    let coarse = octants[rank as usize];
+    let children = find_children(&coarse, &depth);
+
+    let mut keys: Keys = vec![coarse];
+    keys.extend(children.iter());
+    if rank < size - 1 {
+        keys.push(octants[(rank + 1) as usize]);
+    }
+
+    let linearised = linearise_parallel(&mut keys, &depth, &world);
+
+    // Test that only the children remain at each process
+    assert_eq!(linearised, children);
+
+    // Test that processes without keys are skipped, when only even processes hold keys and
+    // overlap with the next even process
+    let mut keys: Keys = Vec::new();
+    if rank % 2 == 0 {
+        keys.push(coarse);
+        keys.extend(children.iter());
+        if rank + 2 < size {
+            keys.push(octants[(rank + 2) as usize]);
+        }
+    }
+
+    let linearised = linearise_parallel(&mut keys, &depth, &world);
+
+    if rank % 2 == 0 {
+        assert_eq!(linearised, children);
+    } else {
+        assert!(linearised.is_empty());
+    }
+}
+
+// Test that refining a tree of clustered points satisfies both ncrit and 2:1 balance
+pub fn test_refine_until_balanced(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+
+    let depth: u64 = 5;
+    let ncrit: usize = 20;
+    let npoints: u64 = 1000;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == 0 {
+        println!("Test refining until balanced");
+    }
+
+    // Half of the points are clustered in a corner, refining it much deeper than the rest
+    let mut points: Points = random(npoints)
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let scale = if i % 2 == 0 { 0.05 } else { 1. };
+            Point::with_index(scale * p.x, scale * p.y, scale * p.z, i)
+        })
+        .collect();
+
+    let (mut octree, _) = unbalanced_tree(&depth, &ncrit, &world, &mut points, x0, r0).unwrap();
+    let before: usize = octree.n_points();
+
+    let result = octree.refine_until_balanced(&ncrit, &depth, &world);
+    assert!(result.is_ok());
+
+    // Test that no leaf which could be split holds more than ncrit points
+    assert!(octree
+        .iter_leaves()
+        .all(|l| (l.npoints <= ncrit) | (l.key.3 == depth)));
+
+    // Test that neighboring local leaves differ by at most one level
+    let leaves: HashSet<Key> = octree.iter_leaves().map(|l| l.key).collect();
+    for key in leaves.iter() {
+        for neighbor in octree.neighbors_of_leaf(key, &depth).iter() {
+            assert!((neighbor.3 as i64 - key.3 as i64).abs() <= 1);
+        }
+    }
+
+    // Test that refining conserves points
+    assert_eq!(octree.n_points(), before);
+
+    // Test that a failed refinement leaves the tree unchanged
+    let sorted = |octree: &Octree| {
+        let mut leaves: Vec<(Key, Key, usize)> = octree
+            .iter_leaves()
+            .map(|l| (l.key, l.block, l.npoints))
+            .collect();
+        leaves.sort();
+        leaves
+    };
+    let expected = sorted(&octree);
+    assert!(octree.refine_until_balanced(&0, &depth, &world).is_err());
+    assert_eq!(sorted(&octree), expected);
+
+    // Test that leaves without their points can't be refined
+    octree.points.clear();
+    let result = octree.refine_until_balanced(&ncrit, &depth, &world);
+    if octree.n_points() > 0 {
+        assert!(matches!(result, Err(TreeError::MissingPoints { .. })));
+    } else {
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,81 @@
+extern crate mpi;
+extern crate tree;
+
+use std::fs::File;
+use std::io::Write;
+
+use mpi::collective::SystemOperation;
+use mpi::environment::Universe;
+use mpi::traits::*;
+
+use tree::data::{gather_all_points, random, read_raw_f64};
+use tree::morton::{Point, Points};
+
+// Test reading points from a raw binary file in parallel
+pub fn test_read_raw_f64(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let npoints: usize = 101;
+    let path = std::env::temp_dir().join("distributed_trees_points.raw");
+
+    if rank == 0 {
+        println!(
+            "Test reading {} raw points across {} processes",
+            npoints, size
+        );
+
+        // The i'th point is (i, 2i, 3i).
+        let mut file = File::create(&path).unwrap();
+        for i in 0..npoints {
+            for c in 1..4 {
+                file.write_all(&((c * i) as f64).to_le_bytes()).unwrap();
+            }
+        }
+    }
+    world.barrier();
+
+    let points = read_raw_f64(&path, &world).unwrap();
+
+    // Test that coordinates match their global index, and indices are contiguous
+    for (i, p) in points.iter().enumerate() {
+        assert_eq!(p.global_idx, points[0].global_idx + i);
+        assert_eq!(p.x, p.global_idx as f64);
+        assert_eq!(p.y, (2 * p.global_idx) as f64);
+        assert_eq!(p.z, (3 * p.global_idx) as f64);
+    }
+
+    // Test that every point is read exactly once
+    let nlocal = points.len();
+    let mut nglobal: usize = 0;
+    world.all_reduce_into(&nlocal, &mut nglobal, SystemOperation::sum());
+    assert_eq!(nglobal, npoints);
+}
+
+// Test gathering every process' points onto all processes
+pub fn test_gather_all_points(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+
+    if rank == 0 {
+        println!("Test gathering all points");
+    }
+
+    // Each process holds a different number of points, indexed by their rank
+    let nlocal = (rank + 1) as u64;
+    let local: Points = random(nlocal)
+        .iter()
+        .map(|p| Point::with_index(p.x, p.y, p.z, rank as usize))
+        .collect();
+
+    let gathered = gather_all_points(&local, &world);
+
+    // Test that the gathered length equals the global count, and points are in rank order
+    let mut nglobal: u64 = 0;
+    world.all_reduce_into(&nlocal, &mut nglobal, SystemOperation::sum());
+    assert_eq!(gathered.len() as u64, nglobal);
+    assert!(gathered
+        .windows(2)
+        .all(|w| w[0].global_idx <= w[1].global_idx));
+}
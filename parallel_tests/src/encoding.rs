@@ -0,0 +1,45 @@
+extern crate mpi;
+extern crate tree;
+
+use mpi::environment::Universe;
+use mpi::traits::*;
+
+use tree::data::random;
+use tree::morton::encode_points_auto;
+
+// Test encoding points over an automatically computed domain
+pub fn test_encode_points_auto(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 4;
+    let npoints: u64 = 1000;
+
+    // Points spanning [-5, 5)^3.
+    let mut points = random(npoints);
+    for p in points.iter_mut() {
+        p.x = 10. * p.x - 5.;
+        p.y = 10. * p.y - 5.;
+        p.z = 10. * p.z - 5.;
+    }
+
+    if rank == 0 {
+        println!(
+            "Test automatic domain encoding with {} points across {} processes",
+            npoints, size
+        );
+    }
+
+    let (_, r0) = encode_points_auto(&mut points, &depth, &world);
+    assert!(r0 <= 5. * 1.001);
+
+    // Test that all keys lie inside the domain
+    let max: u64 = 1 << depth;
+    for p in points.iter() {
+        assert!(p.key.0 < max);
+        assert!(p.key.1 < max);
+        assert!(p.key.2 < max);
+        assert_eq!(p.key.3, depth);
+    }
+}
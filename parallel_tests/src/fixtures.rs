@@ -0,0 +1,64 @@
+extern crate mpi;
+extern crate tree;
+
+use mpi::environment::Universe;
+use mpi::traits::*;
+
+use tree::data::random;
+use tree::morton::{Point, Points};
+use tree::tree::{unbalanced_tree_sorted_by, Octree, SortAlgorithm};
+
+// Depth, critical number of points per leaf and points per process of the shared test tree
+pub const DEPTH: u64 = 3;
+pub const NCRIT: usize = 150;
+pub const NPOINTS: u64 = 1000;
+
+// Setup of a test tree, defaulting to that of the shared test tree
+pub struct TestTree {
+    pub depth: u64,
+    pub points: Points,
+    pub sort: SortAlgorithm,
+}
+
+impl Default for TestTree {
+    fn default() -> Self {
+        TestTree {
+            depth: DEPTH,
+            points: random(NPOINTS),
+            sort: SortAlgorithm::default(),
+        }
+    }
+}
+
+// Build an unbalanced tree over random points in the unit cube, announcing the test from rank 0
+pub fn build_test_tree(universe: &Universe, test: &str) -> Octree {
+    build_test_tree_with(universe, test, TestTree::default())
+}
+
+// Build an unbalanced tree in the unit cube from a given setup, announcing the test from rank 0
+pub fn build_test_tree_with(universe: &Universe, test: &str, setup: TestTree) -> Octree {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let TestTree {
+        depth,
+        mut points,
+        sort,
+    } = setup;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == 0 {
+        println!(
+            "Test {} with {} points across {} processes",
+            test,
+            points.len(),
+            size
+        );
+    }
+
+    let (octree, _) =
+        unbalanced_tree_sorted_by(sort, &depth, &NCRIT, &world, &mut points, x0, r0).unwrap();
+    octree
+}
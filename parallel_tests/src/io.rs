@@ -0,0 +1,49 @@
+extern crate mpi;
+extern crate tree;
+
+use mpi::environment::Universe;
+
+use tree::io::{load_checkpoint, load_checkpoint_at_depth, write_checkpoint};
+
+use crate::fixtures::{build_test_tree, DEPTH};
+
+// Test that writing then loading a checkpoint reproduces the tree
+pub fn test_checkpoint(universe: &Universe) {
+    let world = universe.world();
+
+    let octree = build_test_tree(universe, "checkpointing");
+
+    let dir = std::env::temp_dir().join("distributed_trees_checkpoint");
+    write_checkpoint(&octree, &world, &dir).unwrap();
+    let loaded = load_checkpoint(&dir, &world).unwrap();
+
+    // Test that loading at a different depth than the tree was written at fails clearly
+    let err = load_checkpoint_at_depth(&dir, DEPTH + 1, &world).unwrap_err();
+    let msg = format!("'depth' is {} but {} was expected", DEPTH, DEPTH + 1);
+    assert!(err.to_string().contains(&msg));
+    assert!(load_checkpoint_at_depth(&dir, DEPTH, &world).is_ok());
+
+    assert_eq!(loaded.depth, octree.depth);
+    assert_eq!(loaded.ncrit, octree.ncrit);
+    assert!(loaded.x0.approx_eq(&octree.x0, 1e-12));
+    assert_eq!(loaded.r0, octree.r0);
+    assert_eq!(loaded.tree.len(), octree.tree.len());
+    assert_eq!(loaded.indices, octree.indices);
+
+    for (block, leaves) in octree.tree.iter() {
+        let loaded_leaves = loaded.tree.get(block).unwrap();
+        assert_eq!(loaded_leaves.len(), leaves.len());
+        for (a, b) in leaves.iter().zip(loaded_leaves.iter()) {
+            assert_eq!(a.key, b.key);
+            assert_eq!(a.block, b.block);
+            assert_eq!(a.npoints, b.npoints);
+        }
+    }
+
+    // Test that the points are restored, so that they can still be searched
+    assert_eq!(loaded.points.len(), octree.points.len());
+    for (a, b) in octree.points.iter().zip(loaded.points.iter()) {
+        assert!(a.approx_eq(b, 0.));
+        assert_eq!((a.key, a.global_idx), (b.key, b.global_idx));
+    }
+}
@@ -1 +1,9 @@
+pub mod comm;
+pub mod construction;
+pub mod data;
+pub mod encoding;
+pub mod fixtures;
+pub mod io;
 pub mod sorting;
+#[cfg(feature = "tracing")]
+pub mod spans;
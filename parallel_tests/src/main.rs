@@ -1,14 +1,94 @@
 use mpi::traits::*;
+use parallel_tests::comm::*;
+use parallel_tests::construction::*;
+use parallel_tests::data::*;
+use parallel_tests::encoding::*;
+use parallel_tests::io::*;
 use parallel_tests::sorting::*;
+#[cfg(feature = "tracing")]
+use parallel_tests::spans::*;
 
 fn main() {
     // 1. Test sample sort
-    let universe = mpi::initialize().unwrap();
+    let universe = tree::init().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
     let world = universe.world();
     let rank = world.rank();
 
     if rank == 0 {
         println!("Test sorting algorithms: ");
     }
-    test_sample_sort(universe);
+    test_sample_sort(&universe);
+    test_sample_sort_sub_communicator(&universe);
+    test_sample_sort_conserves_points(&universe);
+    test_histogram_sort(&universe);
+    test_sample_sort_seeded(&universe);
+    test_sample_sort_message_limit(&universe);
+    #[cfg(feature = "f32")]
+    test_sample_sort_f32(&universe);
+
+    // 2. Test communication utilities
+    if rank == 0 {
+        println!("Test communication utilities: ");
+    }
+    test_all_reduce_min_max(&universe);
+    test_split_by_block(&universe);
+    test_all_gather_keys(&universe);
+    test_all_gatherv_quad_keys(&universe);
+    test_run_guarded(&universe);
+    test_init_twice(&universe);
+    test_barrier_timed(&universe);
+    test_message_tags(&universe);
+    test_fragmented_round_trip(&universe);
+    test_partition_surface_area(&universe);
+    test_gatherv_points(&universe);
+    test_reduce_keys_to_root(&universe);
+    test_reduce_count(&universe);
+    test_scan_exclusive(&universe);
+
+    // 3. Test reading and writing trees
+    if rank == 0 {
+        println!("Test IO: ");
+    }
+    test_checkpoint(&universe);
+    test_read_raw_f64(&universe);
+    test_gather_all_points(&universe);
+
+    // 4. Test tree construction
+    if rank == 0 {
+        println!("Test tree construction: ");
+    }
+    test_distributed_matches_sequential(&universe);
+    test_build_streaming(&universe);
+    test_n_points_global(&universe);
+    test_unbalanced_tree_sorted_by(&universe);
+    test_global_leaf_index(&universe);
+    test_coarse_blocks(&universe);
+    test_points_bounding_box_global(&universe);
+    test_collectives_after_construction(&universe);
+    test_stats(&universe);
+    test_morton_ranges(&universe);
+    test_restrict_to(&universe);
+    test_block_partition(&universe);
+    test_block_partition_single_rank(&universe);
+    test_linearise_parallel(&universe);
+    test_complete_blocktree_uneven_seeds(&universe);
+    test_refine_until_balanced(&universe);
+
+    // 5. Test Morton encoding
+    if rank == 0 {
+        println!("Test Morton encoding: ");
+    }
+    test_encode_points_auto(&universe);
+
+    // 6. Test tracing, when enabled
+    #[cfg(feature = "tracing")]
+    {
+        if rank == 0 {
+            println!("Test tracing: ");
+        }
+        test_phase_spans(&universe);
+    }
 }
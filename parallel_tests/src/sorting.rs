@@ -1,16 +1,21 @@
 extern crate mpi;
 extern crate tree;
 
+use mpi::collective::SystemOperation;
 use mpi::environment::Universe;
+use mpi::topology::Color;
 use mpi::traits::*;
 
-use tree::data::random;
+use tree::comm::{gatherv_points, set_max_msg_elements, DEFAULT_MAX_MSG_ELEMENTS};
+use tree::data::{random, random_partitioned};
 use tree::morton::{encode_points, Key, Leaves, Point, Points};
-use tree::tree::sample_sort;
+#[cfg(feature = "f32")]
+use tree::tree::sample_sort_f32;
+use tree::tree::{parallel_sort, sample_sort, sample_sort_seeded, SortAlgorithm};
 
 
 // Test sample sort
-pub fn test_sample_sort(universe: Universe) {
+pub fn test_sample_sort(universe: &Universe) {
     let world = universe.world();
     let rank = world.rank();
     let size = world.size();
@@ -20,13 +25,7 @@ pub fn test_sample_sort(universe: Universe) {
 
     // Generate random test points on a given process.
     let mut points = random(npoints);
-    let x0 = Point {
-        x: 0.5,
-        y: 0.5,
-        z: 0.5,
-        global_idx: 0,
-        key: Key::default(),
-    };
+    let x0 = Point::new(0.5, 0.5, 0.5);
     let r0 = 0.5;
 
     if rank == 0 {
@@ -45,7 +44,7 @@ pub fn test_sample_sort(universe: Universe) {
     let (sorted_leaves, sorted_points) = sample_sort(
         &mut points,
         size,
-        world,
+        &world,
     );
 
     // Test that the maximum on this process is less than the minimum on the next process
@@ -67,3 +66,323 @@ pub fn test_sample_sort(universe: Universe) {
         prev = leaf;
     }
 }
+
+// Test that sample sort sends every point to exactly one process, when processes without points
+// leave fewer splitters than buckets
+pub fn test_sample_sort_conserves_points(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+    let root = 0;
+
+    let depth: u64 = 3;
+    let npoints: u64 = 1000;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == 0 {
+        println!("Test Sample Sort conserves points");
+    }
+
+    // Only even ranks hold points, and their keys are coarse enough to coincide with splitters
+    let nlocal = if rank % 2 == 0 { npoints } else { 0 };
+    let offset = (rank as u64) * npoints;
+    let mut points: Points = random(nlocal)
+        .iter()
+        .enumerate()
+        .map(|(i, p)| Point::with_index(p.x, p.y, p.z, (offset as usize) + i))
+        .collect();
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+    let (_, sorted_points) = sample_sort(&mut points, size, &world);
+
+    // Test that the global indices after the sort are exactly those before it
+    if let Some(gathered) = gatherv_points(&sorted_points, &world, root) {
+        let mut indices: Vec<usize> = gathered.iter().map(|p| p.global_idx).collect();
+        indices.sort_unstable();
+
+        let expected: Vec<usize> = (0..size as u64)
+            .filter(|r| r % 2 == 0)
+            .flat_map(|r| (r * npoints)..((r + 1) * npoints))
+            .map(|i| i as usize)
+            .collect();
+        assert_eq!(indices, expected);
+    }
+}
+
+// Test sample sort on a sub-communicator, splitting the processes by the parity of their rank
+pub fn test_sample_sort_sub_communicator(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+
+    let depth: u64 = 3;
+    let npoints: u64 = 1000;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == 0 {
+        println!("Test Sample Sort on a sub-communicator");
+    }
+
+    let sub = world.split_by_color(Color::with_value(rank % 2)).unwrap();
+    let sub_rank = sub.rank();
+    let sub_size = sub.size();
+
+    let mut points = random(npoints);
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+    let (sorted_leaves, sorted_points) = sample_sort(&mut points, sub_size, &sub);
+
+    // Test that every point is retained within the sub-communicator
+    let mut total: u64 = 0;
+    sub.all_reduce_into(
+        &(sorted_points.len() as u64),
+        &mut total,
+        SystemOperation::sum(),
+    );
+    assert_eq!(total, npoints * (sub_size as u64));
+
+    // Test that leaves are sorted on this process
+    for pair in sorted_leaves.windows(2) {
+        assert!(pair[0] <= pair[1]);
+    }
+
+    // Test that the maximum on this process is at most the minimum on every following process
+    // of the sub-communicator
+    let min = sorted_leaves.first().map_or(Key::default(), |l| l.key);
+    let max = sorted_leaves.last().map_or(Key::default(), |l| l.key);
+    let mut mins = vec![Key::default(); sub_size as usize];
+    let mut counts = vec![0u64; sub_size as usize];
+    sub.all_gather_into(&min, &mut mins[..]);
+    sub.all_gather_into(&(sorted_leaves.len() as u64), &mut counts[..]);
+    for r in (sub_rank as usize + 1)..(sub_size as usize) {
+        if (counts[r] > 0) & !sorted_leaves.is_empty() {
+            assert!(max <= mins[r]);
+        }
+    }
+}
+
+// Test that histogram sort balances an adversarial distribution
+pub fn test_histogram_sort(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 10;
+    let npoints: u64 = 10000;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    // The root process holds every point in the lower octant, while every other process holds a
+    // single point beyond them, so samples drawn per process say little about the leaf density.
+    let mut points: Points = if rank == 0 {
+        random(npoints)
+            .iter()
+            .map(|p| Point::new(0.5 * p.x, 0.5 * p.y, 0.5 * p.z))
+            .collect()
+    } else {
+        vec![Point::new(0.9, 0.9, 0.5 + 0.002 * (rank as f64))]
+    };
+
+    if rank == 0 {
+        println!(
+            "Test Histogram Sort with {} adversarial points across {} processes",
+            npoints, size
+        );
+    }
+
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+    // Largest number of leaves received by a process, relative to the mean
+    let imbalance = |leaves: &Leaves| {
+        let mut counts = vec![0u64; size as usize];
+        world.all_gather_into(&(leaves.len() as u64), &mut counts[..]);
+        let mean = counts.iter().sum::<u64>() as f64 / size as f64;
+        *counts.iter().max().unwrap() as f64 / mean
+    };
+
+    let (sorted_leaves, sorted_points) =
+        parallel_sort(SortAlgorithm::HistogramSort, &mut points, size, &world);
+
+    // Test that no process receives more than 10% over the mean number of leaves
+    assert!(imbalance(&sorted_leaves) <= 1.1);
+
+    // Test that leaves are sorted on this process, and that points follow their leaves
+    for pair in sorted_leaves.windows(2) {
+        assert!(pair[0] <= pair[1]);
+    }
+    if let (Some(first), Some(last)) = (sorted_leaves.first(), sorted_leaves.last()) {
+        assert!(sorted_points
+            .iter()
+            .all(|p| (first.key <= p.key) & (p.key <= last.key)));
+    }
+
+    // Test that the maximum on this process is at most the minimum on every following process
+    let min = sorted_leaves.first().map_or(Key::default(), |l| l.key);
+    let max = sorted_leaves.last().map_or(Key::default(), |l| l.key);
+    let mut mins = vec![Key::default(); size as usize];
+    let mut counts = vec![0u64; size as usize];
+    world.all_gather_into(&min, &mut mins[..]);
+    world.all_gather_into(&(sorted_leaves.len() as u64), &mut counts[..]);
+    for r in (rank as usize + 1)..(size as usize) {
+        if (counts[r] > 0) & !sorted_leaves.is_empty() {
+            assert!(max <= mins[r]);
+        }
+    }
+}
+
+// Test that sample sort with the same base seed gives identical splitters and partitions
+pub fn test_sample_sort_seeded(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 3;
+    let npoints: u64 = 1000;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == 0 {
+        println!(
+            "Test seeded Sample Sort with {} points across {} processes",
+            npoints, size
+        );
+    }
+
+    let mut points = random_partitioned(npoints * size as u64, rank as u64, size as u64, 0);
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+    // Only the base seed at the root is used, so the other processes may pass anything
+    let base_seed = if rank == 0 { 7 } else { rank as u64 };
+    let (leaves_a, points_a) = sample_sort_seeded(&mut points.clone(), size, &world, base_seed);
+    let (leaves_b, points_b) = sample_sort_seeded(&mut points.clone(), size, &world, 7);
+
+    // Test that every process receives the same leaves and points in the same order
+    let same_leaves = |a: &Leaves, b: &Leaves| {
+        (a.len() == b.len())
+            & a.iter()
+                .zip(b.iter())
+                .all(|(l, m)| (l.key == m.key) & (l.npoints == m.npoints))
+    };
+    assert!(same_leaves(&leaves_a, &leaves_b));
+    assert_eq!(points_a.len(), points_b.len());
+    assert!(points_a
+        .iter()
+        .zip(points_b.iter())
+        .all(|(p, q)| (p.key == q.key) & (p.global_idx == q.global_idx)));
+
+    // Test that the splitters, the least key received by each process, are identical
+    let splitters = |leaves: &Leaves| {
+        let min = leaves.first().map_or(Key::default(), |l| l.key);
+        let mut mins = vec![Key::default(); size as usize];
+        world.all_gather_into(&min, &mut mins[..]);
+        mins
+    };
+    assert_eq!(splitters(&leaves_a), splitters(&leaves_b));
+}
+
+// Test that sample sort under a tiny message limit, sending buckets in many rounds, gives the same
+// partition as under the default limit
+pub fn test_sample_sort_message_limit(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 3;
+    let npoints: u64 = 1000;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+    let max_elements = 7 * size as usize;
+
+    if rank == 0 {
+        println!(
+            "Test Sample Sort with messages of at most {} elements across {} processes",
+            max_elements, size
+        );
+    }
+
+    let mut points = random_partitioned(npoints * size as u64, rank as u64, size as u64, 0);
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+    let (leaves_a, points_a) = sample_sort_seeded(&mut points.clone(), size, &world, 7);
+    set_max_msg_elements(max_elements);
+    let (leaves_b, points_b) = sample_sort_seeded(&mut points, size, &world, 7);
+    set_max_msg_elements(DEFAULT_MAX_MSG_ELEMENTS);
+
+    // Test that every process receives the same leaves and points, in the same order
+    assert_eq!(leaves_a.len(), leaves_b.len());
+    assert!(leaves_a
+        .iter()
+        .zip(leaves_b.iter())
+        .all(|(l, m)| (l.key == m.key) & (l.npoints == m.npoints)));
+    assert_eq!(points_a.len(), points_b.len());
+    assert!(points_a
+        .iter()
+        .zip(points_b.iter())
+        .all(|(p, q)| (p.key == q.key) & (p.global_idx == q.global_idx)));
+}
+
+// Test that sending coordinates in single precision gives the same keys as the double precision
+// sort, and coordinates within the rounding error of f32
+#[cfg(feature = "f32")]
+pub fn test_sample_sort_f32(universe: &Universe) {
+    let world = universe.world();
+    let rank = world.rank();
+    let size = world.size();
+
+    let depth: u64 = 3;
+    let npoints: u64 = 1000;
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    if rank == 0 {
+        println!(
+            "Test Sample Sort with f32 transport with {} points across {} processes",
+            npoints, size
+        );
+    }
+
+    // Give every point a unique global index, so that the two sorts can be matched up
+    let mut points: Points = random(npoints)
+        .iter()
+        .enumerate()
+        .map(|(i, p)| Point::with_index(p.x, p.y, p.z, (rank as usize) * (npoints as usize) + i))
+        .collect();
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+    let (leaves_f64, points_f64) = sample_sort(&mut points.clone(), size, &world);
+    let (leaves_f32, points_f32) = sample_sort_f32(&mut points, size, &world);
+
+    // Splitters are sampled at random, so compare the sorted points globally by their index
+    let by_index = |points: Option<Points>| {
+        points.map(|mut points| {
+            points.sort_by_key(|p| p.global_idx);
+            points
+        })
+    };
+    let gathered_f64 = by_index(gatherv_points(&points_f64, &world, 0));
+    let gathered_f32 = by_index(gatherv_points(&points_f32, &world, 0));
+
+    if let (Some(expected), Some(received)) = (gathered_f64, gathered_f32) {
+        assert_eq!(expected.len(), received.len());
+
+        let tol = f32::EPSILON as f64;
+        for (e, r) in expected.iter().zip(received.iter()) {
+            assert_eq!(e.global_idx, r.global_idx);
+            assert_eq!(e.key, r.key);
+            for (a, b) in [(e.x, r.x), (e.y, r.y), (e.z, r.z)].iter() {
+                assert!((a - b).abs() <= tol * a.abs());
+            }
+        }
+    }
+
+    // Test that leaves are sorted on this process, and carry the same total number of points
+    for pair in leaves_f32.windows(2) {
+        assert!(pair[0] <= pair[1]);
+    }
+    let count = |leaves: &Leaves| leaves.len() as u64;
+    let mut totals = [0u64; 2];
+    world.all_reduce_into(&count(&leaves_f64), &mut totals[0], SystemOperation::sum());
+    world.all_reduce_into(&count(&leaves_f32), &mut totals[1], SystemOperation::sum());
+    assert_eq!(totals[0], totals[1]);
+}
@@ -0,0 +1,64 @@
+extern crate mpi;
+extern crate tracing;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mpi::environment::Universe;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use crate::fixtures::build_test_tree;
+
+// Subscriber recording the names of spans in the order they're entered.
+#[derive(Default)]
+struct Recorder {
+    entered: Arc<Mutex<Vec<&'static str>>>,
+    names: Mutex<HashMap<u64, &'static str>>,
+    next_id: AtomicU64,
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.names
+            .lock()
+            .unwrap()
+            .insert(id, span.metadata().name());
+        Id::from_u64(id)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        let name = self.names.lock().unwrap()[&span.into_u64()];
+        self.entered.lock().unwrap().push(name);
+    }
+
+    fn exit(&self, _: &Id) {}
+}
+
+// Test that the phases of construction are traced in order
+pub fn test_phase_spans(universe: &Universe) {
+    let recorder = Recorder::default();
+    let entered = recorder.entered.clone();
+
+    tracing::subscriber::with_default(recorder, || {
+        build_test_tree(universe, "tracing spans of tree construction")
+    });
+
+    let entered = entered.lock().unwrap();
+    assert_eq!(
+        *entered,
+        vec!["encoding", "sorting", "blocktree", "block_splitting"]
+    );
+}
@@ -0,0 +1,118 @@
+use mpi::traits::*;
+
+use scaling_tests::params::{param, weak_scaling};
+
+use tree::comm::{abort_on_error, reduce_count};
+use tree::data::random;
+
+use tree::morton::Point;
+use tree::tree::unbalanced_tree;
+
+/// Phases whose timings are reported, in output order.
+const PHASES: [&str; 3] = ["total", "encoding", "sorting"];
+
+fn main() {
+    // Setup MPI
+    let universe = tree::init().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let world = universe.world();
+    let size = world.size();
+    let rank = world.rank();
+    let root_rank = 0;
+    let root_process = world.process_at_rank(root_rank);
+
+    // 0. Experimental Parameters, NPOINTS is per process for weak scaling and the total for
+    // strong scaling.
+    let depth: u64 = param("DEPTH", 3);
+    let ncrit: usize = param("NCRIT", 1000);
+    let npoints: u64 = param("NPOINTS", 1000000);
+    let weak = weak_scaling();
+
+    let npoints_local = if weak {
+        npoints
+    } else {
+        npoints / (size as u64)
+    };
+
+    // Generate random test points on a given process.
+    let mut points = random(npoints_local);
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+
+    // Generate distributed unbalanced tree from a set of distributed points
+    let (unbalanced, times) = abort_on_error(
+        unbalanced_tree(&depth, &ncrit, &world, &mut points, x0, r0),
+        &world,
+    );
+
+    // Sync for timing purposes
+    world.barrier();
+
+    // Gather per phase timings into root rank
+    let local: Vec<u64> = PHASES
+        .iter()
+        .map(|phase| *times.get(&phase.to_string()).unwrap_or(&0) as u64)
+        .collect();
+
+    let mut gathered = vec![0u64; PHASES.len() * (size as usize)];
+    if rank == root_rank {
+        root_process.gather_into_root(&local[..], &mut gathered[..]);
+    } else {
+        root_process.gather_into(&local[..]);
+    }
+
+    // Reduce total number of leaves into root rank
+    let nleaves = reduce_count(unbalanced.n_leaves() as u64, &world, root_rank);
+
+    if let Some(nleaves) = nleaves {
+        // The slowest process determines the runtime of each phase.
+        let slowest: Vec<u64> = (0..PHASES.len())
+            .map(|i| gathered.chunks(PHASES.len()).map(|t| t[i]).max().unwrap())
+            .collect();
+
+        // Timings of a 1 rank run, given as comma separated milliseconds in the order of PHASES.
+        let baseline: Option<Vec<u64>> = if size == 1 {
+            Some(slowest.clone())
+        } else {
+            std::env::var("BASELINE")
+                .ok()
+                .and_then(|b| b.split(',').map(|t| t.trim().parse().ok()).collect())
+                .filter(|b: &Vec<u64>| b.len() == PHASES.len())
+        };
+
+        // Weak scaling keeps the runtime fixed, strong scaling divides it between processes.
+        let efficiency = |t1: u64, tp: u64| {
+            let ideal = if weak {
+                t1 as f64
+            } else {
+                t1 as f64 / size as f64
+            };
+            ideal / (tp.max(1) as f64)
+        };
+
+        let efficiencies: Vec<f64> = match baseline {
+            Some(baseline) => baseline
+                .iter()
+                .zip(slowest.iter())
+                .map(|(&t1, &tp)| efficiency(t1, tp))
+                .collect(),
+            None => vec![f64::NAN; PHASES.len()],
+        };
+
+        // universe size, number of leaves, total runtime, encoding time, sorting time, followed
+        // by the parallel efficiency of each
+        println!(
+            "{:?}, {:?}, {:?}, {:?}, {:?}, {:.3}, {:.3}, {:.3}",
+            size,
+            nleaves,
+            slowest[0],
+            slowest[1],
+            slowest[2],
+            efficiencies[0],
+            efficiencies[1],
+            efficiencies[2]
+        )
+    }
+}
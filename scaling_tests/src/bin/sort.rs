@@ -0,0 +1,36 @@
+use scaling_tests::params::param;
+
+use tree::data::random;
+use tree::morton::{encode_points, keys_to_leaves, Point};
+use tree::time::timer;
+
+fn main() {
+    // 0. Experimental Parameters, the sort is local so no MPI is required.
+    let depth: u64 = param("DEPTH", 16);
+    let npoints: u64 = param("NPOINTS", 10000000);
+
+    let mut points = random(npoints);
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+    // Time the sequential sort that keys_to_leaves used to perform, against keys_to_leaves itself
+    let sequential = timer(|| {
+        let mut points = points.clone();
+        points.sort_by(|a, b| a.key.cmp(&b.key));
+    });
+
+    let parallel = timer(|| {
+        let mut points = points.clone();
+        keys_to_leaves(&mut points);
+    });
+
+    // number of points, sequential sort time, parallel sort and leaf construction time, speedup
+    println!(
+        "{:?}, {:?}, {:?}, {:.3}",
+        npoints,
+        sequential,
+        parallel,
+        sequential / parallel.max(1.)
+    )
+}
@@ -0,0 +1,55 @@
+use scaling_tests::params::param;
+
+use tree::data::random;
+use tree::morton::{encode_points, find_children, keys_to_leaves, Key, Keys, Leaf, Point};
+use tree::time::timer;
+use tree::tree::{assign_blocks_to_leaves, find_block_weights, Weight, Weights};
+
+/// The scan over every leaf for each block that `find_block_weights` used to perform.
+fn find_block_weights_quadratic(leaves: &[Leaf], blocktree: &[Key]) -> Weights {
+    blocktree
+        .iter()
+        .map(|&block| Weight(leaves.iter().filter(|&l| l.block == block).count() as u64))
+        .collect()
+}
+
+fn main() {
+    // 0. Experimental Parameters, blocks are every octant at LEVEL.
+    let depth: u64 = param("DEPTH", 16);
+    let level: u64 = param("LEVEL", 4);
+    let npoints: u64 = param("NPOINTS", 1000000);
+
+    let mut points = random(npoints);
+    let x0 = Point::new(0.5, 0.5, 0.5);
+    let r0 = 0.5;
+    encode_points(&mut points, &depth, &depth, &x0, &r0);
+    let mut leaves = keys_to_leaves(&mut points);
+
+    let mut blocktree: Keys = vec![Key(0, 0, 0, 0)];
+    for _ in 0..level {
+        blocktree = blocktree
+            .iter()
+            .flat_map(|b| find_children(b, &depth))
+            .collect();
+    }
+    blocktree.sort();
+    assign_blocks_to_leaves(&mut leaves, &blocktree, &depth);
+
+    let quadratic = timer(|| {
+        find_block_weights_quadratic(&leaves, &blocktree);
+    });
+
+    let linear = timer(|| {
+        find_block_weights(&leaves, &blocktree);
+    });
+
+    // number of leaves, number of blocks, quadratic time, single pass time, speedup
+    println!(
+        "{:?}, {:?}, {:?}, {:?}, {:.3}",
+        leaves.len(),
+        blocktree.len(),
+        quadratic,
+        linear,
+        quadratic / linear.max(1.)
+    )
+}
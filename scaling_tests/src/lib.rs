@@ -0,0 +1,4 @@
+//! Shared tools of the scaling experiments.
+
+/// Experimental parameters read from the environment.
+pub mod params;
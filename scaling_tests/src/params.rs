@@ -0,0 +1,25 @@
+use std::str::FromStr;
+
+/// Read an experimental parameter from the environment, falling back to a default when it's
+/// unset. A value which doesn't parse exits, rather than silently running another experiment.
+pub fn param<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value {:?} for {}", value, name);
+            std::process::exit(1)
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Whether to scale weakly, read from SCALING which is either `weak`, the default, or `strong`.
+pub fn weak_scaling() -> bool {
+    match param("SCALING", "weak".to_string()).as_str() {
+        "weak" => true,
+        "strong" => false,
+        other => {
+            eprintln!("Unknown SCALING {:?}, expected weak or strong", other);
+            std::process::exit(1)
+        }
+    }
+}
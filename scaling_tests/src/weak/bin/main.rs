@@ -1,20 +1,22 @@
 use std::time::Instant;
 
 use mpi::traits::*;
-use mpi::collective::{SystemOperation};
 
+use tree::comm::{abort_on_error, reduce_count};
 use tree::data::random;
 
-use tree::morton::{Key, Point};
+use tree::morton::Point;
 use tree::tree::unbalanced_tree;
 
 fn main() {
 
     // Setup MPI
-    let universe = mpi::initialize().unwrap();
+    let universe = tree::init().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
     let world = universe.world();
     let size = world.size();
-    let rank = world.rank();
     let root_rank = 0;
 
     // 0. Experimental Parameters
@@ -24,41 +26,30 @@ fn main() {
 
     // Generate random test points on a given process.
     let mut points = random(npoints);
-    let x0 = Point {
-        x: 0.5,
-        y: 0.5,
-        z: 0.5,
-        global_idx: 0,
-        key: Key::default(),
-    };
+    let x0 = Point::new(0.5, 0.5, 0.5);
     let r0 = 0.5;
 
     // Generate distributed unbalanced tree from a set of distributed points
-    let (unbalanced, times) = unbalanced_tree(&depth, &ncrit, &universe, &mut points, x0, r0);
+    let (unbalanced, times) = abort_on_error(
+        unbalanced_tree(&depth, &ncrit, &world, &mut points, x0, r0),
+        &world,
+    );
 
     // Sync for timing purposes
     world.barrier();
-    // broadcast total number of leaves into root rank
-    let nleaves = unbalanced.len() as u32;
-    let mut sum = 0;
+    // Reduce total number of leaves into root rank
+    let nleaves = reduce_count(unbalanced.tree.len() as u64, &world, root_rank);
 
     // Print runtime to stdout
-    if rank == root_rank {
-        world
-            .process_at_rank(root_rank)
-            .reduce_into_root(&nleaves, &mut sum, SystemOperation::sum());
+    if let Some(nleaves) = nleaves {
         /// universe size, number of leaves, total runtime, encoding time, sorting time
         println!(
             "{:?}, {:?}, {:?}, {:?}, {:?}",
             size,
-            sum,
+            nleaves,
             times.get(&"total".to_string()),
             times.get(&"encoding".to_string()),
             times.get(&"sorting".to_string())
         )
-    } else {
-        world
-            .process_at_rank(root_rank)
-            .reduce_into(&nleaves, SystemOperation::sum())
     }
 }
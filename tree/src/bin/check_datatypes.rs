@@ -0,0 +1,110 @@
+use mpi::traits::*;
+
+use tree::comm::{TAG_BLOCKS, TAG_LEAVES, TAG_POINTS};
+use tree::morton::{Key, Leaf, Point};
+
+/// Keys with every field distinct, and anchors which don't fit in 32 bits.
+fn known_keys() -> Vec<Key> {
+    vec![
+        Key(0, 0, 0, 0),
+        Key(1 << 40, 3, 12345, 16),
+        Key(u64::MAX >> 1, 1, u64::MAX >> 2, 63),
+    ]
+}
+
+/// Points with coordinates spanning the range of f64, and global indices which don't fit in 32
+/// bits.
+fn known_points() -> Vec<Point> {
+    known_keys()
+        .iter()
+        .enumerate()
+        .map(|(i, &key)| {
+            let mut point = Point::with_index(0.1 * (i as f64), -2.5e-300, 1e300, usize::MAX - i);
+            point.key = key;
+            point
+        })
+        .collect()
+}
+
+/// Leaves whose block differs from their key.
+fn known_leaves() -> Vec<Leaf> {
+    known_keys()
+        .iter()
+        .zip(known_keys().iter().rev())
+        .enumerate()
+        .map(|(i, (&key, &block))| Leaf {
+            key,
+            block,
+            npoints: (1 << 33) + i,
+        })
+        .collect()
+}
+
+/// Assert that received values are bit-identical to the known values, naming the side of the
+/// exchange on which they were received.
+fn check(keys: &[Key], points: &[Point], leaves: &[Leaf], side: &str) {
+    let differ = |kind: &str| format!("{} differ on {}", kind, side);
+
+    assert_eq!(keys, &known_keys()[..], "{}", differ("Keys"));
+
+    // Coordinates are compared by their bits, so that any change in value is caught.
+    let bits = |p: &Point| (p.x.to_bits(), p.y.to_bits(), p.z.to_bits(), p.key);
+
+    assert_eq!(points.len(), known_points().len());
+    for (received, expected) in points.iter().zip(known_points().iter()) {
+        assert_eq!(
+            received.global_idx,
+            expected.global_idx,
+            "{}",
+            differ("Points")
+        );
+        assert_eq!(bits(received), bits(expected), "{}", differ("Points"));
+    }
+
+    assert_eq!(leaves.len(), known_leaves().len());
+    for (received, expected) in leaves.iter().zip(known_leaves().iter()) {
+        assert_eq!(received.key, expected.key, "{}", differ("Leaves"));
+        assert_eq!(received.block, expected.block, "{}", differ("Leaves"));
+        assert_eq!(received.npoints, expected.npoints, "{}", differ("Leaves"));
+    }
+}
+
+fn main() {
+    let universe = tree::init().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let world = universe.world();
+    let rank = world.rank();
+
+    if world.size() < 2 {
+        println!("Datatype check requires at least 2 processes, run with `mpirun -n 2`");
+        return;
+    }
+
+    // Rank 0 sends known values to rank 1, which checks them and echoes them back, so that a
+    // layout mismatch on either side is caught.
+    if rank == 0 {
+        let partner = world.process_at_rank(1);
+        partner.send_with_tag(&known_keys()[..], TAG_BLOCKS);
+        partner.send_with_tag(&known_points()[..], TAG_POINTS);
+        partner.send_with_tag(&known_leaves()[..], TAG_LEAVES);
+
+        let (keys, _) = partner.receive_vec_with_tag::<Key>(TAG_BLOCKS);
+        let (points, _) = partner.receive_vec_with_tag::<Point>(TAG_POINTS);
+        let (leaves, _) = partner.receive_vec_with_tag::<Leaf>(TAG_LEAVES);
+        check(&keys, &points, &leaves, "rank 0");
+
+        println!("Key, Point and Leaf datatypes are consistent between ranks 0 and 1");
+    } else if rank == 1 {
+        let partner = world.process_at_rank(0);
+        let (keys, _) = partner.receive_vec_with_tag::<Key>(TAG_BLOCKS);
+        let (points, _) = partner.receive_vec_with_tag::<Point>(TAG_POINTS);
+        let (leaves, _) = partner.receive_vec_with_tag::<Leaf>(TAG_LEAVES);
+        check(&keys, &points, &leaves, "rank 1");
+
+        partner.send_with_tag(&keys[..], TAG_BLOCKS);
+        partner.send_with_tag(&points[..], TAG_POINTS);
+        partner.send_with_tag(&leaves[..], TAG_LEAVES);
+    }
+}
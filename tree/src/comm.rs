@@ -0,0 +1,395 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use mpi::{
+    collective::SystemOperation,
+    datatype::{Equivalence, PartitionMut},
+    environment::Universe,
+    topology::{Color, Rank, UserCommunicator},
+    traits::*,
+    Count, Tag,
+};
+
+use crate::error::TreeError;
+use crate::morton::{find_deepest_first_descendent, find_neighbors, Key, Keys, Point, Points};
+use crate::tree::{Octree, Tree};
+
+/// Tag of point to point messages carrying **Leaves**.
+pub const TAG_LEAVES: Tag = 1;
+/// Tag of point to point messages carrying **Points**.
+pub const TAG_POINTS: Tag = 2;
+/// Tag of point to point messages carrying **Blocks**.
+pub const TAG_BLOCKS: Tag = 3;
+
+/// Default maximum number of elements in a single message, see `set_max_msg_elements`.
+pub const DEFAULT_MAX_MSG_ELEMENTS: usize = 1 << 24;
+
+static MAX_MSG_ELEMENTS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_MSG_ELEMENTS);
+
+/// Maximum number of elements in a single message. Bulk transfers larger than this are split into
+/// fragments or rounds, keeping counts within the limits of MPI and of the transport (sequential).
+pub fn max_msg_elements() -> usize {
+    MAX_MSG_ELEMENTS.load(Ordering::Relaxed)
+}
+
+/// Set the maximum number of elements in a single message, for transports with smaller limits
+/// than the default. Limits are clamped to the counts MPI can represent, and must be the same at
+/// every process (sequential).
+pub fn set_max_msg_elements(max_elements: usize) {
+    let max_elements = max_elements.clamp(1, Count::MAX as usize);
+    MAX_MSG_ELEMENTS.store(max_elements, Ordering::Relaxed);
+}
+
+/// Initialize MPI, returning a **TreeError** explaining the likely cause rather than panicking if
+/// it is already initialized or the runtime fails to start (sequential).
+pub fn init() -> Result<Universe, TreeError> {
+    match catch_unwind(mpi::initialize) {
+        Ok(Some(universe)) => Ok(universe),
+        Ok(None) => Err(TreeError::MpiInit {
+            msg: "MPI is already initialized, keep the first Universe rather than initializing \
+                  again"
+                .to_string(),
+        }),
+        Err(payload) => {
+            let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "the MPI runtime failed to start".to_string()
+            };
+            Err(TreeError::MpiInit { msg })
+        }
+    }
+}
+
+/// Send a buffer of any length to a process, as its length followed by fragments of at most
+/// `max_elements` elements, all with the same tag. Received by `receive_fragmented` (sequential).
+pub fn send_fragmented<T, D>(process: &D, data: &[T], tag: Tag, max_elements: usize)
+where
+    T: Equivalence,
+    D: Destination,
+{
+    debug_assert!(max_elements > 0);
+
+    let len = data.len() as u64;
+    process.send_with_tag(&len, tag);
+
+    for fragment in data.chunks(max_elements) {
+        process.send_with_tag(fragment, tag);
+    }
+}
+
+/// Receive a buffer sent by `send_fragmented`, reassembling its fragments in order. The limit
+/// must match that of the sender (sequential).
+pub fn receive_fragmented<T, S>(process: &S, tag: Tag, max_elements: usize) -> Vec<T>
+where
+    T: Equivalence + Default + Clone,
+    S: Source,
+{
+    debug_assert!(max_elements > 0);
+
+    let mut len = 0u64;
+    process.receive_into_with_tag(&mut len, tag);
+
+    let mut buffer = vec![T::default(); len as usize];
+    for fragment in buffer.chunks_mut(max_elements) {
+        process.receive_into_with_tag(fragment, tag);
+    }
+    buffer
+}
+
+/// Displacements of variable length buffers packed contiguously, from their counts.
+pub(crate) fn displacements(counts: &[Count]) -> Vec<Count> {
+    counts
+        .iter()
+        .scan(0, |acc, &x| {
+            let tmp = *acc;
+            *acc += x;
+            Some(tmp)
+        })
+        .collect()
+}
+
+/// Find the global minimum and maximum **Morton Keys** across all processes. **Keys** have a custom
+/// ordering, so rather than reducing over their raw bytes each process gathers all local extrema
+/// and reduces them locally using `Ord for Key`. Processes without **Keys** contribute no extrema,
+/// and every process fails if none holds any (parallel).
+pub fn all_reduce_min_max<C: Communicator>(
+    keys: &[Key],
+    world: &C,
+) -> Result<(Key, Key), TreeError> {
+    let local: Keys = match (keys.iter().min(), keys.iter().max()) {
+        (Some(&min), Some(&max)) => vec![min, max],
+        _ => Vec::new(),
+    };
+
+    let received = all_gather_keys(&local, world);
+
+    match (received.iter().min(), received.iter().max()) {
+        (Some(&min), Some(&max)) => Ok((min, max)),
+        _ => Err(TreeError::EmptyPartition),
+    }
+}
+
+/// Split a communicator by coarse **Block** ownership. Each process is colored by the block of
+/// the global coarse blocktree containing its least **Leaf**, so processes whose **Leaves** start
+/// in the same coarse block share a sub-communicator. Processes without **Leaves** take no part
+/// and receive `None`. Fails at every process if no process holds coarse blocks, as for trees
+/// read from checkpoints written without them (parallel).
+pub fn split_by_block<C: Communicator>(
+    octree: &Octree,
+    world: &C,
+) -> Result<Option<UserCommunicator>, TreeError> {
+    let mut blocks = all_gather_keys(octree.coarse_blocks(), world);
+    if blocks.is_empty() {
+        return Err(TreeError::EmptyPartition);
+    }
+    blocks.sort();
+
+    // The containing block is the greatest block that doesn't follow the least leaf, and colors
+    // are its index in the blocktree.
+    let color = match octree.tree.values().flatten().map(|leaf| leaf.key).min() {
+        Some(min) => {
+            let idx = blocks.partition_point(|b| *b <= min).max(1) - 1;
+            Color::with_value(idx as Count)
+        }
+        None => Color::undefined(),
+    };
+
+    Ok(world.split_by_color(color))
+}
+
+/// Find the global axis aligned bounding box of a distributed set of **Points**, returned as its
+/// lower and upper corners (parallel).
+pub fn all_reduce_bounding_box<C: Communicator>(points: &[Point], world: &C) -> (Point, Point) {
+    let mut local_min = [f64::MAX; 3];
+    let mut local_max = [f64::MIN; 3];
+
+    for p in points.iter() {
+        for (i, &c) in [p.x, p.y, p.z].iter().enumerate() {
+            local_min[i] = local_min[i].min(c);
+            local_max[i] = local_max[i].max(c);
+        }
+    }
+
+    let mut global_min = [0f64; 3];
+    let mut global_max = [0f64; 3];
+    world.all_reduce_into(&local_min[..], &mut global_min[..], SystemOperation::min());
+    world.all_reduce_into(&local_max[..], &mut global_max[..], SystemOperation::max());
+
+    let lower = Point::new(global_min[0], global_min[1], global_min[2]);
+    let upper = Point::new(global_max[0], global_max[1], global_max[2]);
+
+    (lower, upper)
+}
+
+/// Gather a variable number of elements from every process onto all processes, concatenated in
+/// rank order (parallel).
+pub fn all_gatherv<T, C: Communicator>(local: &[T], world: &C) -> Vec<T>
+where
+    T: Equivalence + Default + Clone,
+{
+    let size = world.size();
+
+    let count = local.len() as Count;
+    let mut counts: Vec<Count> = vec![0; size as usize];
+    world.all_gather_into(&count, &mut counts[..]);
+
+    let displs = displacements(&counts);
+    let total: Count = counts.iter().sum();
+
+    let mut received = vec![T::default(); total as usize];
+    let mut partition = PartitionMut::new(&mut received[..], counts, &displs[..]);
+    world.all_gather_varcount_into(local, &mut partition);
+
+    received
+}
+
+/// Gather a variable number of elements from every process onto the root process, concatenated
+/// in rank order. Only the root process receives the gathered elements (parallel).
+pub fn gatherv<T, C: Communicator>(local: &[T], world: &C, root: Rank) -> Option<Vec<T>>
+where
+    T: Equivalence + Default + Clone,
+{
+    let root_process = world.process_at_rank(root);
+    let count = local.len() as Count;
+
+    if world.rank() == root {
+        let mut counts: Vec<Count> = vec![0; world.size() as usize];
+        root_process.gather_into_root(&count, &mut counts[..]);
+
+        let displs = displacements(&counts);
+        let total: Count = counts.iter().sum();
+
+        let mut received = vec![T::default(); total as usize];
+        let mut partition = PartitionMut::new(&mut received[..], counts, &displs[..]);
+        root_process.gather_varcount_into_root(local, &mut partition);
+
+        Some(received)
+    } else {
+        root_process.gather_into(&count);
+        root_process.gather_varcount_into(local);
+        None
+    }
+}
+
+/// Gather a variable number of **Keys** from every process onto all processes, in rank order
+/// (parallel).
+pub fn all_gather_keys<C: Communicator>(local: &[Key], world: &C) -> Keys {
+    all_gatherv(local, world)
+}
+
+/// Sum a count over all processes onto the root process, as a `u64` so that counts of leaves or
+/// points in large runs can't overflow. Only the root process receives the total (parallel).
+pub fn reduce_count<C: Communicator>(local: u64, world: &C, root: Rank) -> Option<u64> {
+    let root_process = world.process_at_rank(root);
+
+    if world.rank() == root {
+        let mut total: u64 = 0;
+        root_process.reduce_into_root(&local, &mut total, SystemOperation::sum());
+        Some(total)
+    } else {
+        root_process.reduce_into(&local, SystemOperation::sum());
+        None
+    }
+}
+
+/// Exclusive prefix sum of a value over the processes in rank order, the sum of the values at all
+/// lower ranks. Unlike `scan_into` the local value isn't included, and the first process receives
+/// zero (parallel).
+pub fn scan_exclusive<T, C: Communicator>(local: T, world: &C) -> T
+where
+    T: Equivalence + Default,
+{
+    let mut prefix = T::default();
+    world.exclusive_scan_into(&local, &mut prefix, &SystemOperation::sum());
+
+    // MPI leaves the result at the first process undefined.
+    if world.rank() == 0 {
+        T::default()
+    } else {
+        prefix
+    }
+}
+
+/// Gather a variable number of **Points** from every process onto the root process, in rank
+/// order. Only the root process receives the gathered **Points** (parallel).
+pub fn gatherv_points<C: Communicator>(local: &[Point], world: &C, root: Rank) -> Option<Points> {
+    gatherv(local, world, root)
+}
+
+/// Gather a variable number of **Keys** from every process onto the root process, sorted in Morton
+/// order with duplicates removed, for example to inspect the global blocktree. Only the root
+/// process receives the gathered **Keys** (parallel).
+pub fn reduce_keys_to_root<C: Communicator>(local: &[Key], world: &C, root: Rank) -> Option<Keys> {
+    gatherv(local, world, root).map(|mut received| {
+        received.sort();
+        received.dedup();
+        received
+    })
+}
+
+/// Count the **Leaves** across all processes with a neighbor owned by a different process, under
+/// the partition of the Morton curve whose i'th splitter is the least key owned by rank i. This
+/// estimates the communication cost of the partition (parallel).
+pub fn partition_surface_area<C: Communicator>(
+    tree: &Tree,
+    splitters: &[Key],
+    depth: &u64,
+    world: &C,
+) -> u64 {
+    let owner = |key: &Key| -> Rank {
+        let first = find_deepest_first_descendent(key, depth);
+        (splitters.partition_point(|s| *s <= first).max(1) - 1) as Rank
+    };
+    partition_surface_area_by(tree, owner, depth, world)
+}
+
+/// Count the **Leaves** across all processes with a neighbor owned by a different process, under
+/// an arbitrary assignment of octants to ranks (parallel).
+pub fn partition_surface_area_by<C: Communicator>(
+    tree: &Tree,
+    owner: impl Fn(&Key) -> Rank,
+    depth: &u64,
+    world: &C,
+) -> u64 {
+    let local: u64 = tree
+        .values()
+        .flatten()
+        .filter(|leaf| {
+            let rank = owner(&leaf.key);
+            find_neighbors(&leaf.key, depth)
+                .iter()
+                .any(|n| owner(n) != rank)
+        })
+        .count() as u64;
+
+    let mut global = 0;
+    world.all_reduce_into(&local, &mut global, SystemOperation::sum());
+    global
+}
+
+/// Block until all processes reach the barrier, returning the time spent waiting. Long waits
+/// indicate that this process finished its work well before the slowest one (parallel).
+pub fn barrier_timed<C: Communicator>(world: &C) -> Duration {
+    let start = Instant::now();
+    world.barrier();
+    start.elapsed()
+}
+
+/// Agree on the outcome of work which may fail at only some processes, so that every process
+/// returns together rather than leaving the others blocked in their next collective. A failing
+/// process returns its own error, and the others an error naming the first process to fail
+/// (parallel).
+pub fn agree<T, C: Communicator>(result: Result<T, TreeError>, world: &C) -> Result<T, TreeError> {
+    let size = world.size();
+
+    let failed = if result.is_err() { world.rank() } else { size };
+    let mut first_failed = size;
+    world.all_reduce_into(&failed, &mut first_failed, SystemOperation::min());
+
+    match result {
+        Err(e) => Err(e),
+        Ok(_) if first_failed < size => Err(TreeError::RankFailed { rank: first_failed }),
+        Ok(value) => Ok(value),
+    }
+}
+
+/// Run the work of a process, converting any panic into a **TreeError** recording the rank rather
+/// than letting it abort the whole job (sequential).
+pub fn run_guarded<T, F, C: Communicator>(f: F, world: &C) -> Result<T, TreeError>
+where
+    F: FnOnce() -> Result<T, TreeError>,
+{
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "unknown panic payload".to_string()
+            };
+
+            Err(TreeError::Panic {
+                rank: world.rank(),
+                msg,
+            })
+        }
+    }
+}
+
+/// Unwrap the result of guarded work, or print a diagnostic including the rank and cleanly abort
+/// every process in the job (parallel).
+pub fn abort_on_error<T, C: Communicator>(result: Result<T, TreeError>, world: &C) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("Rank {}: {}", world.rank(), e);
+            world.abort(1)
+        }
+    }
+}
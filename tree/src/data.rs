@@ -1,24 +1,343 @@
-use rand::Rng;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
 
-use crate::morton::{Point, Points};
+use mpi::traits::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::comm::all_gatherv;
+use crate::morton::{encode_points, Point, Points};
+
+/// Number of bytes in an interleaved (x, y, z) triple of f64 coordinates.
+const TRIPLE_BYTES: u64 = 24;
+
+/// Number of uniform samples used to estimate the maximum of a density.
+const DENSITY_SAMPLES: usize = 10000;
+
+/// Number of 32 bit words of a random stream consumed by drawing the three f64 coordinates of a
+/// point.
+const WORDS_PER_POINT: u64 = 6;
 
 /// Generate random distribution of PointsVec in range [0, 1),
-/// for testing.
+/// for testing. Points are indexed in order of generation.
 pub fn random(npoints: u64) -> Points {
     let mut range = rand::thread_rng();
 
     let mut points: Points = Vec::new();
 
-    for _ in 0..npoints {
+    for i in 0..npoints {
         let x: f64 = range.gen();
         let y: f64 = range.gen();
         let z: f64 = range.gen();
-        let mut p = Point::default();
-        p.x = x;
-        p.y = y;
-        p.z = z;
-        points.push(p);
+        points.push(Point::with_index(x, y, z, i as usize));
+    }
+
+    points
+}
+
+/// Generate a distribution of **Points** within a box, given by its lower and upper corners,
+/// sampled according to an unnormalised density by rejection sampling. The maximum density is
+/// estimated from uniform samples of the box, and raised if a larger value is found while
+/// sampling.
+pub fn random_density(
+    npoints: u64,
+    rho: impl Fn(f64, f64, f64) -> f64,
+    bounds: (Point, Point),
+) -> Points {
+    let mut range = rand::thread_rng();
+    let (lower, upper) = bounds;
+
+    let sample = |range: &mut rand::rngs::ThreadRng| -> (f64, f64, f64) {
+        (
+            lower.x + range.gen::<f64>() * (upper.x - lower.x),
+            lower.y + range.gen::<f64>() * (upper.y - lower.y),
+            lower.z + range.gen::<f64>() * (upper.z - lower.z),
+        )
+    };
+
+    let mut rho_max = (0..DENSITY_SAMPLES)
+        .map(|_| {
+            let (x, y, z) = sample(&mut range);
+            rho(x, y, z)
+        })
+        .fold(0., f64::max);
+
+    let mut points: Points = Vec::new();
+
+    while points.len() < npoints as usize {
+        let (x, y, z) = sample(&mut range);
+        let density = rho(x, y, z);
+        rho_max = rho_max.max(density);
+
+        if range.gen::<f64>() * rho_max < density {
+            points.push(Point::with_index(x, y, z, points.len()));
+        }
     }
 
     points
 }
+
+/// Find the offset and length of the contiguous range of a global set of `total` items held by a
+/// given rank, with any remainder spread over the first ranks.
+fn partition_range(total: u64, rank: u64, size: u64) -> (u64, u64) {
+    let n = total / size + if rank < total % size { 1 } else { 0 };
+    let offset = rank * (total / size) + rank.min(total % size);
+    (offset, n)
+}
+
+/// Generate this process's share of a reproducible global distribution of `total` random
+/// **Points** in range [0, 1). All points are drawn from a single stream seeded by `seed`, which
+/// each process skips ahead to its first point, so the union over all processes is independent
+/// of the number of processes.
+pub fn random_partitioned(total: u64, rank: u64, size: u64, seed: u64) -> Points {
+    let (offset, n) = partition_range(total, rank, size);
+
+    let mut range = ChaCha8Rng::seed_from_u64(seed);
+    range.set_word_pos((offset * WORDS_PER_POINT) as u128);
+
+    let mut points: Points = Vec::new();
+
+    for i in offset..(offset + n) {
+        points.push(Point::with_index(
+            range.gen(),
+            range.gen(),
+            range.gen(),
+            i as usize,
+        ));
+    }
+
+    points
+}
+
+/// Generate reproducible random **Points** uniformly distributed in the domain with centre `x0`
+/// and half side length `r0`, encoded at `depth` and indexed in order of generation.
+pub fn random_encoded(npoints: u64, depth: &u64, x0: &Point, r0: &f64, seed: u64) -> Points {
+    let mut range = StdRng::seed_from_u64(seed);
+
+    let width = 2. * r0;
+
+    let mut points: Points = (0..npoints)
+        .map(|i| {
+            Point::with_index(
+                x0.x - r0 + width * range.gen::<f64>(),
+                x0.y - r0 + width * range.gen::<f64>(),
+                x0.z - r0 + width * range.gen::<f64>(),
+                i as usize,
+            )
+        })
+        .collect();
+
+    encode_points(&mut points, depth, depth, x0, r0);
+    points
+}
+
+/// Read **Points** from a raw binary file of interleaved little endian f64 (x, y, z) triples. Each
+/// process reads a contiguous range of the file, and points are given global indices from their
+/// position in the file (parallel).
+pub fn read_raw_f64<P: AsRef<Path>, C: Communicator>(path: P, world: &C) -> io::Result<Points> {
+    let mut points: Points = Vec::new();
+    for chunk in read_raw_f64_chunked(path, usize::MAX, world)? {
+        points.extend(chunk?);
+    }
+    Ok(points)
+}
+
+/// **RawF64Chunks**, an iterator over the range of a raw binary file of (x, y, z) triples held by
+/// a process, yielding **Points** in chunks of at most `chunk_size` as they are read.
+pub struct RawF64Chunks {
+    reader: BufReader<File>,
+    next_idx: u64,
+    end_idx: u64,
+    chunk_size: usize,
+}
+
+impl Iterator for RawF64Chunks {
+    type Item = io::Result<Points>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_idx == self.end_idx {
+            return None;
+        }
+
+        let n = (self.end_idx - self.next_idx).min(self.chunk_size as u64);
+        let mut points: Points = Vec::new();
+        let mut buf = [0u8; 8];
+
+        for i in self.next_idx..(self.next_idx + n) {
+            let mut coords = [0f64; 3];
+            for coord in coords.iter_mut() {
+                if let Err(e) = self.reader.read_exact(&mut buf) {
+                    // Stop after the first error, rather than reading a misaligned remainder.
+                    self.next_idx = self.end_idx;
+                    return Some(Err(e));
+                }
+                *coord = f64::from_le_bytes(buf);
+            }
+            let [x, y, z] = coords;
+            points.push(Point::with_index(x, y, z, i as usize));
+        }
+
+        self.next_idx += n;
+        Some(Ok(points))
+    }
+}
+
+/// Read **Points** from a raw binary file of interleaved little endian f64 (x, y, z) triples in
+/// chunks, so that a process never holds more than `chunk_size` unprocessed points. Each process
+/// reads the same contiguous range of the file as `read_raw_f64` (parallel).
+pub fn read_raw_f64_chunked<P: AsRef<Path>, C: Communicator>(
+    path: P,
+    chunk_size: usize,
+    world: &C,
+) -> io::Result<RawF64Chunks> {
+    debug_assert!(chunk_size > 0);
+
+    let rank = world.rank() as u64;
+    let size = world.size() as u64;
+
+    let mut file = File::open(path)?;
+    let ntriples = file.metadata()?.len() / TRIPLE_BYTES;
+
+    let (offset, nlocal) = partition_range(ntriples, rank, size);
+    file.seek(SeekFrom::Start(offset * TRIPLE_BYTES))?;
+
+    Ok(RawF64Chunks {
+        reader: BufReader::new(file),
+        next_idx: offset,
+        end_idx: offset + nlocal,
+        chunk_size,
+    })
+}
+
+/// Gather a variable number of **Points** from every process onto all processes, concatenated in
+/// rank order. Useful for comparing a distributed result against a sequential baseline in tests
+/// (parallel).
+pub fn gather_all_points<C: Communicator>(local: &[Point], world: &C) -> Points {
+    all_gatherv(local, world)
+}
+
+/// Merge **Points** that fall in the same octant at the finest level of discretisation, so that
+/// coincident points can't overflow a leaf. Each merged point is represented by the first point
+/// found in its octant, along with its multiplicity. Points are encoded inplace (sequential).
+pub fn dedup_on_grid(points: &mut Points, depth: &u64, x0: &Point, r0: &f64) -> Vec<(Point, u32)> {
+    encode_points(points, depth, depth, x0, r0);
+    points.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut merged: Vec<(Point, u32)> = Vec::new();
+
+    for &point in points.iter() {
+        match merged.last_mut() {
+            Some((last, multiplicity)) if last.key == point.key => *multiplicity += 1,
+            _ => merged.push((point, 1)),
+        }
+    }
+
+    merged
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_partitioned() {
+        let total = 1001;
+        let seed = 42;
+
+        let gather = |size: u64| -> Points {
+            (0..size)
+                .flat_map(|rank| random_partitioned(total, rank, size, seed))
+                .collect()
+        };
+
+        // Test that the global set doesn't depend on the number of processes
+        let a = gather(2);
+        let b = gather(4);
+        assert_eq!(a.len(), total as usize);
+        assert_eq!(a, b);
+
+        for (i, p) in a.iter().enumerate() {
+            assert_eq!(p.global_idx, i);
+        }
+
+        // Test that a different seed gives a different set
+        let c = random_partitioned(total, 0, 1, seed + 1);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_dedup_on_grid() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let a = Point::new(0.3, 0.3, 0.3);
+        let mut b = a;
+        b.x = 0.9;
+        b.global_idx = 1;
+        let mut c = a;
+        c.global_idx = 2;
+
+        let mut points: Points = vec![a, b, c];
+        let merged = dedup_on_grid(&mut points, &depth, &x0, &r0);
+
+        // Test that the coincident points collapse to one entry
+        assert_eq!(merged.len(), 2);
+        let total: u32 = merged.iter().map(|(_, m)| m).sum();
+        assert_eq!(total, 3);
+
+        let (point, multiplicity) = merged.iter().find(|(p, _)| p.approx_eq(&a, 1e-12)).unwrap();
+        assert_eq!(*multiplicity, 2);
+        assert_eq!(point.global_idx, a.global_idx);
+    }
+
+    #[test]
+    fn test_random_density() {
+        let npoints = 1000;
+        let bounds = (Point::new(0., 0., 0.), Point::new(1., 1., 1.));
+
+        // Density one hundred times larger in the lower octant
+        let rho = |x: f64, y: f64, z: f64| {
+            if (x < 0.5) & (y < 0.5) & (z < 0.5) {
+                100.
+            } else {
+                1.
+            }
+        };
+
+        let points = random_density(npoints, rho, bounds);
+        assert_eq!(points.len(), npoints as usize);
+
+        // Test that most points lie in the dense octant
+        let ndense = points
+            .iter()
+            .filter(|p| (p.x < 0.5) & (p.y < 0.5) & (p.z < 0.5))
+            .count();
+        assert!(ndense > 4 * npoints as usize / 5);
+    }
+
+    #[test]
+    fn test_random_encoded() {
+        use crate::morton::Key;
+
+        let depth = 4;
+        let x0 = Point::new(1., -2., 0.5);
+        let r0 = 2.;
+
+        let points = random_encoded(1000, &depth, &x0, &r0, 7);
+
+        // Test that every point has a key at the given depth within range
+        let max = 1 << depth;
+        for point in points.iter() {
+            assert_ne!(point.key, Key::default());
+            assert_eq!(point.key.3, depth);
+            assert!((point.key.0 < max) & (point.key.1 < max) & (point.key.2 < max));
+        }
+
+        // Test that the points are reproducible from their seed
+        let again = random_encoded(1000, &depth, &x0, &r0, 7);
+        for (a, b) in points.iter().zip(again.iter()) {
+            assert_eq!((a.x, a.y, a.z, a.key), (b.x, b.y, b.z, b.key));
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use mpi::topology::Rank;
+
+use crate::morton::quad::Key2;
+use crate::morton::Key;
+
+#[derive(Debug, Clone, PartialEq)]
+/// **TreeError**, the ways in which constructing or querying a tree can fail.
+pub enum TreeError {
+    /// A **Leaf** holds more than `ncrit` points, which a deeper tree would separate.
+    LeafOverflow { key: Key, npoints: usize },
+    /// A **Leaf** holds more than `ncrit` coincident points, which no depth of tree can separate.
+    IrreducibleDuplicates { key: Key, npoints: usize },
+    /// A **Block** at the maximum depth holds more than `ncrit` points, so can't be split.
+    CannotSatisfyNcrit {
+        block: Key,
+        npoints: usize,
+        ncrit: usize,
+    },
+    /// A quadtree **Block** at the maximum depth holds more than `ncrit` points, so can't be split.
+    QuadtreeNcrit {
+        block: Key2,
+        npoints: usize,
+        ncrit: usize,
+    },
+    /// A process holds no **Leaves**, so has no interval of the Morton curve to seed or partition.
+    EmptyPartition,
+    /// Consecutive **Seeds** at a process are out of Morton order, so can't be completed.
+    UnsortedSeeds { a: Key, b: Key },
+    /// A point lies outside of the domain with centre `x0` and half side length `r0`.
+    OutOfDomain { x: f64, y: f64, z: f64 },
+    /// A path from the root doesn't describe a **Morton Key** within the depth of the tree.
+    InvalidPath { path: String },
+    /// A level is deeper than the depth of the tree, or the depth is too deep for a **Morton Key**.
+    InvalidLevel { level: u64, depth: u64 },
+    /// A field attached to the **Leaves** doesn't have one value per **Leaf**.
+    FieldSize {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// A **Leaf** holds more points than are retained inside of it, so it can't be split.
+    MissingPoints {
+        leaf: Key,
+        expected: usize,
+        found: usize,
+    },
+    /// Splitting and balancing the **Leaves** didn't reach a fixed point within a number of rounds.
+    RefinementLimit { rounds: usize },
+    /// MPI couldn't be initialized, because it already was or the runtime isn't usable.
+    MpiInit { msg: String },
+    /// Reading input **Points** failed.
+    Io { msg: String },
+    /// Another process failed while streaming its **Points**, so the collective build was abandoned.
+    StreamFailed { rank: Rank },
+    /// Another process failed during a collective operation, so it was abandoned at every process.
+    RankFailed { rank: Rank },
+    /// A process panicked, caught at an MPI entry point.
+    Panic { rank: Rank, msg: String },
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeError::LeafOverflow { key, npoints } => write!(
+                f,
+                "Leaf {:?} contains {} points, you are packing too many points into a leaf, \
+                 increase tree depth!",
+                key, npoints
+            ),
+            TreeError::IrreducibleDuplicates { key, npoints } => write!(
+                f,
+                "Leaf {:?} contains {} coincident points, which can't be separated by refining \
+                 the tree, increase ncrit or remove duplicates!",
+                key, npoints
+            ),
+            TreeError::CannotSatisfyNcrit {
+                block,
+                npoints,
+                ncrit,
+            } => write!(
+                f,
+                "Block {:?} at the maximum depth contains {} points, which can't be split to \
+                 satisfy ncrit = {}",
+                block, npoints, ncrit
+            ),
+            TreeError::QuadtreeNcrit {
+                block,
+                npoints,
+                ncrit,
+            } => write!(
+                f,
+                "Quadtree block {:?} at the maximum depth contains {} points, which can't be \
+                 split to satisfy ncrit = {}",
+                block, npoints, ncrit
+            ),
+            TreeError::EmptyPartition => write!(
+                f,
+                "Process holds no leaves, use fewer processes or more points!"
+            ),
+            TreeError::UnsortedSeeds { a, b } => write!(
+                f,
+                "Seeds {:?} and {:?} are out of Morton order, seeds must be sorted!",
+                a, b
+            ),
+            TreeError::OutOfDomain { x, y, z } => write!(
+                f,
+                "Point ({}, {}, {}) lies outside of the domain, increase r0!",
+                x, y, z
+            ),
+            TreeError::InvalidPath { path } => write!(
+                f,
+                "Path '{}' isn't a sequence of child octants from 0 to 7 within the tree depth!",
+                path
+            ),
+            TreeError::InvalidLevel { level, depth } => write!(
+                f,
+                "Level {} is invalid in a tree of depth {}, levels can't be deeper than the depth, \
+                 which must be less than 64!",
+                level, depth
+            ),
+            TreeError::FieldSize {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Field {} has {} values, expected one for each of the {} leaves!",
+                name, found, expected
+            ),
+            TreeError::RefinementLimit { rounds } => write!(
+                f,
+                "Splitting and balancing leaves didn't converge within {} rounds!",
+                rounds
+            ),
+            TreeError::MpiInit { msg } => write!(
+                f,
+                "Failed to initialize MPI: {}, check that an MPI runtime is installed and that the \
+                 program is launched with mpirun!",
+                msg
+            ),
+            TreeError::Io { msg } => write!(f, "Failed to read points: {}", msg),
+            TreeError::StreamFailed { rank } => write!(
+                f,
+                "Rank {} failed while streaming its points, see its error for the cause",
+                rank
+            ),
+            TreeError::MissingPoints {
+                leaf,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Leaf {:?} holds {} points but only {} are retained inside of it, keep the points \
+                 of every leaf to refine it!",
+                leaf, expected, found
+            ),
+            TreeError::RankFailed { rank } => write!(
+                f,
+                "Rank {} failed during a collective operation, see its error for the cause",
+                rank
+            ),
+            TreeError::Panic { rank, msg } => write!(f, "Rank {} panicked: {}", rank, msg),
+        }
+    }
+}
+
+impl Error for TreeError {}
+
+impl From<io::Error> for TreeError {
+    fn from(e: io::Error) -> Self {
+        TreeError::Io { msg: e.to_string() }
+    }
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let key = Key(1, 2, 3, 2);
+        let errors = vec![
+            TreeError::LeafOverflow { key, npoints: 10 },
+            TreeError::IrreducibleDuplicates { key, npoints: 10 },
+            TreeError::CannotSatisfyNcrit {
+                block: key,
+                npoints: 10,
+                ncrit: 5,
+            },
+            TreeError::EmptyPartition,
+            TreeError::UnsortedSeeds {
+                a: key,
+                b: Key(0, 0, 0, 1),
+            },
+            TreeError::OutOfDomain {
+                x: 1.5,
+                y: 0.5,
+                z: 0.5,
+            },
+            TreeError::InvalidPath {
+                path: "0/9".to_string(),
+            },
+            TreeError::FieldSize {
+                name: "potential".to_string(),
+                expected: 8,
+                found: 7,
+            },
+            TreeError::RefinementLimit { rounds: 64 },
+            TreeError::MpiInit {
+                msg: "already initialized".to_string(),
+            },
+            TreeError::Io {
+                msg: "unexpected end of file".to_string(),
+            },
+            TreeError::StreamFailed { rank: 3 },
+            TreeError::Panic {
+                rank: 2,
+                msg: "message".to_string(),
+            },
+            TreeError::InvalidLevel { level: 5, depth: 4 },
+            TreeError::RankFailed { rank: 1 },
+            TreeError::MissingPoints {
+                leaf: Key(0, 0, 0, 1),
+                expected: 20,
+                found: 0,
+            },
+            TreeError::QuadtreeNcrit {
+                block: Key2(4, 4, 3),
+                npoints: 12,
+                ncrit: 6,
+            },
+        ];
+
+        // Test that every variant formats to a distinct non empty message
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        for (i, msg) in messages.iter().enumerate() {
+            assert!(!msg.is_empty());
+            assert!(messages[i + 1..].iter().all(|m| m != msg));
+        }
+
+        // Test that each variant is usable as a boxed standard error
+        for e in errors.into_iter() {
+            let boxed: Box<dyn Error> = Box::new(e.clone());
+            assert_eq!(boxed.to_string(), e.to_string());
+        }
+
+        assert!(messages[5].contains("1.5"));
+        assert!(messages[6].contains("0/9"));
+        assert!(messages[7].contains("potential"));
+        assert!(messages[8].contains("64"));
+        assert!(messages[9].contains("already initialized"));
+        assert!(messages[10].contains("unexpected end of file"));
+        assert!(messages[11].contains("Rank 3"));
+        assert!(messages[12].contains("Rank 2"));
+        assert!(messages[13].contains("Level 5"));
+        assert!(messages[14].contains("Rank 1"));
+        assert!(messages[15].contains("holds 20 points"));
+        assert!(messages[16].contains("Quadtree block Key2(4, 4, 3)"));
+
+        // Test that I/O errors convert to a TreeError carrying their message
+        let e: TreeError = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated").into();
+        assert_eq!(
+            e,
+            TreeError::Io {
+                msg: "truncated".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,795 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use mpi::{topology::Rank, traits::*};
+#[cfg(feature = "zip")]
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::morton::{check_level, side_shift, Key, Keys, Leaf, Leaves, Point, Points};
+#[cfg(feature = "ndarray")]
+use crate::tree::Fields;
+use crate::tree::{Octree, Tree};
+
+/// Name of the manifest file in a checkpoint directory.
+const MANIFEST: &str = "manifest";
+
+/// Magic bytes beginning the **Tree Header** of each checkpoint file.
+const HEADER_MAGIC: &[u8; 8] = b"DTREEHDR";
+
+/// VTK cell type of a hexahedron.
+const VTK_HEXAHEDRON: u8 = 12;
+
+/// Magic string and format version (1.0) beginning a NumPy `.npy` file.
+#[cfg(feature = "zip")]
+const NPY_MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+
+/// Corners of the unit cube, in the vertex order of a VTK hexahedron.
+const HEX_CORNERS: [(u64, u64, u64); 8] = [
+    (0, 0, 0),
+    (1, 0, 0),
+    (1, 1, 0),
+    (0, 1, 0),
+    (0, 0, 1),
+    (1, 0, 1),
+    (1, 1, 1),
+    (0, 1, 1),
+];
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_f64<W: Write>(writer: &mut W, value: f64) -> io::Result<()> {
+    write_u64(writer, value.to_bits())
+}
+
+fn read_f64<R: Read>(reader: &mut R) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(reader)?))
+}
+
+fn write_key<W: Write>(writer: &mut W, key: &Key) -> io::Result<()> {
+    write_u64(writer, key.0)?;
+    write_u64(writer, key.1)?;
+    write_u64(writer, key.2)?;
+    write_u64(writer, key.3)
+}
+
+fn read_key<R: Read>(reader: &mut R) -> io::Result<Key> {
+    Ok(Key(
+        read_u64(reader)?,
+        read_u64(reader)?,
+        read_u64(reader)?,
+        read_u64(reader)?,
+    ))
+}
+
+fn write_point<W: Write>(writer: &mut W, point: &Point) -> io::Result<()> {
+    write_f64(writer, point.x)?;
+    write_f64(writer, point.y)?;
+    write_f64(writer, point.z)?;
+    write_key(writer, &point.key)?;
+    write_u64(writer, point.global_idx as u64)
+}
+
+fn read_point<R: Read>(reader: &mut R) -> io::Result<Point> {
+    Ok(Point {
+        x: read_f64(reader)?,
+        y: read_f64(reader)?,
+        z: read_f64(reader)?,
+        key: read_key(reader)?,
+        global_idx: read_u64(reader)? as usize,
+    })
+}
+
+/// Write named field data, as the number of fields followed by the name and values of each, in
+/// order of their names.
+#[cfg(feature = "ndarray")]
+fn write_fields<W: Write>(writer: &mut W, fields: &Fields) -> io::Result<()> {
+    let mut names: Vec<&String> = fields.keys().collect();
+    names.sort();
+
+    write_u64(writer, names.len() as u64)?;
+    for name in names {
+        write_u64(writer, name.len() as u64)?;
+        writer.write_all(name.as_bytes())?;
+
+        let values = &fields[name];
+        write_u64(writer, values.len() as u64)?;
+        for &value in values.iter() {
+            write_f64(writer, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read named field data written by `write_fields`, failing if a field doesn't hold a value for
+/// each of `nleaves` **Leaves**.
+fn read_fields<R: Read>(reader: &mut R, nleaves: usize) -> io::Result<Vec<(String, Vec<f64>)>> {
+    let nfields = read_u64(reader)?;
+    let mut fields = Vec::new();
+
+    for _ in 0..nfields {
+        let mut name = vec![0u8; read_u64(reader)? as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|e| invalid_data(e.to_string()))?;
+
+        let nvalues = read_u64(reader)? as usize;
+        if nvalues != nleaves {
+            return Err(invalid_data(format!(
+                "Field '{}' has {} values, but there are {} leaves",
+                name, nvalues, nleaves
+            )));
+        }
+
+        let values = (0..nvalues)
+            .map(|_| read_f64(reader))
+            .collect::<io::Result<Vec<f64>>>()?;
+        fields.push((name, values));
+    }
+    Ok(fields)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Clone, Copy)]
+/// **Tree Header**, the depth, `ncrit` and domain of a saved tree. The **Keys** of a tree are
+/// meaningless without them, so every writer embeds them alongside the **Leaves**.
+pub struct TreeHeader {
+    pub depth: u64,
+    pub ncrit: usize,
+    pub x0: Point,
+    pub r0: f64,
+}
+
+impl TreeHeader {
+    /// The **Tree Header** of an **Octree** (sequential).
+    pub fn of(octree: &Octree) -> TreeHeader {
+        TreeHeader {
+            depth: octree.depth,
+            ncrit: octree.ncrit,
+            x0: octree.x0,
+            r0: octree.r0,
+        }
+    }
+
+    /// Check that this header agrees exactly with an expected one, failing with an error naming
+    /// the first field which differs (sequential).
+    pub fn check(&self, expected: &TreeHeader) -> io::Result<()> {
+        let fields = [
+            ("depth", self.depth as f64, expected.depth as f64),
+            ("ncrit", self.ncrit as f64, expected.ncrit as f64),
+            ("x0.x", self.x0.x, expected.x0.x),
+            ("x0.y", self.x0.y, expected.x0.y),
+            ("x0.z", self.x0.z, expected.x0.z),
+            ("r0", self.r0, expected.r0),
+        ];
+
+        match fields.iter().find(|(_, found, wanted)| found != wanted) {
+            Some((name, found, wanted)) => Err(invalid_data(format!(
+                "Inconsistent tree header, '{}' is {} but {} was expected",
+                name, found, wanted
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, header: &TreeHeader) -> io::Result<()> {
+    writer.write_all(HEADER_MAGIC)?;
+    write_u64(writer, header.depth)?;
+    write_u64(writer, header.ncrit as u64)?;
+    write_f64(writer, header.x0.x)?;
+    write_f64(writer, header.x0.y)?;
+    write_f64(writer, header.x0.z)?;
+    write_f64(writer, header.r0)
+}
+
+fn read_header<R: Read>(reader: &mut R) -> io::Result<TreeHeader> {
+    // A file too short to hold the magic has no header, any other read error is passed on.
+    let mut magic = [0u8; 8];
+    match reader.read_exact(&mut magic) {
+        Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e),
+        Ok(()) if &magic == HEADER_MAGIC => {}
+        _ => return Err(invalid_data("Missing tree header".to_string())),
+    }
+
+    Ok(TreeHeader {
+        depth: read_u64(reader)?,
+        ncrit: read_u64(reader)? as usize,
+        x0: Point::new(read_f64(reader)?, read_f64(reader)?, read_f64(reader)?),
+        r0: read_f64(reader)?,
+    })
+}
+
+/// Parse the `idx`th value of a named parameter from a checkpoint manifest.
+fn manifest_param<T: FromStr>(
+    params: &HashMap<String, Vec<String>>,
+    name: &str,
+    idx: usize,
+) -> io::Result<T> {
+    params
+        .get(name)
+        .and_then(|values| values.get(idx))
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid_data(format!("Missing or malformed '{}' in manifest", name)))
+}
+
+fn checkpoint_file(dir: &Path, rank: i32) -> PathBuf {
+    dir.join(format!("rank_{}.bin", rank))
+}
+
+fn write_local_checkpoint(octree: &Octree, dir: &Path, rank: i32, size: i32) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    if rank == 0 {
+        let mut manifest = File::create(dir.join(MANIFEST))?;
+        writeln!(manifest, "depth {}", octree.depth)?;
+        writeln!(manifest, "ncrit {}", octree.ncrit)?;
+        writeln!(manifest, "x0 {} {} {}", octree.x0.x, octree.x0.y, octree.x0.z)?;
+        writeln!(manifest, "r0 {}", octree.r0)?;
+        writeln!(manifest, "size {}", size)?;
+    }
+
+    let mut writer = BufWriter::new(File::create(checkpoint_file(dir, rank))?);
+
+    write_header(&mut writer, &TreeHeader::of(octree))?;
+    write_u64(&mut writer, octree.tree.len() as u64)?;
+    for (block, leaves) in octree.tree.iter() {
+        write_key(&mut writer, block)?;
+        write_u64(&mut writer, leaves.len() as u64)?;
+        for leaf in leaves.iter() {
+            write_key(&mut writer, &leaf.key)?;
+            write_key(&mut writer, &leaf.block)?;
+            write_u64(&mut writer, leaf.npoints as u64)?;
+
+            let indices = octree.point_indices(&leaf.key).unwrap_or(&[]);
+            write_u64(&mut writer, indices.len() as u64)?;
+            for &idx in indices.iter() {
+                write_u64(&mut writer, idx as u64)?;
+            }
+        }
+    }
+
+    // Field data is only attached when built with the ndarray feature.
+    #[cfg(feature = "ndarray")]
+    write_fields(&mut writer, &octree.fields)?;
+    #[cfg(not(feature = "ndarray"))]
+    write_u64(&mut writer, 0)?;
+
+    write_u64(&mut writer, octree.local_leaves().is_some() as u64)?;
+
+    let coarse_blocks = octree.coarse_blocks();
+    write_u64(&mut writer, coarse_blocks.len() as u64)?;
+    for block in coarse_blocks.iter() {
+        write_key(&mut writer, block)?;
+    }
+
+    write_u64(&mut writer, octree.points.len() as u64)?;
+    for point in octree.points.iter() {
+        write_point(&mut writer, point)?;
+    }
+
+    writer.flush()
+}
+
+/// Write a checkpoint of a distributed **Octree** to a directory, as one binary file per process
+/// beginning with its **Tree Header** and holding its **Leaves**, point indices, fields, whether
+/// sorted **Leaves** are kept, its coarse **Blocks** and **Points**, and a manifest recording the
+/// depth, `ncrit`, domain and number of processes (parallel).
+pub fn write_checkpoint<P: AsRef<Path>, C: Communicator>(
+    octree: &Octree,
+    world: &C,
+    dir: P,
+) -> io::Result<()> {
+    let result = write_local_checkpoint(octree, dir.as_ref(), world.rank(), world.size());
+
+    // Checkpoint is only complete once every process has written its file.
+    world.barrier();
+    result
+}
+
+/// Load the local **Octree** at this process from a checkpoint directory. Fails if the checkpoint
+/// was written by a different number of processes than the current communicator, or if the
+/// **Tree Header** of its file is missing or disagrees with the manifest (parallel).
+pub fn load_checkpoint<P: AsRef<Path>, C: Communicator>(dir: P, world: &C) -> io::Result<Octree> {
+    load_local_checkpoint(dir.as_ref(), None, world)
+}
+
+/// Load the local **Octree** at this process from a checkpoint directory, as `load_checkpoint`,
+/// also failing if the checkpoint was written at a different depth than expected (parallel).
+pub fn load_checkpoint_at_depth<P: AsRef<Path>, C: Communicator>(
+    dir: P,
+    depth: u64,
+    world: &C,
+) -> io::Result<Octree> {
+    load_local_checkpoint(dir.as_ref(), Some(depth), world)
+}
+
+fn load_local_checkpoint<C: Communicator>(
+    dir: &Path,
+    depth: Option<u64>,
+    world: &C,
+) -> io::Result<Octree> {
+    let rank = world.rank();
+    let size = world.size();
+
+    let mut params: HashMap<String, Vec<String>> = HashMap::new();
+    for line in BufReader::new(File::open(dir.join(MANIFEST))?).lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        if let Some(name) = fields.next() {
+            params.insert(name.to_string(), fields.map(String::from).collect());
+        }
+    }
+
+    let nprocs: i32 = manifest_param(&params, "size", 0)?;
+    if nprocs != size {
+        return Err(invalid_data(format!(
+            "Checkpoint was written by {} processes, but the communicator has {}",
+            nprocs, size
+        )));
+    }
+
+    let manifest = TreeHeader {
+        depth: manifest_param(&params, "depth", 0)?,
+        ncrit: manifest_param(&params, "ncrit", 0)?,
+        x0: Point::new(
+            manifest_param(&params, "x0", 0)?,
+            manifest_param(&params, "x0", 1)?,
+            manifest_param(&params, "x0", 2)?,
+        ),
+        r0: manifest_param(&params, "r0", 0)?,
+    };
+
+    let mut reader = BufReader::new(File::open(checkpoint_file(dir, rank))?);
+    let header = read_header(&mut reader)?;
+    header.check(&manifest)?;
+    check_level(&header.depth, &0).map_err(|e| invalid_data(e.to_string()))?;
+
+    if let Some(depth) = depth {
+        header.check(&TreeHeader { depth, ..header })?;
+    }
+    let mut tree: Tree = HashMap::new();
+    let mut indices: HashMap<Key, Vec<usize>> = HashMap::new();
+
+    let nblocks = read_u64(&mut reader)?;
+    for _ in 0..nblocks {
+        let block = read_key(&mut reader)?;
+        let nleaves = read_u64(&mut reader)?;
+        let mut leaves: Leaves = Vec::new();
+        for _ in 0..nleaves {
+            let leaf = Leaf {
+                key: read_key(&mut reader)?,
+                block: read_key(&mut reader)?,
+                npoints: read_u64(&mut reader)? as usize,
+            };
+
+            let nindices = read_u64(&mut reader)?;
+            if nindices > 0 {
+                let entry = indices.entry(leaf.key).or_default();
+                for _ in 0..nindices {
+                    entry.push(read_u64(&mut reader)? as usize);
+                }
+            }
+            leaves.push(leaf);
+        }
+        tree.insert(block, leaves);
+    }
+
+    // Fields are skipped unless built with the ndarray feature.
+    let nleaves = tree.values().map(|leaves| leaves.len()).sum();
+    #[cfg(feature = "ndarray")]
+    let fields = read_fields(&mut reader, nleaves)?.into_iter().collect();
+    #[cfg(not(feature = "ndarray"))]
+    read_fields(&mut reader, nleaves)?;
+
+    let keeps_sorted_leaves = read_u64(&mut reader)? != 0;
+
+    let ncoarse = read_u64(&mut reader)?;
+    let coarse_blocks = (0..ncoarse)
+        .map(|_| read_key(&mut reader))
+        .collect::<io::Result<Keys>>()?;
+
+    let npoints = read_u64(&mut reader)?;
+    let points = (0..npoints)
+        .map(|_| read_point(&mut reader))
+        .collect::<io::Result<Points>>()?;
+
+    let mut octree = Octree {
+        tree,
+        sorted_leaves: None,
+        coarse_blocks,
+        indices,
+        points,
+        #[cfg(feature = "ndarray")]
+        fields,
+        depth: header.depth,
+        ncrit: header.ncrit,
+        x0: header.x0,
+        r0: header.r0,
+    };
+
+    if keeps_sorted_leaves {
+        octree.keep_sorted_leaves();
+    }
+    Ok(octree)
+}
+
+/// Write the local **Leaves** as hexahedral cells of a legacy VTK file, with a cell data array
+/// recording the rank that owns them, and the **Tree Header** in its title. Loading every rank's
+/// file together colours the domain by ownership, for visualising the quality of a partition
+/// (sequential).
+pub fn write_partition_vtk<P: AsRef<Path>>(
+    tree: &Tree,
+    rank: Rank,
+    header: &TreeHeader,
+    path: P,
+) -> io::Result<()> {
+    let TreeHeader {
+        depth,
+        ncrit,
+        x0,
+        r0,
+    } = header;
+
+    let mut leaves: Leaves = tree.values().flatten().copied().collect();
+    leaves.sort();
+    let ncells = leaves.len();
+
+    let side_length = (r0 * 2.) / (side_shift(depth, &0) as f64);
+    let origin = (x0.x - r0, x0.y - r0, x0.z - r0);
+
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writeln!(writer, "# vtk DataFile Version 3.0")?;
+    writeln!(
+        writer,
+        "Partition of rank {} depth {} ncrit {} x0 {} {} {} r0 {}",
+        rank, depth, ncrit, x0.x, x0.y, x0.z, r0
+    )?;
+    writeln!(writer, "ASCII")?;
+    writeln!(writer, "DATASET UNSTRUCTURED_GRID")?;
+
+    writeln!(writer, "POINTS {} double", 8 * ncells)?;
+    for leaf in leaves.iter() {
+        let width = side_shift(depth, &leaf.key.3);
+        for &(i, j, k) in HEX_CORNERS.iter() {
+            writeln!(
+                writer,
+                "{} {} {}",
+                origin.0 + ((leaf.key.0 + i * width) as f64) * side_length,
+                origin.1 + ((leaf.key.1 + j * width) as f64) * side_length,
+                origin.2 + ((leaf.key.2 + k * width) as f64) * side_length
+            )?;
+        }
+    }
+
+    writeln!(writer, "CELLS {} {}", ncells, 9 * ncells)?;
+    for i in 0..ncells {
+        let vertices: Vec<String> = (8 * i..8 * (i + 1)).map(|v| v.to_string()).collect();
+        writeln!(writer, "8 {}", vertices.join(" "))?;
+    }
+
+    writeln!(writer, "CELL_TYPES {}", ncells)?;
+    for _ in 0..ncells {
+        writeln!(writer, "{}", VTK_HEXAHEDRON)?;
+    }
+
+    writeln!(writer, "CELL_DATA {}", ncells)?;
+    writeln!(writer, "SCALARS rank int 1")?;
+    writeln!(writer, "LOOKUP_TABLE default")?;
+    for _ in 0..ncells {
+        writeln!(writer, "{}", rank)?;
+    }
+
+    writer.flush()
+}
+
+/// Write **Points** paired with their **Leaf**, **Block** and owning rank, as exported by
+/// `Octree::export_points_with_owner`, to a CSV file. The **Tree Header** is written first as a
+/// `#` comment line, followed by a header row (sequential).
+pub fn write_points_with_owner_csv<P: AsRef<Path>>(
+    rows: &[(Point, Key, Key, Rank)],
+    header: &TreeHeader,
+    path: P,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writeln!(
+        writer,
+        "# depth {} ncrit {} x0 {} {} {} r0 {}",
+        header.depth, header.ncrit, header.x0.x, header.x0.y, header.x0.z, header.r0
+    )?;
+    writeln!(
+        writer,
+        "x,y,z,leaf_x,leaf_y,leaf_z,leaf_level,block_x,block_y,block_z,block_level,rank"
+    )?;
+    for (point, leaf, block, rank) in rows.iter() {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            point.x,
+            point.y,
+            point.z,
+            leaf.0,
+            leaf.1,
+            leaf.2,
+            leaf.3,
+            block.0,
+            block.1,
+            block.2,
+            block.3,
+            rank
+        )?;
+    }
+
+    writer.flush()
+}
+
+/// Serialise a C ordered array of little endian int64 values in the NumPy `.npy` format.
+#[cfg(feature = "zip")]
+fn npy_i64(shape: &[usize], data: &[i64]) -> Vec<u8> {
+    let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+    npy("<i8", shape, &bytes)
+}
+
+/// Serialise a C ordered array of little endian float64 values in the NumPy `.npy` format.
+#[cfg(feature = "zip")]
+fn npy_f64(shape: &[usize], data: &[f64]) -> Vec<u8> {
+    let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+    npy("<f8", shape, &bytes)
+}
+
+/// Serialise a C ordered array of little endian values of a NumPy type in the `.npy` format. The
+/// header is padded so that the data is aligned to 64 bytes, as NumPy writes it.
+#[cfg(feature = "zip")]
+fn npy(descr: &str, shape: &[usize], data: &[u8]) -> Vec<u8> {
+    // One dimensional shapes are written as Python tuples, with a trailing comma.
+    let dims: Vec<String> = shape.iter().map(|n| n.to_string()).collect();
+    let shape = if dims.len() == 1 {
+        format!("({},)", dims[0])
+    } else {
+        format!("({})", dims.join(", "))
+    };
+
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // Magic string, version and header length precede the header, which ends with a newline.
+    let unpadded = NPY_MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut bytes = NPY_MAGIC.to_vec();
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// Write the local **Leaves** in Morton order to a NumPy `.npz` archive, read by `numpy.load`.
+/// The archive holds `keys`, an N by 4 array of the (x, y, z, level) of each leaf, and `npoints`,
+/// the number of points in each leaf, with the **Tree Header** as the scalars `depth`, `ncrit` and
+/// `r0` and the array `x0` (sequential).
+#[cfg(feature = "zip")]
+pub fn write_npz<P: AsRef<Path>>(tree: &Tree, header: &TreeHeader, path: P) -> io::Result<()> {
+    let mut leaves: Leaves = tree.values().flatten().copied().collect();
+    leaves.sort();
+
+    let keys: Vec<i64> = leaves
+        .iter()
+        .flat_map(|l| vec![l.key.0, l.key.1, l.key.2, l.key.3])
+        .map(|c| c as i64)
+        .collect();
+    let npoints: Vec<i64> = leaves.iter().map(|l| l.npoints as i64).collect();
+
+    let mut writer = ZipWriter::new(File::create(path)?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    writer.start_file("keys.npy", options)?;
+    writer.write_all(&npy_i64(&[leaves.len(), 4], &keys))?;
+    writer.start_file("npoints.npy", options)?;
+    writer.write_all(&npy_i64(&[leaves.len()], &npoints))?;
+
+    writer.start_file("depth.npy", options)?;
+    writer.write_all(&npy_i64(&[], &[header.depth as i64]))?;
+    writer.start_file("ncrit.npy", options)?;
+    writer.write_all(&npy_i64(&[], &[header.ncrit as i64]))?;
+    writer.start_file("x0.npy", options)?;
+    writer.write_all(&npy_f64(&[3], &[header.x0.x, header.x0.y, header.x0.z]))?;
+    writer.start_file("r0.npy", options)?;
+    writer.write_all(&npy_f64(&[], &[header.r0]))?;
+
+    writer.finish()?;
+    Ok(())
+}
+
+mod tests {
+    use super::*;
+
+    use std::fs::read_to_string;
+
+    use crate::morton::find_children;
+
+    #[test]
+    fn test_write_partition_vtk() {
+        let depth = 2;
+        let rank: Rank = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+
+        let mut tree: Tree = HashMap::new();
+        let blocks = find_children(&Key(0, 0, 0, 0), &depth);
+        for &key in find_children(&blocks[0], &depth).iter() {
+            tree.entry(blocks[0]).or_default().push(Leaf {
+                key,
+                block: blocks[0],
+                npoints: 1,
+            });
+        }
+        for &block in blocks[1..].iter() {
+            tree.entry(block).or_default().push(Leaf {
+                key: block,
+                block,
+                npoints: 1,
+            });
+        }
+        let nleaves = tree.values().flatten().count();
+
+        let header = TreeHeader {
+            depth,
+            ncrit: 1,
+            x0,
+            r0: 0.5,
+        };
+
+        let path = std::env::temp_dir().join("distributed_trees_partition.vtk");
+        write_partition_vtk(&tree, rank, &header, &path).unwrap();
+        let contents = read_to_string(&path).unwrap();
+
+        // Test that there is one rank value per leaf
+        let mut lines = contents
+            .lines()
+            .skip_while(|l| !l.starts_with("LOOKUP_TABLE"));
+        lines.next();
+        let ranks: Vec<Rank> = lines.map(|l| l.parse().unwrap()).collect();
+        assert_eq!(ranks.len(), nleaves);
+        assert!(ranks.iter().all(|&r| r == rank));
+
+        assert!(contents.contains("depth 2 ncrit 1 x0 0.5 0.5 0.5 r0 0.5"));
+        assert!(contents.contains(&format!("CELL_DATA {}", nleaves)));
+        assert!(contents.contains(&format!("POINTS {} double", 8 * nleaves)));
+    }
+
+    #[test]
+    fn test_write_points_with_owner_csv() {
+        let leaf = Key(2, 0, 2, 2);
+        let block = Key(0, 0, 0, 1);
+        let rows = vec![
+            (Point::new(0.3, 0.1, 0.3), leaf, block, 1),
+            (Point::new(0.35, 0.15, 0.3), leaf, block, 1),
+        ];
+
+        let header = TreeHeader {
+            depth: 2,
+            ncrit: 1,
+            x0: Point::new(0.5, 0.5, 0.5),
+            r0: 0.5,
+        };
+
+        let path = std::env::temp_dir().join("distributed_trees_points.csv");
+        write_points_with_owner_csv(&rows, &header, &path).unwrap();
+        let contents = read_to_string(&path).unwrap();
+
+        // Test that the tree header comes first, then a header row, and a row of twelve columns
+        // per point
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "# depth 2 ncrit 1 x0 0.5 0.5 0.5 r0 0.5");
+        assert_eq!(lines.len(), rows.len() + 2);
+        assert!(lines[1..].iter().all(|l| l.split(',').count() == 12));
+        assert_eq!(lines[2], "0.3,0.1,0.3,2,0,2,2,0,0,0,1,1");
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn test_write_npz() {
+        let depth = 2;
+        let mut tree: Tree = HashMap::new();
+        for &block in find_children(&Key(0, 0, 0, 0), &depth).iter() {
+            for (i, &key) in find_children(&block, &depth).iter().enumerate() {
+                tree.entry(block).or_default().push(Leaf {
+                    key,
+                    block,
+                    npoints: i,
+                });
+            }
+        }
+        let nleaves = tree.values().flatten().count();
+        let header = TreeHeader {
+            depth,
+            ncrit: 4,
+            x0: Point::new(0.5, 0.5, 0.5),
+            r0: 0.5,
+        };
+
+        let path = std::env::temp_dir().join("distributed_trees_leaves.npz");
+        write_npz(&tree, &header, &path).unwrap();
+
+        // Test that the archive holds the two named arrays, each with one row per leaf, and the
+        // tree header
+        let mut archive = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(archive.len(), 6);
+
+        for (name, shape, ncols, nrows) in [
+            ("keys.npy", format!("({}, 4)", nleaves), 4, nleaves),
+            ("npoints.npy", format!("({},)", nleaves), 1, nleaves),
+            ("depth.npy", "()".to_string(), 1, 1),
+            ("ncrit.npy", "()".to_string(), 1, 1),
+            ("x0.npy", "(3,)".to_string(), 3, 1),
+            ("r0.npy", "()".to_string(), 1, 1),
+        ]
+        .iter()
+        {
+            let mut bytes = Vec::new();
+            let mut file = archive.by_name(name).unwrap();
+            file.read_to_end(&mut bytes).unwrap();
+            assert!(bytes.starts_with(NPY_MAGIC));
+
+            let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+            let header = String::from_utf8(bytes[10..10 + header_len].to_vec()).unwrap();
+            assert!(header.contains(&format!("'shape': {}", shape)));
+            assert_eq!((10 + header_len) % 64, 0);
+            assert_eq!(bytes.len(), 10 + header_len + 8 * ncols * nrows);
+        }
+    }
+
+    #[test]
+    fn test_tree_header() {
+        let header = TreeHeader {
+            depth: 3,
+            ncrit: 150,
+            x0: Point::new(0.5, -0.25, 1e-3),
+            r0: 0.75,
+        };
+
+        // Test that the header round trips exactly
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header).unwrap();
+        let read = read_header(&mut &bytes[..]).unwrap();
+        assert!(read.check(&header).is_ok());
+        assert_eq!(read.x0.y.to_bits(), header.x0.y.to_bits());
+
+        // Test that a file without a header fails
+        let missing = read_header(&mut &[0u8; 56][..]).unwrap_err();
+        assert!(missing.to_string().contains("Missing tree header"));
+        let empty = read_header(&mut &[][..]).unwrap_err();
+        assert!(empty.to_string().contains("Missing tree header"));
+
+        // Test that a read error other than end of file is passed on
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"))
+            }
+        }
+        let failed = read_header(&mut Failing).unwrap_err();
+        assert_eq!(failed.kind(), io::ErrorKind::PermissionDenied);
+
+        // Test that a header at a different depth than expected fails, naming the depth
+        let expected = TreeHeader { depth: 4, ..header };
+        let err = read.check(&expected).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("'depth' is 3 but 4 was expected"));
+    }
+}
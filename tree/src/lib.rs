@@ -14,14 +14,27 @@
 //! [3] Chan, T. "Closest-point problems simplified on the RAM", ACM-SIAM Symposium on Discrete
 //! Algorithms (2002)
 
+extern crate alloc;
+
 /// Perform operations on Morton keys.
 pub mod morton;
 
 /// Create octrees in parallel.
 pub mod tree;
 
+/// Communication utilities for distributed **Morton Keys**.
+pub mod comm;
+
 /// Data manipulation and generation tools.
 pub mod data;
 
+/// Error handling.
+pub mod error;
+
+/// Reading and writing trees.
+pub mod io;
+
 /// Timing tools
 pub mod time;
+
+pub use crate::comm::init;
@@ -5,15 +5,36 @@ use std::hash::{Hash, Hasher};
 use memoffset::offset_of;
 use mpi::{
     datatype::{Equivalence, UncommittedUserDatatype, UserDatatype},
+    traits::Communicator,
     Address,
 };
 use rayon::prelude::*;
 
+use crate::comm::all_reduce_bounding_box;
+use crate::error::TreeError;
+use crate::morton::quad::Key2;
+
+/// Morton key arithmetic, free of MPI and of `std`, needing only `core` and `alloc`.
+pub mod keys;
+
+/// Morton keys and sequential construction for 2D quadtrees. Quadtree keys can be ordered and
+/// communicated, but distributed sorting and tree construction are only implemented for 3D
+/// **Keys**.
+pub mod quad;
+
+pub use self::keys::{
+    anchor_matches_depth, children_array, find_ancestor_at_level, find_ancestors, find_children,
+    find_children_per_axis, find_deepest_first_descendent, find_deepest_last_descendent,
+    find_parent, find_siblings, next, prev, siblings_array, side_shift, z_index, Depths, Key, Keys,
+    LEVEL_BITS,
+};
+
 /// Maximum points per **Leaf**
 pub const MAX_POINTS: usize = 50;
 
-/// Used as an integer sentinel value.
-const SENTINEL: KeyType = 999;
+/// Relative padding applied to automatically computed domains, so that points on the upper faces
+/// of the bounding box are encoded inside the domain.
+const DOMAIN_PADDING: f64 = 1e-6;
 
 type PointType = f64;
 #[derive(Clone, Copy, Debug)]
@@ -29,13 +50,6 @@ pub struct Point {
 /// Vector of **Points**.
 pub type Points = Vec<Point>;
 
-type KeyType = u64;
-#[derive(Clone, Copy, Debug)]
-/// **Morton Key**, anchor and level represented as (x, y, z, level).
-pub struct Key(pub KeyType, pub KeyType, pub KeyType, pub KeyType);
-/// Vector of **Keys**.
-pub type Keys = Vec<Key>;
-
 #[derive(Clone, Copy, Debug)]
 /// **Leaf Key**, bundles **Morton Key**, associated **Block** and particle **Points** it contains.
 pub struct Leaf {
@@ -47,22 +61,36 @@ pub struct Leaf {
 /// Vector of **Leaves**.
 pub type Leaves = Vec<Leaf>;
 
-impl Default for Key {
-    fn default() -> Self {
-        Key(SENTINEL, SENTINEL, SENTINEL, 0)
+impl Point {
+    /// Create a **Point** from its coordinates, with a default key and global index.
+    pub fn new(x: PointType, y: PointType, z: PointType) -> Point {
+        Point::with_index(x, y, z, 0)
     }
-}
 
-impl Default for Point {
-    fn default() -> Self {
+    /// Create a **Point** from its coordinates and global index, with a default key.
+    pub fn with_index(x: PointType, y: PointType, z: PointType, global_idx: usize) -> Point {
         Point {
-            x: PointType::NAN,
-            y: PointType::NAN,
-            z: PointType::NAN,
+            x,
+            y,
+            z,
             key: Key::default(),
-            global_idx: 0,
+            global_idx,
         }
     }
+
+    /// Test whether two **Points** coincide up to a tolerance `eps` in each coordinate, ignoring
+    /// their keys and global indices. Points with NaN coordinates are never approximately equal.
+    pub fn approx_eq(&self, other: &Point, eps: PointType) -> bool {
+        [(self.x, other.x), (self.y, other.y), (self.z, other.z)]
+            .iter()
+            .all(|(a, b)| (a - b).abs() <= eps)
+    }
+}
+
+impl Default for Point {
+    fn default() -> Self {
+        Point::new(PointType::NAN, PointType::NAN, PointType::NAN)
+    }
 }
 
 impl Default for Leaf {
@@ -75,107 +103,6 @@ impl Default for Leaf {
     }
 }
 
-/// Test **Morton Keys** for equality. Keys are considered equal if their anchors and levels match.
-fn equal(a: &Key, b: &Key) -> bool {
-    (a.0 == b.0) & (a.1 == b.1) & (a.2 == b.2) & (a.3 == b.3)
-}
-
-/// Subroutine in less than function, equivalent to comparing floor of log_2(x). Adapted from [3].
-fn most_significant_bit(x: u64, y: u64) -> bool {
-    (x < y) & (x < (x ^ y))
-}
-
-/// Implementation of Algorithm 12 in [1]. to compare the ordering of two **Morton Keys**. If key
-/// `a` is less than key `b`, this function evaluates to true.
-fn less_than(a: &Key, b: &Key) -> Option<bool> {
-    // If anchors match, the one at the coarser level has the lesser Morton id.
-    let same_anchor = (a.0 == b.0) & (a.1 == b.1) & (a.2 == b.2);
-
-    match same_anchor {
-        true => {
-            if a.3 < b.3 {
-                Some(true)
-            } else {
-                Some(false)
-            }
-        }
-        false => {
-            let x = vec![a.0 ^ b.0, a.1 ^ b.1, a.2 ^ b.2];
-
-            let mut argmax = 0;
-
-            for dim in 1..3 {
-                if most_significant_bit(x[argmax as usize], x[dim as usize]) {
-                    argmax = dim
-                }
-            }
-
-            match argmax {
-                0 => {
-                    if a.0 < b.0 {
-                        Some(true)
-                    } else {
-                        Some(false)
-                    }
-                }
-                1 => {
-                    if a.1 < b.1 {
-                        Some(true)
-                    } else {
-                        Some(false)
-                    }
-                }
-                2 => {
-                    if a.2 < b.2 {
-                        Some(true)
-                    } else {
-                        Some(false)
-                    }
-                }
-                _ => None,
-            }
-        }
-    }
-}
-
-impl Ord for Key {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
-    }
-}
-
-impl PartialOrd for Key {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let less = less_than(self, other).unwrap();
-        let eq = self.eq(other);
-
-        match eq {
-            true => Some(Ordering::Equal),
-            false => match less {
-                true => Some(Ordering::Less),
-                false => Some(Ordering::Greater),
-            },
-        }
-    }
-}
-
-impl PartialEq for Key {
-    fn eq(&self, other: &Self) -> bool {
-        equal(self, other)
-    }
-}
-
-impl Eq for Key {}
-
-impl Hash for Key {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.hash(state);
-        self.1.hash(state);
-        self.2.hash(state);
-        self.3.hash(state);
-    }
-}
-
 impl Ord for Leaf {
     fn cmp(&self, other: &Self) -> Ordering {
         self.partial_cmp(other).unwrap()
@@ -184,22 +111,13 @@ impl Ord for Leaf {
 
 impl PartialOrd for Leaf {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let less = less_than(&self.key, &other.key).unwrap();
-        let eq = self.eq(other);
-
-        match eq {
-            true => Some(Ordering::Equal),
-            false => match less {
-                true => Some(Ordering::Less),
-                false => Some(Ordering::Greater),
-            },
-        }
+        self.key.partial_cmp(&other.key)
     }
 }
 
 impl PartialEq for Leaf {
     fn eq(&self, other: &Self) -> bool {
-        equal(&self.key, &other.key)
+        self.key == other.key
     }
 }
 
@@ -214,6 +132,9 @@ impl Hash for Leaf {
     }
 }
 
+/// Exact comparison of coordinates, ignoring keys and global indices. `Eq` assumes finite, exactly
+/// specified coordinates, as NaN coordinates (the default) are unequal to themselves. Points that
+/// may differ by rounding should be compared with `Point::approx_eq`.
 impl PartialEq for Point {
     fn eq(&self, other: &Self) -> bool {
         (self.x == other.x) & (self.y == other.y) & (self.z == other.z)
@@ -222,6 +143,65 @@ impl PartialEq for Point {
 
 impl Eq for Point {}
 
+impl Key {
+    /// Whether a **Morton Key** is a strict ancestor of another, found by masking the other's
+    /// anchor to this key's level rather than allocating its ancestors. A key isn't its own
+    /// ancestor.
+    pub fn is_ancestor_of(&self, other: &Key, depth: &u64) -> bool {
+        (self.3 < other.3) && (find_ancestor_at_level(other, &self.3, depth) == *self)
+    }
+
+    /// Whether a **Morton Key** is a strict descendant of another. A key isn't its own
+    /// descendant.
+    pub fn is_descendant_of(&self, other: &Key, depth: &u64) -> bool {
+        other.is_ancestor_of(self, depth)
+    }
+
+    /// Path from the root to a **Morton Key** as the index of the child octant taken at each
+    /// level, separated by slashes, e.g. `"0/3/5"`. The root has an empty path.
+    pub fn to_path(&self, depth: &u64) -> String {
+        let octants: Vec<String> = (1..=self.3)
+            .map(|level| {
+                let bit = depth - level;
+                let octant = (((self.0 >> bit) & 1) << 2)
+                    | (((self.1 >> bit) & 1) << 1)
+                    | ((self.2 >> bit) & 1);
+                octant.to_string()
+            })
+            .collect();
+
+        octants.join("/")
+    }
+
+    /// Parse a **Morton Key** from its path from the root, the inverse of `to_path`. Fails if a
+    /// segment isn't a child index from 0 to 7, or if the path is deeper than `depth`.
+    pub fn from_path(path: &str, depth: &u64) -> Result<Key, TreeError> {
+        let invalid = || TreeError::InvalidPath {
+            path: path.to_string(),
+        };
+
+        let mut key = Key(0, 0, 0, 0);
+        if path.is_empty() {
+            return Ok(key);
+        }
+
+        for segment in path.split('/') {
+            let octant: u64 = segment.parse().map_err(|_| invalid())?;
+            if (octant > 7) | (key.3 == *depth) {
+                return Err(invalid());
+            }
+
+            key.3 += 1;
+            let bit = depth - key.3;
+            key.0 |= ((octant >> 2) & 1) << bit;
+            key.1 |= ((octant >> 1) & 1) << bit;
+            key.2 |= (octant & 1) << bit;
+        }
+
+        Ok(key)
+    }
+}
+
 unsafe impl Equivalence for Key {
     type Out = UserDatatype;
     fn equivalent_datatype() -> Self::Out {
@@ -243,6 +223,25 @@ unsafe impl Equivalence for Key {
     }
 }
 
+unsafe impl Equivalence for Key2 {
+    type Out = UserDatatype;
+    fn equivalent_datatype() -> Self::Out {
+        UserDatatype::structured(
+            &[1, 1, 1],
+            &[
+                offset_of!(Key2, 0) as Address,
+                offset_of!(Key2, 1) as Address,
+                offset_of!(Key2, 2) as Address,
+            ],
+            &[
+                UncommittedUserDatatype::contiguous(1, &u64::equivalent_datatype()).as_ref(),
+                UncommittedUserDatatype::contiguous(1, &u64::equivalent_datatype()).as_ref(),
+                UncommittedUserDatatype::contiguous(1, &u64::equivalent_datatype()).as_ref(),
+            ],
+        )
+    }
+}
+
 unsafe impl Equivalence for Leaf {
     type Out = UserDatatype;
     fn equivalent_datatype() -> Self::Out {
@@ -342,96 +341,164 @@ unsafe impl Equivalence for Point {
     }
 }
 
-/// Subroutine for finding the parent of a Morton key in its component representation. The trick
-/// is to figure out whether the anchor of a key survives at its parent level, and notice that
-/// anchors at odd indices don't survive. `parent_level_diff' refers to the difference between the
-/// parent's key's level, and the maximum depth of the tree.
-fn odd_index(idx: u64, parent_level_diff: u64) -> bool {
-    let factor = 1 << parent_level_diff;
-    (idx % factor) != 0
+#[cfg(feature = "f32")]
+#[derive(Clone, Copy, Debug, Default)]
+/// **Packed Point**, a **Point** with single precision coordinates, sent in place of **Points** to
+/// reduce communication. Its **Morton Key** is computed from the double precision coordinates
+/// before packing, so is exact, but coordinates are rounded to within a relative error of
+/// `f32::EPSILON / 2`, about 6e-8, on unpacking.
+pub struct PackedPoint {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub key: Key,
+    pub global_idx: usize,
 }
 
-/// Find the parent of a **Morton Key**. Parents contain the key, and are at the previous level of
-/// discretisation.
-pub fn find_parent(key: &Key, depth: &u64) -> Key {
-    // Return root if root fed in
-    if (key.0 == 0) & (key.1 == 0) & (key.2 == 0) {
-        match key.3 {
-            0 => Key(0, 0, 0, 0),
-            _ => Key(0, 0, 0, key.3 - 1),
+#[cfg(feature = "f32")]
+impl From<&Point> for PackedPoint {
+    fn from(point: &Point) -> Self {
+        PackedPoint {
+            x: point.x as f32,
+            y: point.y as f32,
+            z: point.z as f32,
+            key: point.key,
+            global_idx: point.global_idx,
         }
-    } else {
-        let level_diff = depth - key.3;
-        let shift = 1 << level_diff;
-        let parent_level_diff = depth - (key.3 - 1);
-
-        let x_odd = odd_index(key.0, parent_level_diff);
-        let y_odd = odd_index(key.1, parent_level_diff);
-        let z_odd = odd_index(key.2, parent_level_diff);
-
-        let mut parent = Key(key.0, key.1, key.2, key.3 - 1);
+    }
+}
 
-        if x_odd {
-            parent.0 = key.0 - shift;
-        };
-        if y_odd {
-            parent.1 = key.1 - shift;
-        }
-        if z_odd {
-            parent.2 = key.2 - shift;
+#[cfg(feature = "f32")]
+impl From<&PackedPoint> for Point {
+    fn from(packed: &PackedPoint) -> Self {
+        Point {
+            x: packed.x as f64,
+            y: packed.y as f64,
+            z: packed.z as f64,
+            key: packed.key,
+            global_idx: packed.global_idx,
         }
-        parent
     }
 }
 
-/// Find the siblings of a **Morton Key**. Siblings share the same parent.
-pub fn find_siblings(key: &Key, depth: &u64) -> Keys {
-    let parent = find_parent(key, depth);
+#[cfg(feature = "f32")]
+unsafe impl Equivalence for PackedPoint {
+    type Out = UserDatatype;
+    fn equivalent_datatype() -> Self::Out {
+        UserDatatype::structured(
+            &[1, 1, 1, 1, 1],
+            &[
+                offset_of!(PackedPoint, x) as Address,
+                offset_of!(PackedPoint, y) as Address,
+                offset_of!(PackedPoint, z) as Address,
+                offset_of!(PackedPoint, key) as Address,
+                offset_of!(PackedPoint, global_idx) as Address,
+            ],
+            &[
+                UncommittedUserDatatype::contiguous(1, &f32::equivalent_datatype()).as_ref(),
+                UncommittedUserDatatype::contiguous(1, &f32::equivalent_datatype()).as_ref(),
+                UncommittedUserDatatype::contiguous(1, &f32::equivalent_datatype()).as_ref(),
+                UncommittedUserDatatype::contiguous(1, &Key::equivalent_datatype()).as_ref(),
+                UncommittedUserDatatype::contiguous(1, &usize::equivalent_datatype()).as_ref(),
+            ],
+        )
+    }
+}
+
+/// Find the **Morton Key** of a Cartesian coordinate directly, without needing a **Point** to
+/// encode in place. Anchors are always at the resolution of `depth`, so encoding at a coarser
+/// `level` gives the ancestor at that level of the deepest octant containing the coordinate.
+pub fn key_from_coords(
+    x: f64,
+    y: f64,
+    z: f64,
+    &level: &u64,
+    &depth: &u64,
+    &x0: &Point,
+    &r0: &f64,
+) -> Key {
+    let mut key = Key(0, 0, 0, depth);
+    let mut displacement = x0;
+    displacement.x = x0.x - r0;
+    displacement.y = x0.y - r0;
+    displacement.z = x0.z - r0;
 
-    let mut first_child = parent;
-    first_child.3 += 1;
+    let side_length: f64 = (r0 * 2.) / (side_shift(&depth, &0) as f64);
 
-    let mut siblings: Keys = Vec::new();
+    key.0 = ((x - displacement.x) / side_length).floor() as u64;
+    key.1 = ((y - displacement.y) / side_length).floor() as u64;
+    key.2 = ((z - displacement.z) / side_length).floor() as u64;
+    find_ancestor_at_level(&key, &level, &depth)
+}
 
-    let level_diff = depth - key.3;
-    let shift = 1 << level_diff;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// **BoundaryRounding**, the octant to which a coordinate lying exactly on the boundary between two
+/// octants is assigned along each axis.
+pub enum BoundaryRounding {
+    /// Assign boundary coordinates to the lower octant, so octants are closed above and open
+    /// below, except at the lower faces of the domain which remain in the first octant.
+    FloorLower,
+    /// Assign boundary coordinates to the upper octant, so octants are closed below and open
+    /// above, matching `check_domain`. The convention used by `encode_point`.
+    FloorUpper,
+}
 
-    for i in 0..2 {
-        for j in 0..2 {
-            for k in 0..2 {
-                siblings.push(Key(
-                    first_child.0 + shift * i,
-                    first_child.1 + shift * j,
-                    first_child.2 + shift * k,
-                    first_child.3,
-                ));
-            }
-        }
+impl Default for BoundaryRounding {
+    fn default() -> Self {
+        BoundaryRounding::FloorUpper
     }
-    siblings
 }
 
-/// Find the children of a **Morton Key**.
-pub fn find_children(key: &Key, depth: &u64) -> Keys {
-    let mut first_child = *key;
-    first_child.3 += 1;
-    find_siblings(&first_child, depth)
+impl BoundaryRounding {
+    /// Index of the octant containing a coordinate, given in units of the side length of the
+    /// deepest octants from the lower face of the domain.
+    pub fn index(self, t: f64) -> u64 {
+        match self {
+            BoundaryRounding::FloorLower => (t.ceil() as u64).saturating_sub(1),
+            BoundaryRounding::FloorUpper => t.floor() as u64,
+        }
+    }
 }
 
-/// Encode a **Point** in a **Morton Key**.
-pub fn encode_point(mut point: &mut Point, &level: &u64, &depth: &u64, &x0: &Point, &r0: &f64) {
-    let mut key = Key(0, 0, 0, level);
-    let mut displacement = x0;
-    displacement.x = x0.x - r0;
-    displacement.y = x0.y - r0;
-    displacement.z = x0.z - r0;
+/// Encode a **Point** in a **Morton Key**. Coordinates on the boundary between octants are
+/// assigned to the upper octant, see **BoundaryRounding**.
+pub fn encode_point(point: &mut Point, level: &u64, depth: &u64, x0: &Point, r0: &f64) {
+    point.key = key_from_coords(point.x, point.y, point.z, level, depth, x0, r0);
+}
 
-    let side_length: f64 = (r0 * 2.) / ((1 << depth) as f64);
+/// Encode a **Point** in a **Morton Key** with per axis depths, anchored at the deepest of the
+/// depths. Each axis is encoded no finer than its own depth.
+pub fn encode_point_per_axis(
+    point: &mut Point,
+    level: &u64,
+    depths: &Depths,
+    x0: &Point,
+    r0: &f64,
+) {
+    let depth = depths.max();
+    let key = key_from_coords(point.x, point.y, point.z, level, &depth, x0, r0);
+
+    let mask = |axis_depth: u64| !(side_shift(&depth, &axis_depth.min(*level)) - 1);
+    point.key = Key(
+        key.0 & mask(depths.0),
+        key.1 & mask(depths.1),
+        key.2 & mask(depths.2),
+        *level,
+    );
+}
 
-    key.0 = ((point.x - displacement.x) / side_length).floor() as u64;
-    key.1 = ((point.y - displacement.y) / side_length).floor() as u64;
-    key.2 = ((point.z - displacement.z) / side_length).floor() as u64;
-    point.key = key;
+/// Encode a **Point** in a **Morton Key**, assigning coordinates on octant boundaries by a given
+/// convention. `FloorUpper` matches `encode_point`.
+pub fn encode_point_with_rounding(
+    point: &mut Point,
+    level: &u64,
+    depth: &u64,
+    x0: &Point,
+    r0: &f64,
+    rounding: BoundaryRounding,
+) {
+    let encoder = Encoder::new(*x0, *r0, *depth).with_rounding(rounding);
+    point.key = encoder.key(point.x, point.y, point.z, level);
 }
 
 /// Encode a vector of **Points** with their corresponding Morton keys at a given discretisation
@@ -443,75 +510,340 @@ pub fn encode_points(points: &mut [Point], level: &u64, depth: &u64, x0: &Point,
         .collect()
 }
 
-/// Find all ancestors of a **Morton Key**, excludes the key.
-pub fn find_ancestors(key: &Key, depth: &u64) -> Keys {
-    let root = Key(0, 0, 0, 0);
-    let mut parent = find_parent(key, depth);
-    let mut ancestors: Keys = vec![parent];
+/// Check that every **Point** lies inside the domain with centre `x0` and half side length `r0`,
+/// the domain is closed below and open above so that every point has a valid **Morton Key**.
+pub fn check_domain(points: &[Point], x0: &Point, r0: &f64) -> Result<(), TreeError> {
+    let inside = |c: f64, c0: f64| (c0 - r0 <= c) & (c < c0 + r0);
+
+    match points
+        .iter()
+        .find(|p| !(inside(p.x, x0.x) & inside(p.y, x0.y) & inside(p.z, x0.z)))
+    {
+        Some(p) => Err(TreeError::OutOfDomain {
+            x: p.x,
+            y: p.y,
+            z: p.z,
+        }),
+        None => Ok(()),
+    }
+}
 
-    while parent != root {
-        parent = find_parent(&parent, depth);
-        ancestors.push(parent);
+/// Check that a level is no deeper than `depth`, and that `depth` is shallow enough for the side
+/// lengths of octants to fit in a **Morton Key**, so that they may be used with `side_shift`.
+pub fn check_level(depth: &u64, level: &u64) -> Result<(), TreeError> {
+    if (level > depth) | (*depth >= u64::BITS as u64) {
+        Err(TreeError::InvalidLevel {
+            level: *level,
+            depth: *depth,
+        })
+    } else {
+        Ok(())
     }
-    ancestors
 }
 
-/// Find the finest common ancestor of two **Morton Keys**.
-pub fn find_finest_common_ancestor(a: &Key, b: &Key, depth: &u64) -> Key {
-    let ancestors_a: HashSet<Key> = find_ancestors(a, depth).into_iter().collect();
-    let ancestors_b: HashSet<Key> = find_ancestors(b, depth).into_iter().collect();
+/// Encode **Points** arriving in chunks at the deepest level, checking that each chunk lies inside
+/// the domain with centre `x0` and half side length `r0`. Chunks are encoded lazily as they are
+/// read, so that only one chunk need be held at a time (parallel within a chunk).
+pub fn encode_points_chunked<I, E>(
+    chunks: I,
+    depth: &u64,
+    x0: &Point,
+    r0: &f64,
+) -> impl Iterator<Item = Result<Points, TreeError>>
+where
+    I: IntoIterator<Item = Result<Points, E>>,
+    E: Into<TreeError>,
+{
+    let (depth, x0, r0) = (*depth, *x0, *r0);
+
+    chunks.into_iter().map(move |chunk| {
+        let mut points = chunk.map_err(Into::into)?;
+        check_domain(&points, &x0, &r0)?;
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+        Ok(points)
+    })
+}
 
-    let intersection: HashSet<Key> = ancestors_a.intersection(&ancestors_b).copied().collect();
+#[derive(Debug, Clone)]
+/// **Encoder**, encodes **Points** in a fixed domain with centre `x0` and half side length `r0`,
+/// with the lower corner of the domain and the side length of the deepest octants computed once,
+/// so that the same points can be cheaply encoded at several levels.
+pub struct Encoder {
+    x0: Point,
+    r0: f64,
+    depth: u64,
+    rounding: BoundaryRounding,
+    lower: Point,
+    side_length: f64,
+}
 
-    intersection.into_iter().max().unwrap()
+impl Encoder {
+    /// Create an **Encoder** for a domain and maximum depth.
+    pub fn new(x0: Point, r0: f64, depth: u64) -> Encoder {
+        Encoder {
+            x0,
+            r0,
+            depth,
+            rounding: BoundaryRounding::default(),
+            lower: Point::new(x0.x - r0, x0.y - r0, x0.z - r0),
+            side_length: (r0 * 2.) / (side_shift(&depth, &0) as f64),
+        }
+    }
+
+    /// Assign coordinates on octant boundaries by a given convention.
+    pub fn with_rounding(mut self, rounding: BoundaryRounding) -> Encoder {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Centre of the domain.
+    pub fn x0(&self) -> Point {
+        self.x0
+    }
+
+    /// Half side length of the domain.
+    pub fn r0(&self) -> f64 {
+        self.r0
+    }
+
+    /// Maximum depth of the **Morton Keys** found.
+    pub fn depth(&self) -> u64 {
+        self.depth
+    }
+
+    /// Convention by which coordinates on octant boundaries are assigned.
+    pub fn rounding(&self) -> BoundaryRounding {
+        self.rounding
+    }
+
+    /// Find the **Morton Key** of a Cartesian coordinate at a given level.
+    pub fn key(&self, x: f64, y: f64, z: f64, level: &u64) -> Key {
+        let key = Key(
+            self.rounding.index((x - self.lower.x) / self.side_length),
+            self.rounding.index((y - self.lower.y) / self.side_length),
+            self.rounding.index((z - self.lower.z) / self.side_length),
+            self.depth,
+        );
+        find_ancestor_at_level(&key, level, &self.depth)
+    }
+
+    /// Encode a vector of **Points** with their Morton keys at a given level in parallel.
+    pub fn encode(&self, points: &mut [Point], level: &u64) {
+        points
+            .par_iter_mut()
+            .for_each(|p| p.key = self.key(p.x, p.y, p.z, level));
+    }
+}
+
+/// Find the centre of the octant represented by a **Morton Key**, in the physical coordinates of
+/// the domain with centre `x0` and half side length `r0`.
+pub fn key_to_centre(key: &Key, &depth: &u64, x0: &Point, &r0: &f64) -> Point {
+    let side_length: f64 = (r0 * 2.) / (side_shift(&depth, &0) as f64);
+    let half_width = 0.5 * side_length * (side_shift(&depth, &key.3) as f64);
+
+    Point::new(
+        x0.x - r0 + (key.0 as f64) * side_length + half_width,
+        x0.y - r0 + (key.1 as f64) * side_length + half_width,
+        x0.z - r0 + (key.2 as f64) * side_length + half_width,
+    )
 }
 
-/// The deepest first descendent of a **Morton Key**. First descendants always share anchors.
-pub fn find_deepest_first_descendent(key: &Key, depth: &u64) -> Key {
-    if key.3 < *depth {
-        Key(key.0, key.1, key.2, *depth)
+/// Remap a **Morton Key** between two domains, each given as a centre and half side length, by
+/// encoding the physical centre of its octant in the target domain at the same level. Fails if
+/// the centre lies outside of the target domain.
+pub fn remap_key(
+    key: &Key,
+    from: (Point, f64),
+    to: (Point, f64),
+    depth: &u64,
+) -> Result<Key, TreeError> {
+    let centre = key_to_centre(key, depth, &from.0, &from.1);
+    check_domain(&[centre], &to.0, &to.1)?;
+
+    let finest = key_from_coords(centre.x, centre.y, centre.z, depth, depth, &to.0, &to.1);
+    Ok(find_ancestor_at_level(&finest, &key.3, depth))
+}
+
+/// Encode a distributed vector of **Points** over a domain computed from their global bounding
+/// box. The domain is the smallest cube containing every point, returned as its centre and half
+/// side length (parallel).
+pub fn encode_points_auto<C: Communicator>(
+    points: &mut [Point],
+    depth: &u64,
+    world: &C,
+) -> (Point, f64) {
+    let (lower, upper) = all_reduce_bounding_box(points, world);
+
+    let x0 = Point::new(
+        0.5 * (lower.x + upper.x),
+        0.5 * (lower.y + upper.y),
+        0.5 * (lower.z + upper.z),
+    );
+
+    let width = (upper.x - lower.x)
+        .max(upper.y - lower.y)
+        .max(upper.z - lower.z);
+    let half = 0.5 * width;
+    let r0 = if half > 0. {
+        half * (1. + DOMAIN_PADDING)
     } else {
-        *key
-    }
+        0.5
+    };
+
+    encode_points(points, depth, depth, &x0, &r0);
+
+    (x0, r0)
 }
 
-/// The deepest last descendent of a **Morton Key**. At the deepest level nodes are considered to
-/// have side lengths of 1.
-pub fn find_deepest_last_descendent(key: &Key, depth: &u64) -> Key {
-    if key.3 < *depth {
-        let mut level_diff = depth - key.3;
-        let mut dld = *find_children(key, depth).iter().max().unwrap();
+/// Find the neighbors of a **Morton Key**. Neighbors are the octants at the same level that share
+/// a face, edge or vertex with the key, and which lie inside the domain.
+pub fn find_neighbors(key: &Key, depth: &u64) -> Keys {
+    let shift = side_shift(depth, &key.3) as i64;
+    let max = side_shift(depth, &0) as i64;
+
+    let mut neighbors: Keys = Vec::new();
+
+    for i in -1..2 {
+        for j in -1..2 {
+            for k in -1..2 {
+                if (i == 0) & (j == 0) & (k == 0) {
+                    continue;
+                }
+
+                let x = key.0 as i64 + shift * i;
+                let y = key.1 as i64 + shift * j;
+                let z = key.2 as i64 + shift * k;
 
-        while level_diff > 1 {
-            let tmp = dld;
-            dld = *find_children(&tmp, depth).iter().max().unwrap();
-            level_diff -= 1;
+                if (0..max).contains(&x) & (0..max).contains(&y) & (0..max).contains(&z) {
+                    neighbors.push(Key(x as u64, y as u64, z as u64, key.3));
+                }
+            }
         }
+    }
+    neighbors
+}
 
-        dld
+/// Find the neighbors of a **Morton Key** expressed at another level. At a coarser level these are
+/// the octants containing each neighbor, excluding the one containing the key. At a finer level
+/// these are the descendants of each neighbor which abut the key.
+pub fn find_neighbors_at_level(key: &Key, level: &u64, depth: &u64) -> Keys {
+    let neighbors = find_neighbors(key, depth);
+
+    let mut result: Keys = if *level <= key.3 {
+        let ancestor = find_ancestor_at_level(key, level, depth);
+        neighbors
+            .iter()
+            .map(|n| find_ancestor_at_level(n, level, depth))
+            .filter(|a| *a != ancestor)
+            .collect()
     } else {
-        *key
-    }
+        let width = side_shift(depth, &key.3);
+        let fine_width = side_shift(depth, level);
+
+        // Along each axis only the slab of descendants facing the key abuts it.
+        let slabs = |n: u64, k: u64| -> Vec<u64> {
+            if n < k {
+                vec![n + width - fine_width]
+            } else if n > k {
+                vec![n]
+            } else {
+                (0..width / fine_width)
+                    .map(|i| n + i * fine_width)
+                    .collect()
+            }
+        };
+
+        let mut descendants: Keys = Vec::new();
+        for n in neighbors.iter() {
+            for &x in slabs(n.0, key.0).iter() {
+                for &y in slabs(n.1, key.1).iter() {
+                    for &z in slabs(n.2, key.2).iter() {
+                        descendants.push(Key(x, y, z, *level));
+                    }
+                }
+            }
+        }
+        descendants
+    };
+
+    result.sort();
+    result.dedup();
+    result
+}
+
+/// Find the parents of a slice of **Morton Keys**, in the same order, in parallel.
+pub fn find_parents(keys: &[Key], depth: &u64) -> Keys {
+    keys.par_iter().map(|key| find_parent(key, depth)).collect()
+}
+
+/// Find the interaction list of a **Morton Key**, as used by the far-field operators of the FMM
+/// [2]. These are the children of the parent's neighbors which are not themselves neighbors of
+/// the key.
+pub fn interaction_list(key: &Key, depth: &u64) -> Keys {
+    let parent = find_parent(key, depth);
+    let neighbors: HashSet<Key> = find_neighbors(key, depth).into_iter().collect();
+
+    let mut interactions: Keys = find_neighbors(&parent, depth)
+        .iter()
+        .flat_map(|n| find_children(n, depth))
+        .filter(|c| !neighbors.contains(c))
+        .collect();
+
+    interactions.sort();
+    interactions
+}
+
+/// Find the finest common ancestor of two **Morton Keys**.
+pub fn find_finest_common_ancestor(a: &Key, b: &Key, depth: &u64) -> Key {
+    let ancestors_a: HashSet<Key> = find_ancestors(a, depth).into_iter().collect();
+    let ancestors_b: HashSet<Key> = find_ancestors(b, depth).into_iter().collect();
+
+    let intersection: HashSet<Key> = ancestors_a.intersection(&ancestors_b).copied().collect();
+
+    intersection.into_iter().max().unwrap()
+}
+
+/// Find the finest octant containing every one of a set of **Morton Keys**, folding the pairwise
+/// finest common ancestor. Unlike `find_finest_common_ancestor` a key may be its own ancestor, so
+/// a single key, or a key with its descendants, gives the key itself. An empty set gives the root.
+pub fn common_ancestor_many(keys: &[Key], depth: &u64) -> Key {
+    let contains = |a: &Key, b: &Key| (a == b) || a.is_ancestor_of(b, depth);
+
+    let mut iter = keys.iter();
+    let first = match iter.next() {
+        Some(first) => *first,
+        None => return Key(0, 0, 0, 0),
+    };
+
+    iter.fold(first, |ancestor, key| {
+        if contains(&ancestor, key) {
+            ancestor
+        } else if contains(key, &ancestor) {
+            *key
+        } else {
+            find_finest_common_ancestor(&ancestor, key, depth)
+        }
+    })
 }
 
-/// Convert a vector of **Points**, to a Vector of **Leaves**.
+/// Convert a vector of **Points**, to a Vector of **Leaves**. The **Points** are sorted by key in
+/// place, stably and in parallel, so callers may pass them in any order.
 pub fn keys_to_leaves(points: &mut [Point]) -> Leaves {
-    // Sort points by Leaf key
-    points.sort_by(|a, b| a.key.cmp(&b.key));
+    // Sort points by Leaf key, counts below rely on points with equal keys being contiguous
+    points.par_sort_by(|a, b| a.key.cmp(&b.key));
+    debug_assert!(points.windows(2).all(|w| w[0].key <= w[1].key));
 
-    // Find unique Leaf keys
-    let mut key_indices: Vec<usize> = Vec::new();
-    let mut curr = Key::default();
+    // Find unique Leaf keys, and the index of the first point in each
     let mut unique_keys: Keys = Vec::new();
     let mut key_indices: Vec<usize> = Vec::new();
 
     for (i, &p) in points.iter().enumerate() {
-        if curr != p.key {
-            curr = p.key;
-            unique_keys.push(curr);
+        if unique_keys.last() != Some(&p.key) {
+            unique_keys.push(p.key);
             key_indices.push(i)
         }
     }
+    // Bound the final leaf's range by the number of points
     key_indices.push(points.len());
 
     let mut leaves: Leaves = Vec::new();
@@ -535,6 +867,7 @@ mod tests {
     use super::*;
     use crate::data::random;
     use itertools::Itertools;
+    use rand::Rng;
 
     #[test]
     fn test_find_parent() {
@@ -563,6 +896,95 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_is_ancestor_of() {
+        let depth = 3;
+        let grandparent = Key(0, 0, 0, 1);
+        let parent = Key(2, 2, 2, 2);
+        let child = Key(3, 3, 3, 3);
+
+        // Test parent and child
+        assert!(parent.is_ancestor_of(&child, &depth));
+        assert!(child.is_descendant_of(&parent, &depth));
+        assert!(!child.is_ancestor_of(&parent, &depth));
+        assert!(!parent.is_descendant_of(&child, &depth));
+
+        // Test grandparent and grandchild, and that the root is an ancestor of both
+        assert!(grandparent.is_ancestor_of(&child, &depth));
+        assert!(child.is_descendant_of(&grandparent, &depth));
+        assert!(Key(0, 0, 0, 0).is_ancestor_of(&child, &depth));
+
+        // Test that a key is neither its own ancestor nor its own descendant
+        assert!(!parent.is_ancestor_of(&parent, &depth));
+        assert!(!parent.is_descendant_of(&parent, &depth));
+
+        // Test unrelated keys, at the same and at different levels
+        let unrelated = Key(4, 0, 0, 1);
+        assert!(!unrelated.is_ancestor_of(&child, &depth));
+        assert!(!child.is_descendant_of(&unrelated, &depth));
+        assert!(!Key(0, 0, 2, 2).is_ancestor_of(&child, &depth));
+        assert!(!Key(2, 2, 0, 3).is_descendant_of(&parent, &depth));
+
+        // Test agreement with the ancestors found by allocating
+        for ancestor in find_ancestors(&child, &depth).iter() {
+            assert!(ancestor.is_ancestor_of(&child, &depth));
+        }
+    }
+
+    #[test]
+    fn test_key_paths() {
+        let depth = 4;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // Test that random keys at every level round trip through their paths
+        for (i, p) in random(1000).iter_mut().enumerate() {
+            let level = (i as u64) % (depth + 1);
+            encode_point(p, &level, &depth, &x0, &r0);
+
+            let path = p.key.to_path(&depth);
+            let nsegments = path.split('/').filter(|s| !s.is_empty()).count();
+            assert_eq!(nsegments as u64, level);
+            assert_eq!(Key::from_path(&path, &depth).unwrap(), p.key);
+        }
+
+        // Test that each segment is the child index, with x as the most significant bit
+        assert_eq!(Key(0, 0, 0, 0).to_path(&depth), "");
+        assert_eq!(Key(0, 8, 8, 3).to_path(&depth), "3/0/0");
+        assert_eq!(Key::from_path("0/3/5", &depth).unwrap(), Key(2, 4, 6, 3));
+
+        // Test that invalid octants, and paths deeper than the tree, are rejected
+        for path in ["0/8", "0/x", "0//1", "1/2/3/4/5"].iter() {
+            assert_eq!(
+                Key::from_path(path, &depth),
+                Err(TreeError::InvalidPath {
+                    path: path.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_parents() {
+        let depth = 4;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let mut keys: Keys = random(1000)
+            .iter_mut()
+            .enumerate()
+            .map(|(i, p)| {
+                let level = (i as u64) % (depth + 1);
+                encode_point(p, &level, &depth, &x0, &r0);
+                p.key
+            })
+            .collect();
+        keys.push(Key(0, 0, 0, 0));
+
+        let expected: Keys = keys.iter().map(|k| find_parent(k, &depth)).collect();
+        assert_eq!(find_parents(&keys, &depth), expected);
+    }
+
     #[test]
     fn test_find_siblings() {
         let depth = 3;
@@ -655,26 +1077,297 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_z_index() {
+        let mut rng = rand::thread_rng();
+        let depth: u64 = 5;
+
+        let mut random_key = || {
+            let level: u64 = rng.gen_range(0..(depth + 1));
+            let shift: u64 = 1 << (depth - level);
+            let n: u64 = 1 << level;
+            Key(
+                rng.gen_range(0..n) * shift,
+                rng.gen_range(0..n) * shift,
+                rng.gen_range(0..n) * shift,
+                level,
+            )
+        };
+
+        // Test that the ordering of keys agrees with the ordering of their z indices
+        for _ in 0..10000 {
+            let a = random_key();
+            let b = random_key();
+            assert_eq!(a.cmp(&b), z_index(&a, &depth).cmp(&z_index(&b, &depth)));
+        }
+    }
+
+    #[test]
+    fn test_point_new() {
+        let point = Point::new(0.1, 0.2, 0.3);
+        assert_eq!((point.x, point.y, point.z), (0.1, 0.2, 0.3));
+        assert_eq!(point.key, Key::default());
+        assert_eq!(point.global_idx, 0);
+
+        let point = Point::with_index(0.1, 0.2, 0.3, 7);
+        assert_eq!(point.global_idx, 7);
+        assert_eq!(point, Point::new(0.1, 0.2, 0.3));
+    }
+
+    #[test]
+    fn test_point_approx_eq() {
+        let a = Point::new(0.1, 0.2, 0.3);
+        let b = Point::new(0.1 + 1e-15, 0.2, 0.3);
+
+        // Test that rounding differences are tolerated, unlike exact equality
+        assert!(a.approx_eq(&b, 1e-12));
+        assert_ne!(a, b);
+
+        // Test that points further apart than the tolerance differ
+        assert!(!a.approx_eq(&Point::new(0.1, 0.2, 0.3 + 1e-9), 1e-12));
+
+        // Test that default points with NaN coordinates never coincide
+        assert!(!Point::default().approx_eq(&Point::default(), 1e-12));
+    }
+
     #[test]
     fn test_encode_point() {
         let depth = 2;
-        let x0 = Point {
-            x: 0.5,
-            y: 0.5,
-            z: 0.5,
-            global_idx: 0,
-            key: Key::default(),
-        };
+        let x0 = Point::new(0.5, 0.5, 0.5);
         let r0 = 0.5;
-        let mut point = Point::default();
-        point.x = 0.0;
-        point.y = 0.0;
-        point.z = 0.0;
+        let mut point = Point::new(0.0, 0.0, 0.0);
         encode_point(&mut point, &depth, &depth, &x0, &r0);
         let expected = Key(0, 0, 0, 2);
         assert_eq!(point.key, expected);
     }
 
+    #[test]
+    fn test_encode_point_per_axis() {
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // Test that uniform depths match the isotropic encoding
+        let depth = 3;
+        for mut point in random(100) {
+            let mut expected = point;
+            encode_point(&mut expected, &2, &depth, &x0, &r0);
+            encode_point_per_axis(&mut point, &2, &Depths::uniform(depth), &x0, &r0);
+            assert_eq!(point.key, expected.key);
+        }
+
+        // Test that a shallow z axis never exceeds its resolution, and that keys at each level
+        // are the parents of those at the next
+        let depths = Depths(3, 3, 1);
+        for point in random(100) {
+            let mut coarse = point;
+            let mut fine = point;
+            for level in 0..depths.max() {
+                encode_point_per_axis(&mut coarse, &level, &depths, &x0, &r0);
+                encode_point_per_axis(&mut fine, &(level + 1), &depths, &x0, &r0);
+                assert!(depths.anchor(&fine.key)[2] < (1 << depths.2));
+                assert_eq!(find_parent(&fine.key, &depths.max()), coarse.key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_point_coarse_level() {
+        let level = 1;
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let mut point = Point::new(0.9, 0.3, 0.6);
+        encode_point(&mut point, &level, &depth, &x0, &r0);
+
+        // Test that anchors are aligned to the grid of the coarser level
+        let key = point.key;
+        assert_eq!(key.3, level);
+        assert_eq!(key, Key(4, 0, 4, level));
+        for &anchor in [key.0, key.1, key.2].iter() {
+            assert_eq!(anchor % 4, 0);
+        }
+    }
+
+    #[test]
+    fn test_encoder() {
+        let depth = 4;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+        let encoder = Encoder::new(x0, r0, depth);
+        assert_eq!(encoder.x0(), x0);
+        assert_eq!(encoder.r0(), r0);
+        assert_eq!(encoder.depth(), depth);
+        assert_eq!(encoder.rounding(), BoundaryRounding::default());
+
+        let points = random(1000);
+
+        // Test that the encoder matches encode_points at every level
+        for level in 0..(depth + 1) {
+            let mut expected = points.clone();
+            encode_points(&mut expected, &level, &depth, &x0, &r0);
+
+            let mut result = points.clone();
+            encoder.encode(&mut result, &level);
+
+            for (e, r) in expected.iter().zip(result.iter()) {
+                assert_eq!(e.key, r.key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_domain() {
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let mut points = random(100);
+        assert_eq!(check_domain(&points, &x0, &r0), Ok(()));
+
+        // Test that points on the upper faces of the domain are rejected
+        points.push(Point::new(0.5, 1.0, 0.5));
+        assert_eq!(
+            check_domain(&points, &x0, &r0),
+            Err(TreeError::OutOfDomain {
+                x: 0.5,
+                y: 1.0,
+                z: 0.5
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_level() {
+        assert_eq!(check_level(&4, &4), Ok(()));
+        assert_eq!(check_level(&63, &0), Ok(()));
+
+        // Test that keys deeper than the tree, and trees too deep for a key, give a clean error
+        assert_eq!(
+            check_level(&2, &3),
+            Err(TreeError::InvalidLevel { level: 3, depth: 2 })
+        );
+        assert_eq!(
+            check_level(&64, &0),
+            Err(TreeError::InvalidLevel {
+                level: 0,
+                depth: 64
+            })
+        );
+    }
+
+    #[test]
+    fn test_boundary_rounding() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // A point on the boundary between level 1 octants along x, and inside octants along y, z
+        let point = Point::new(0.5, 0.3, 0.3);
+
+        let mut upper = point;
+        encode_point_with_rounding(
+            &mut upper,
+            &1,
+            &depth,
+            &x0,
+            &r0,
+            BoundaryRounding::FloorUpper,
+        );
+        assert_eq!(upper.key, Key(4, 0, 0, 1));
+
+        let mut lower = point;
+        encode_point_with_rounding(
+            &mut lower,
+            &1,
+            &depth,
+            &x0,
+            &r0,
+            BoundaryRounding::FloorLower,
+        );
+        assert_eq!(lower.key, Key(0, 0, 0, 1));
+
+        // Test that the default convention matches encode_point
+        let mut expected = point;
+        encode_point(&mut expected, &1, &depth, &x0, &r0);
+        assert_eq!(upper.key, expected.key);
+
+        // Test that the modes agree inside octants, and on the lower faces of the domain
+        let floor_lower = BoundaryRounding::FloorLower;
+        let floor_upper = BoundaryRounding::FloorUpper;
+        for &t in [0., 0.5, 2.3, 7.9].iter() {
+            assert_eq!(floor_lower.index(t), floor_upper.index(t));
+        }
+        assert_eq!(floor_lower.index(4.), 3);
+        assert_eq!(floor_upper.index(4.), 4);
+    }
+
+    #[test]
+    fn test_encode_points_chunked() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let mut points = random(100);
+        let chunks: Vec<Result<Points, TreeError>> =
+            points.chunks(7).map(|c| Ok(c.to_vec())).collect();
+
+        // Test that encoding in chunks matches encoding all points at once
+        let encoded: Points = encode_points_chunked(chunks, &depth, &x0, &r0)
+            .flat_map(|chunk| chunk.unwrap())
+            .collect();
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+        let keys = |points: &Points| points.iter().map(|p| p.key).collect::<Keys>();
+        assert_eq!(keys(&encoded), keys(&points));
+
+        // Test that a chunk outside of the domain is reported when it is reached
+        let chunks: Vec<Result<Points, TreeError>> = vec![
+            Ok(vec![Point::new(0.5, 0.5, 0.5)]),
+            Ok(vec![Point::new(2., 0.5, 0.5)]),
+        ];
+        let results: Vec<Result<Points, TreeError>> =
+            encode_points_chunked(chunks, &depth, &x0, &r0).collect();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(TreeError::OutOfDomain { .. })));
+    }
+
+    #[test]
+    fn test_key_from_coords() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+        let mut point = Point::new(0.3, 0.6, 0.9);
+        encode_point(&mut point, &depth, &depth, &x0, &r0);
+        let result = key_from_coords(0.3, 0.6, 0.9, &depth, &depth, &x0, &r0);
+        assert_eq!(point.key, result);
+    }
+
+    #[test]
+    fn test_remap_key() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // Test that remapping within identical domains is the identity
+        let key = Key(4, 1, 6, 3);
+        assert_eq!(remap_key(&key, (x0, r0), (x0, r0), &depth), Ok(key));
+
+        // Test that translating the domain by two finest octants shifts anchors by two
+        let mut translated = x0;
+        translated.x += 0.25;
+        let result = remap_key(&key, (x0, r0), (translated, r0), &depth);
+        assert_eq!(result, Ok(Key(2, 1, 6, 3)));
+
+        let key = Key(4, 2, 2, 2);
+        let result = remap_key(&key, (x0, r0), (translated, r0), &depth);
+        assert_eq!(result, Ok(Key(2, 2, 2, 2)));
+
+        // Test that a key whose centre lies outside of the target domain fails
+        let key = Key(0, 1, 6, 3);
+        let result = remap_key(&key, (x0, r0), (translated, r0), &depth);
+        assert!(matches!(result, Err(TreeError::OutOfDomain { .. })));
+    }
+
     #[test]
     fn test_find_ancestors() {
         let key = Key(0, 0, 0, 2);
@@ -698,6 +1391,78 @@ mod tests {
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn test_interaction_list() {
+        // Interior octant, children of the parent's 26 neighbors, less the key's 19 neighbors
+        // which aren't siblings.
+        let depth = 3;
+        let key = Key(2, 2, 2, 3);
+        let result = interaction_list(&key, &depth);
+        assert_eq!(result.len(), 27 * 8 - 27);
+
+        let neighbors = find_neighbors(&key, &depth);
+        for k in result.iter() {
+            assert_eq!(k.3, key.3);
+            assert!(!neighbors.contains(k));
+            assert!(*k != key);
+        }
+
+        // Boundary octant, the parent only has 7 neighbors and all of the key's neighbors are its
+        // siblings.
+        let key = Key(0, 0, 0, 3);
+        let result = interaction_list(&key, &depth);
+        assert_eq!(result.len(), 7 * 8);
+
+        // Octants at the coarsest levels have no interaction list.
+        let key = Key(0, 0, 0, 1);
+        assert!(interaction_list(&key, &depth).is_empty());
+    }
+
+    #[test]
+    fn test_find_neighbors_at_level() {
+        let depth = 4;
+        let key = Key(4, 4, 4, 2);
+
+        // Test that the same level gives the ordinary neighbors
+        let mut expected = find_neighbors(&key, &depth);
+        expected.sort();
+        assert_eq!(find_neighbors_at_level(&key, &key.3, &depth), expected);
+
+        // Test that one level coarser gives the containing octants of the neighbors, excluding
+        // the parent of the key
+        let coarse = find_neighbors_at_level(&key, &1, &depth);
+        let parent = find_parent(&key, &depth);
+        assert_eq!(coarse.len(), 7);
+        assert!(!coarse.contains(&parent));
+        for n in find_neighbors(&key, &depth).iter() {
+            let ancestor = find_parent(n, &depth);
+            assert!((ancestor == parent) | coarse.contains(&ancestor));
+        }
+
+        // Test that one level finer gives the children of the neighbors which abut the key
+        let fine = find_neighbors_at_level(&key, &3, &depth);
+        assert_eq!(fine.len(), 4 * 4 * 4 - 8);
+        for f in fine.iter() {
+            let anchor = [f.0, f.1, f.2];
+            assert_eq!(f.3, 3);
+            assert!(anchor.iter().all(|a| [2, 4, 6, 8].contains(a)));
+            assert!(!anchor.iter().all(|a| [4, 6].contains(a)));
+        }
+    }
+
+    #[test]
+    fn test_find_ancestor_at_level() {
+        let key = Key(3, 2, 5, 3);
+        let depth = 3;
+        let ancestors = find_ancestors(&key, &depth);
+
+        for level in 0..key.3 {
+            let result = find_ancestor_at_level(&key, &level, &depth);
+            assert_eq!(result.3, level);
+            assert!(ancestors.contains(&result));
+        }
+    }
+
     #[test]
     fn test_find_dld() {
         let key = Key(0, 0, 0, 0);
@@ -716,6 +1481,54 @@ mod tests {
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn test_dfd_dld_bound_descendants() {
+        let mut rng = rand::thread_rng();
+        let depth: u64 = 5;
+
+        for _ in 0..1000 {
+            let level: u64 = rng.gen_range(0..(depth + 1));
+            let shift: u64 = 1 << (depth - level);
+            let n: u64 = 1 << level;
+            let key = Key(
+                rng.gen_range(0..n) * shift,
+                rng.gen_range(0..n) * shift,
+                rng.gen_range(0..n) * shift,
+                level,
+            );
+
+            let dfd = find_deepest_first_descendent(&key, &depth);
+            let dld = find_deepest_last_descendent(&key, &depth);
+
+            // Test that the first descendent precedes the last, and both are deepest
+            assert!(dfd <= dld);
+            assert_eq!((dfd.3, dld.3), (depth, depth));
+            if level == depth {
+                assert_eq!((dfd, dld), (key, key));
+            }
+
+            // Test that sampled descendants at the deepest level lie between the first and last
+            // descendants, and that descendants at any level have deepest descendants within them
+            for _ in 0..10 {
+                let descendant_level: u64 = rng.gen_range(level..(depth + 1));
+                let descendant_shift: u64 = 1 << (depth - descendant_level);
+                let m = shift / descendant_shift;
+                let descendant = Key(
+                    key.0 + rng.gen_range(0..m) * descendant_shift,
+                    key.1 + rng.gen_range(0..m) * descendant_shift,
+                    key.2 + rng.gen_range(0..m) * descendant_shift,
+                    descendant_level,
+                );
+                assert_eq!(find_ancestor_at_level(&descendant, &level, &depth), key);
+                assert!(key <= descendant);
+
+                let first = find_deepest_first_descendent(&descendant, &depth);
+                let last = find_deepest_last_descendent(&descendant, &depth);
+                assert!((dfd <= first) & (first <= last) & (last <= dld));
+            }
+        }
+    }
+
     #[test]
     fn test_find_finest_common_ancestor() {
         let a = Key(3, 3, 3, 2);
@@ -726,6 +1539,27 @@ mod tests {
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn test_common_ancestor_many() {
+        let depth = 3;
+
+        // Test that the common ancestor of a block's children is the block
+        let block = Key(4, 0, 4, 1);
+        let children = find_children(&block, &depth);
+        assert_eq!(common_ancestor_many(&children, &depth), block);
+
+        // Test that including the block, or only a single key, doesn't coarsen the result
+        let mut with_block = children.clone();
+        with_block.push(block);
+        assert_eq!(common_ancestor_many(&with_block, &depth), block);
+        assert_eq!(common_ancestor_many(&children[..1], &depth), children[0]);
+
+        // Test that keys spanning the whole domain have the root as their common ancestor
+        let spanning = vec![Key(0, 0, 0, 3), Key(2, 2, 0, 2), Key(7, 7, 7, 3)];
+        assert_eq!(common_ancestor_many(&spanning, &depth), Key(0, 0, 0, 0));
+        assert_eq!(common_ancestor_many(&[], &depth), Key(0, 0, 0, 0));
+    }
+
     #[test]
     fn test_keys_to_leaves() {
         let npoints = 342;
@@ -733,13 +1567,7 @@ mod tests {
         let mut points = random(npoints);
         let level = 1;
         let depth = 1;
-        let x0 = Point {
-            x: 0.5,
-            y: 0.5,
-            z: 0.5,
-            global_idx: 0,
-            key: Key::default(),
-        };
+        let x0 = Point::new(0.5, 0.5, 0.5);
         let r0 = 0.5;
         encode_points(&mut points, &level, &depth, &x0, &r0);
         let unique_keys: Keys = points.iter().map(|p| p.key).unique().clone().collect();
@@ -755,4 +1583,51 @@ mod tests {
         }
         assert_eq!(npoints as usize, nleaf_points);
     }
+
+    #[test]
+    fn test_keys_to_leaves_unsorted() {
+        let depth = 2;
+        let a = Key(0, 0, 0, depth);
+        let b = Key(1, 2, 3, depth);
+        let c = Key(3, 3, 3, depth);
+
+        // Interleave keys so that equal keys are never adjacent
+        let mut points: Points = Vec::new();
+        for (i, &key) in [c, a, b, a, c, a, b, c, a].iter().enumerate() {
+            let mut point = Point::with_index(0., 0., 0., i);
+            point.key = key;
+            points.push(point);
+        }
+
+        let leaves = keys_to_leaves(&mut points);
+
+        // Test that each leaf counts every point with its key
+        let counts: Vec<(Key, usize)> = leaves.iter().map(|l| (l.key, l.npoints)).collect();
+        assert_eq!(counts, vec![(a, 4), (b, 2), (c, 3)]);
+
+        // Test that points are left sorted, stably
+        assert!(points.windows(2).all(|w| w[0].key <= w[1].key));
+        let idxs: Vec<usize> = points.iter().map(|p| p.global_idx).collect();
+        assert_eq!(idxs, vec![1, 3, 5, 8, 2, 6, 0, 4, 7]);
+    }
+
+    #[test]
+    fn test_keys_to_leaves_matches_sequential_sort() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // Many points share each key, so the order of equal keys is tested too
+        let mut points = random(100000);
+        encode_points(&mut points, &2, &depth, &x0, &r0);
+
+        let mut expected = points.clone();
+        expected.sort_by(|a, b| a.key.cmp(&b.key));
+
+        keys_to_leaves(&mut points);
+
+        let idxs: Vec<usize> = points.iter().map(|p| p.global_idx).collect();
+        let expected: Vec<usize> = expected.iter().map(|p| p.global_idx).collect();
+        assert_eq!(idxs, expected);
+    }
 }
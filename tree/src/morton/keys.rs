@@ -0,0 +1,644 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::hash::{Hash, Hasher};
+
+/// Used as an integer sentinel value.
+const SENTINEL: KeyType = 999;
+
+type KeyType = u64;
+#[derive(Clone, Copy, Debug)]
+/// **Morton Key**, anchor and level represented as (x, y, z, level).
+pub struct Key(pub KeyType, pub KeyType, pub KeyType, pub KeyType);
+/// Vector of **Keys**.
+pub type Keys = Vec<Key>;
+
+impl Default for Key {
+    fn default() -> Self {
+        Key(SENTINEL, SENTINEL, SENTINEL, 0)
+    }
+}
+
+/// Test **Morton Keys** for equality. Keys are considered equal if their anchors and levels match.
+fn equal(a: &Key, b: &Key) -> bool {
+    (a.0 == b.0) & (a.1 == b.1) & (a.2 == b.2) & (a.3 == b.3)
+}
+
+/// Subroutine in less than function, equivalent to comparing floor of log_2(x). Adapted from [3].
+pub(crate) fn most_significant_bit(x: u64, y: u64) -> bool {
+    (x < y) & (x < (x ^ y))
+}
+
+/// Implementation of Algorithm 12 in [1]. to compare the ordering of two **Morton Keys**. If key
+/// `a` is less than key `b`, this function evaluates to true.
+fn less_than(a: &Key, b: &Key) -> Option<bool> {
+    // If anchors match, the one at the coarser level has the lesser Morton id.
+    let same_anchor = (a.0 == b.0) & (a.1 == b.1) & (a.2 == b.2);
+
+    match same_anchor {
+        true => {
+            if a.3 < b.3 {
+                Some(true)
+            } else {
+                Some(false)
+            }
+        }
+        false => {
+            let x = [a.0 ^ b.0, a.1 ^ b.1, a.2 ^ b.2];
+
+            let mut argmax = 0;
+
+            for dim in 1..3 {
+                if most_significant_bit(x[argmax as usize], x[dim as usize]) {
+                    argmax = dim
+                }
+            }
+
+            match argmax {
+                0 => {
+                    if a.0 < b.0 {
+                        Some(true)
+                    } else {
+                        Some(false)
+                    }
+                }
+                1 => {
+                    if a.1 < b.1 {
+                        Some(true)
+                    } else {
+                        Some(false)
+                    }
+                }
+                2 => {
+                    if a.2 < b.2 {
+                        Some(true)
+                    } else {
+                        Some(false)
+                    }
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Number of bits reserved for the level in a **Z Index**.
+pub const LEVEL_BITS: u64 = 8;
+
+/// Compute the canonical **Z Index** of a **Morton Key**, found by interleaving the bits of its
+/// anchor, with x the most significant axis, followed by its level to break ties between octants
+/// that share an anchor. Comparing **Z Indices** gives the Morton ordering, the depth must be at
+/// most 40 so that the index fits in 128 bits.
+pub fn z_index(key: &Key, depth: &u64) -> u128 {
+    debug_assert!(3 * depth + LEVEL_BITS <= 128);
+
+    let mut index: u128 = 0;
+
+    for bit in (0..*depth).rev() {
+        index = (index << 1) | ((key.0 >> bit) & 1) as u128;
+        index = (index << 1) | ((key.1 >> bit) & 1) as u128;
+        index = (index << 1) | ((key.2 >> bit) & 1) as u128;
+    }
+
+    (index << LEVEL_BITS) | key.3 as u128
+}
+
+/// Step a **Morton Key** to its neighbour at the same level in Morton order, carrying through the
+/// interleaved bits of its anchor from the least significant, `None` if the step leaves the
+/// domain. Works on the anchors directly, so holds at any depth.
+fn step(key: &Key, depth: &u64, forward: bool) -> Option<Key> {
+    let shift = side_shift(depth, &key.3);
+    let mut anchor = [key.0 / shift, key.1 / shift, key.2 / shift];
+
+    for bit in 0..key.3 {
+        // At each bit the z coordinate is least significant, then y, then x.
+        for axis in (0..3).rev() {
+            let set = (anchor[axis] >> bit) & 1 == 1;
+            anchor[axis] ^= 1 << bit;
+            if set != forward {
+                return Some(Key(
+                    anchor[0] * shift,
+                    anchor[1] * shift,
+                    anchor[2] * shift,
+                    key.3,
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// Find the octant following a **Morton Key** at the same level in Morton order, `None` for the
+/// last octant of the domain.
+pub fn next(key: &Key, depth: &u64) -> Option<Key> {
+    step(key, depth, true)
+}
+
+/// Find the octant preceding a **Morton Key** at the same level in Morton order, `None` for the
+/// first octant of the domain.
+pub fn prev(key: &Key, depth: &u64) -> Option<Key> {
+    step(key, depth, false)
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.partial_cmp(other).unwrap()
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let less = less_than(self, other).unwrap();
+        let eq = self.eq(other);
+
+        match eq {
+            true => Some(Ordering::Equal),
+            false => match less {
+                true => Some(Ordering::Less),
+                false => Some(Ordering::Greater),
+            },
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        equal(self, other)
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+        self.1.hash(state);
+        self.2.hash(state);
+        self.3.hash(state);
+    }
+}
+
+/// Side length of an octant at a given level, in units of the finest octants at `depth`. The level
+/// must be no deeper than `depth`, and the shift mustn't overflow a **KeyType**. This is only
+/// checked in debug builds, so depths and levels from callers are validated once with
+/// `check_level` instead.
+pub fn side_shift(depth: &u64, level: &u64) -> u64 {
+    debug_assert!(
+        level <= depth,
+        "Key at level {} is deeper than the tree depth {}",
+        level,
+        depth
+    );
+    let level_diff = depth.wrapping_sub(*level);
+    debug_assert!(
+        level_diff < KeyType::BITS as u64,
+        "Level difference {} overflows a {} bit shift",
+        level_diff,
+        KeyType::BITS
+    );
+    1 << level_diff
+}
+
+/// Test whether the anchor of a **Morton Key** is consistent with a tree of the given depth, lying
+/// inside the domain and aligned to the side length of octants at the key's level. This only
+/// catches keys whose anchors are out of range or misaligned at `depth`, a key built for a
+/// shallower depth often passes, so use the key operations of an **Octree**, which carry its
+/// depth, to rule out mixing depths. Panics like `side_shift` in debug builds if the key is deeper
+/// than `depth`.
+pub fn anchor_matches_depth(key: &Key, depth: &u64) -> bool {
+    let shift = side_shift(depth, &key.3);
+    let max = side_shift(depth, &0);
+    [key.0, key.1, key.2]
+        .iter()
+        .all(|&a| (a < max) & (a % shift == 0))
+}
+
+/// Check that the anchor of a **Morton Key** is in range and aligned at the depth of the tree in
+/// debug builds, see `anchor_matches_depth`.
+fn debug_assert_anchor(key: &Key, depth: &u64) {
+    debug_assert!(
+        anchor_matches_depth(key, depth),
+        "Key {:?} is inconsistent with the tree depth {}",
+        key,
+        depth
+    );
+}
+
+/// Subroutine for finding the parent of a Morton key in its component representation. The trick
+/// is to figure out whether the anchor of a key survives at its parent level, and notice that
+/// anchors at odd indices don't survive. `parent_level_diff' refers to the difference between the
+/// parent's key's level, and the maximum depth of the tree.
+fn odd_index(idx: u64, parent_level_diff: u64) -> bool {
+    let factor = 1 << parent_level_diff;
+    (idx % factor) != 0
+}
+
+/// Find the parent of a **Morton Key**. Parents contain the key, and are at the previous level of
+/// discretisation.
+pub fn find_parent(key: &Key, depth: &u64) -> Key {
+    let shift = side_shift(depth, &key.3);
+    debug_assert_anchor(key, depth);
+
+    // Return root if root fed in
+    if (key.0 == 0) & (key.1 == 0) & (key.2 == 0) {
+        match key.3 {
+            0 => Key(0, 0, 0, 0),
+            _ => Key(0, 0, 0, key.3 - 1),
+        }
+    } else {
+        let parent_level_diff = depth - (key.3 - 1);
+
+        let x_odd = odd_index(key.0, parent_level_diff);
+        let y_odd = odd_index(key.1, parent_level_diff);
+        let z_odd = odd_index(key.2, parent_level_diff);
+
+        let mut parent = Key(key.0, key.1, key.2, key.3 - 1);
+
+        if x_odd {
+            parent.0 = key.0 - shift;
+        };
+        if y_odd {
+            parent.1 = key.1 - shift;
+        }
+        if z_odd {
+            parent.2 = key.2 - shift;
+        }
+        parent
+    }
+}
+
+/// Find the siblings of a **Morton Key**, without allocating. Siblings share the same parent, so
+/// their anchors are found directly by masking the key's anchor to the parent's anchor and adding
+/// the eight offsets.
+pub fn siblings_array(key: &Key, depth: &u64) -> [Key; 8] {
+    let shift: u64 = side_shift(depth, &key.3);
+    debug_assert_anchor(key, depth);
+    let mask: u64 = !((shift << 1).wrapping_sub(1));
+
+    let mut siblings = [*key; 8];
+
+    // Offsets ordered as nested loops over x, y then z.
+    for (idx, sibling) in siblings.iter_mut().enumerate() {
+        let (i, j, k) = ((idx >> 2) as u64, ((idx >> 1) & 1) as u64, (idx & 1) as u64);
+        sibling.0 = (key.0 & mask) + shift * i;
+        sibling.1 = (key.1 & mask) + shift * j;
+        sibling.2 = (key.2 & mask) + shift * k;
+    }
+    siblings
+}
+
+/// Find the siblings of a **Morton Key**.
+pub fn find_siblings(key: &Key, depth: &u64) -> Keys {
+    let siblings = siblings_array(key, depth).to_vec();
+    debug_assert!((key.3 == 0) | (siblings == find_siblings_from_parent(key, depth)));
+    siblings
+}
+
+/// Find the siblings of a **Morton Key** by constructing the first child of its parent, reference
+/// implementation for `find_siblings`.
+fn find_siblings_from_parent(key: &Key, depth: &u64) -> Keys {
+    let parent = find_parent(key, depth);
+
+    let mut first_child = parent;
+    first_child.3 += 1;
+
+    let mut siblings: Keys = Vec::new();
+
+    let shift = side_shift(depth, &key.3);
+
+    for i in 0..2 {
+        for j in 0..2 {
+            for k in 0..2 {
+                siblings.push(Key(
+                    first_child.0 + shift * i,
+                    first_child.1 + shift * j,
+                    first_child.2 + shift * k,
+                    first_child.3,
+                ));
+            }
+        }
+    }
+    siblings
+}
+
+/// Find the children of a **Morton Key**, without allocating.
+pub fn children_array(key: &Key, depth: &u64) -> [Key; 8] {
+    let mut first_child = *key;
+    first_child.3 += 1;
+    siblings_array(&first_child, depth)
+}
+
+/// Find the children of a **Morton Key**.
+pub fn find_children(key: &Key, depth: &u64) -> Keys {
+    children_array(key, depth).to_vec()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// **Depths**, the depth along each of the x, y and z axes, for domains refined to a different
+/// resolution along each axis, such as thin slabs. Keys are anchored at the deepest of the depths,
+/// and an axis is no longer split once the level passes its own depth, so the anchor of a shallow
+/// axis stays aligned to its coarser octants. These keys are ordinary **Morton Keys**, and are
+/// ordered, compared and communicated as any other.
+pub struct Depths(pub u64, pub u64, pub u64);
+
+impl Depths {
+    /// Equal depths along every axis, matching an isotropic tree of the given depth.
+    pub fn uniform(depth: u64) -> Depths {
+        Depths(depth, depth, depth)
+    }
+
+    /// The deepest of the depths, at which keys are anchored.
+    pub fn max(&self) -> u64 {
+        self.0.max(self.1).max(self.2)
+    }
+
+    /// Component of the anchor of a **Morton Key** along each axis, in units of the deepest
+    /// octants along that axis.
+    pub fn anchor(&self, key: &Key) -> [u64; 3] {
+        let depth = self.max();
+        [
+            key.0 >> (depth - self.0),
+            key.1 >> (depth - self.1),
+            key.2 >> (depth - self.2),
+        ]
+    }
+}
+
+/// Find the children of a **Morton Key** with per axis depths. Only axes which haven't passed
+/// their depth are split, so a key has two, four or eight children. Their parent is found with
+/// `find_parent` at the deepest of the depths, as masking an axis which wasn't split leaves it
+/// unchanged.
+pub fn find_children_per_axis(key: &Key, depths: &Depths) -> Keys {
+    let level = key.3 + 1;
+    let shift = side_shift(&depths.max(), &level);
+    let splits = |depth: u64| if level <= depth { 2 } else { 1 };
+
+    let mut children: Keys = Vec::new();
+
+    for i in 0..splits(depths.0) {
+        for j in 0..splits(depths.1) {
+            for k in 0..splits(depths.2) {
+                children.push(Key(
+                    key.0 + shift * i,
+                    key.1 + shift * j,
+                    key.2 + shift * k,
+                    level,
+                ));
+            }
+        }
+    }
+    children
+}
+
+/// Find all ancestors of a **Morton Key**, excludes the key. Ancestors are ordered from the parent
+/// up to the root, with one ancestor at every coarser level.
+pub fn find_ancestors(key: &Key, depth: &u64) -> Keys {
+    let root = Key(0, 0, 0, 0);
+    let mut parent = find_parent(key, depth);
+    let mut ancestors: Keys = vec![parent];
+
+    while parent != root {
+        parent = find_parent(&parent, depth);
+        ancestors.push(parent);
+    }
+    ancestors
+}
+
+/// Find the ancestor of a **Morton Key** at a given coarser level, found by masking its anchor to
+/// the side length of octants at that level.
+pub fn find_ancestor_at_level(key: &Key, level: &u64, depth: &u64) -> Key {
+    debug_assert!(*level <= key.3);
+    debug_assert_anchor(key, depth);
+    let shift: u64 = side_shift(depth, level);
+    let mask: u64 = !(shift - 1);
+    Key(key.0 & mask, key.1 & mask, key.2 & mask, *level)
+}
+
+/// The deepest first descendent of a **Morton Key**. First descendants always share anchors.
+pub fn find_deepest_first_descendent(key: &Key, depth: &u64) -> Key {
+    if key.3 < *depth {
+        Key(key.0, key.1, key.2, *depth)
+    } else {
+        *key
+    }
+}
+
+/// The deepest last descendent of a **Morton Key**, at the far corner of its anchor. At the
+/// deepest level nodes are considered to have side lengths of 1.
+pub fn find_deepest_last_descendent(key: &Key, depth: &u64) -> Key {
+    let shift = side_shift(depth, &key.3);
+    Key(
+        key.0 + shift - 1,
+        key.1 + shift - 1,
+        key.2 + shift - 1,
+        *depth,
+    )
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_siblings_from_parent() {
+        let depth = 4;
+
+        for level in 1..(depth + 1) {
+            let shift = 1 << (depth - level);
+            let max = 1 << depth;
+            let key = Key(3 * shift % max, 2 * shift % max, 5 * shift % max, level);
+
+            let mut expected = find_siblings_from_parent(&key, &depth);
+            expected.sort();
+
+            let mut result = find_siblings(&key, &depth);
+            result.sort();
+            assert_eq!(result, expected);
+        }
+    }
+
+    #[test]
+    fn test_find_ancestors_root_corner() {
+        // Keys anchored at the origin must still step up one level at a time.
+        let depth = 4;
+        let key = Key(0, 0, 0, 3);
+        let expected: Keys = vec![Key(0, 0, 0, 2), Key(0, 0, 0, 1), Key(0, 0, 0, 0)];
+        assert_eq!(find_ancestors(&key, &depth), expected);
+
+        let key = Key(0, 0, 0, depth);
+        let ancestors = find_ancestors(&key, &depth);
+        assert_eq!(ancestors.len(), depth as usize);
+        for (i, ancestor) in ancestors.iter().enumerate() {
+            assert_eq!(*ancestor, Key(0, 0, 0, depth - 1 - i as u64));
+        }
+    }
+
+    #[test]
+    fn test_keys_ancestry() {
+        let depth = 3;
+        let key = Key(3, 5, 1, 3);
+
+        // Test that walking up parents matches the ancestors
+        let ancestors = find_ancestors(&key, &depth);
+        let mut parent = key;
+        for ancestor in ancestors.iter() {
+            parent = find_parent(&parent, &depth);
+            assert_eq!(parent, *ancestor);
+            assert!(parent < key);
+        }
+        assert_eq!(parent, Key(0, 0, 0, 0));
+
+        // Test that the deepest descendents of the root bound every key
+        let root = Key(0, 0, 0, 0);
+        let dfd = find_deepest_first_descendent(&root, &depth);
+        let dld = find_deepest_last_descendent(&root, &depth);
+        assert!((dfd <= key) & (key <= dld));
+    }
+
+    #[test]
+    fn test_side_shift() {
+        let depth = 4;
+        assert_eq!(side_shift(&depth, &depth), 1);
+        assert_eq!(side_shift(&depth, &0), 16);
+        assert_eq!(side_shift(&63, &0), 1 << 63);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "deeper than the tree depth")]
+    fn test_key_deeper_than_depth() {
+        let depth = 2;
+        let key = Key(1, 1, 1, depth + 1);
+        find_parent(&key, &depth);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "inconsistent with the tree depth")]
+    fn test_mixed_depths() {
+        // A key at level 2 of a depth 3 tree, whose anchor lies outside of a depth 2 tree
+        let key = Key(6, 2, 4, 2);
+        assert!(anchor_matches_depth(&key, &3));
+        assert!(!anchor_matches_depth(&key, &2));
+        find_parent(&key, &2);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overflows")]
+    fn test_side_shift_overflow() {
+        side_shift(&64, &0);
+    }
+
+    #[test]
+    fn test_arrays_match_vecs() {
+        let depth = 4;
+
+        for level in 0..depth {
+            let shift = 1 << (depth - level);
+            let max = 1 << depth;
+            let key = Key(3 * shift % max, 5 * shift % max, 6 * shift % max, level);
+
+            // Test that the arrays contain the same keys, in the same order
+            assert_eq!(
+                children_array(&key, &depth).to_vec(),
+                find_children(&key, &depth)
+            );
+            let child = children_array(&key, &depth)[5];
+            assert_eq!(
+                siblings_array(&child, &depth).to_vec(),
+                find_siblings(&child, &depth)
+            );
+            assert!(siblings_array(&child, &depth).contains(&child));
+            assert_eq!(
+                siblings_array(&child, &depth).to_vec(),
+                find_siblings_from_parent(&child, &depth)
+            );
+        }
+    }
+
+    #[test]
+    fn test_per_axis_depths() {
+        let depths = Depths(3, 3, 1);
+        let depth = depths.max();
+        let root = Key(0, 0, 0, 0);
+
+        // Test that uniform depths match an isotropic tree
+        let uniform = Depths::uniform(depth);
+        assert_eq!(
+            find_children_per_axis(&root, &uniform),
+            find_children(&root, &depth)
+        );
+
+        // Walk every key down to the deepest level, in depth first order
+        let mut keys: Keys = Vec::new();
+        let mut stack: Keys = vec![root];
+        while let Some(key) = stack.pop() {
+            keys.push(key);
+            if key.3 == depth {
+                continue;
+            }
+
+            let mut children = find_children_per_axis(&key, &depths);
+
+            // Test that z is only split while the level is within its depth
+            assert_eq!(children.len(), if key.3 < depths.2 { 8 } else { 4 });
+            for child in children.iter() {
+                assert_eq!(find_parent(child, &depth), key);
+            }
+
+            children.sort();
+            stack.extend(children.iter().rev());
+        }
+
+        // Test that the z component never exceeds 2^1
+        assert!(keys.iter().all(|k| depths.anchor(k)[2] < (1 << depths.2)));
+        assert_eq!(keys.iter().filter(|k| k.3 == depth).count(), 8 * 8 * 2);
+
+        // Test that each subtree is contiguous in Morton order, so that the keys are ordered
+        // consistently with their tree
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn test_next_prev() {
+        let depth = 3;
+        let level = 2;
+        let shift = side_shift(&depth, &level);
+
+        let mut keys: Keys = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    keys.push(Key(x * shift, y * shift, z * shift, level));
+                }
+            }
+        }
+        keys.sort();
+
+        // Test that the domain ends have no successor or predecessor
+        assert_eq!(next(keys.last().unwrap(), &depth), None);
+        assert_eq!(prev(&keys[0], &depth), None);
+        assert_eq!(next(&Key(0, 0, 0, 0), &depth), None);
+
+        // Test that next follows Morton order, and that next and prev are inverses in the interior
+        for pair in keys.windows(2) {
+            assert_eq!(next(&pair[0], &depth), Some(pair[1]));
+            assert_eq!(prev(&pair[1], &depth), Some(pair[0]));
+        }
+
+        // Test the deepest keys, whose Morton indices don't fit in 128 bits
+        let depth = 63;
+        let last = (1 << 63) - 1;
+        assert_eq!(
+            next(&Key(0, 0, 1, depth), &depth),
+            Some(Key(0, 1, 0, depth))
+        );
+        assert_eq!(
+            prev(&Key(1 << 62, 0, 0, depth), &depth),
+            Some(Key((1 << 62) - 1, last, last, depth))
+        );
+        assert_eq!(next(&Key(last, last, last, depth), &depth), None);
+    }
+}
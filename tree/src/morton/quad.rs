@@ -0,0 +1,537 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use super::check_level;
+use super::keys::{most_significant_bit, side_shift, LEVEL_BITS};
+use crate::error::TreeError;
+
+type KeyType = u64;
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+/// **Quadtree Morton Key**, anchor and level represented as (x, y, level), the 2D analogue of a
+/// **Morton Key** with four children per node. The default is the root.
+pub struct Key2(pub KeyType, pub KeyType, pub KeyType);
+/// Vector of **Quadtree Keys**.
+pub type Keys2 = Vec<Key2>;
+
+#[derive(Clone, Copy, Debug)]
+/// **Quadtree Point**, Cartesian coordinates (x, y), the 2D analogue of a **Point**.
+pub struct Point2 {
+    pub x: f64,
+    pub y: f64,
+    pub key: Key2,
+    pub global_idx: usize,
+}
+
+/// Vector of **Quadtree Points**.
+pub type Points2 = Vec<Point2>;
+
+impl Point2 {
+    /// Create a **Quadtree Point** from its coordinates, with a default key and global index.
+    pub fn new(x: f64, y: f64) -> Point2 {
+        Point2::with_index(x, y, 0)
+    }
+
+    /// Create a **Quadtree Point** from its coordinates and global index, with a default key.
+    pub fn with_index(x: f64, y: f64, global_idx: usize) -> Point2 {
+        Point2 {
+            x,
+            y,
+            key: Key2::default(),
+            global_idx,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// **Quadtree Leaf**, bundles a **Quadtree Key**, its **Block** and the number of **Points** it
+/// contains, the 2D analogue of a **Leaf**.
+pub struct Leaf2 {
+    pub key: Key2,
+    pub block: Key2,
+    pub npoints: usize,
+}
+
+/// Vector of **Quadtree Leaves**.
+pub type Leaves2 = Vec<Leaf2>;
+
+/// **Quadtree**, **Quadtree Leaves** grouped by the **Block** containing them.
+pub type Tree2 = HashMap<Key2, Leaves2>;
+
+/// Compare the ordering of two **Quadtree Keys**, as Algorithm 12 in [1] does in 3D. If key `a`
+/// is less than key `b`, this function evaluates to true.
+fn less_than(a: &Key2, b: &Key2) -> bool {
+    // If anchors match, the one at the coarser level has the lesser Morton id.
+    if (a.0 == b.0) & (a.1 == b.1) {
+        a.2 < b.2
+    } else if most_significant_bit(a.0 ^ b.0, a.1 ^ b.1) {
+        a.1 < b.1
+    } else {
+        a.0 < b.0
+    }
+}
+
+impl Ord for Key2 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            Ordering::Equal
+        } else if less_than(self, other) {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    }
+}
+
+impl PartialOrd for Key2 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compute the canonical **Z Index** of a **Quadtree Key**, interleaving the bits of its anchor
+/// with x the most significant axis, followed by its level. The depth must be at most 60.
+pub fn z_index(key: &Key2, depth: &u64) -> u128 {
+    debug_assert!(2 * depth + LEVEL_BITS <= 128);
+
+    let mut index: u128 = 0;
+
+    for bit in (0..*depth).rev() {
+        index = (index << 1) | ((key.0 >> bit) & 1) as u128;
+        index = (index << 1) | ((key.1 >> bit) & 1) as u128;
+    }
+
+    (index << LEVEL_BITS) | key.2 as u128
+}
+
+/// Check that every **Quadtree Point** lies inside the square domain with centre `x0` and half
+/// side length `r0`, closed below and open above as `check_domain` is for octrees. Points outside
+/// are reported with a z coordinate of zero.
+pub fn check_domain(points: &[Point2], x0: &Point2, r0: &f64) -> Result<(), TreeError> {
+    let inside = |c: f64, c0: f64| (c0 - r0 <= c) & (c < c0 + r0);
+
+    match points
+        .iter()
+        .find(|p| !(inside(p.x, x0.x) & inside(p.y, x0.y)))
+    {
+        Some(p) => Err(TreeError::OutOfDomain {
+            x: p.x,
+            y: p.y,
+            z: 0.,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Find the **Quadtree Key** containing a physical coordinate at a given level, in the square
+/// domain with centre `x0` and half side length `r0`. Fails if the coordinate lies outside of the
+/// domain.
+pub fn key_from_coords(
+    x: f64,
+    y: f64,
+    level: &u64,
+    depth: &u64,
+    x0: &Point2,
+    r0: &f64,
+) -> Result<Key2, TreeError> {
+    check_domain(&[Point2::new(x, y)], x0, r0)?;
+
+    let side_length: f64 = (r0 * 2.) / (side_shift(depth, &0) as f64);
+
+    let key = Key2(
+        ((x - (x0.x - r0)) / side_length).floor() as u64,
+        ((y - (x0.y - r0)) / side_length).floor() as u64,
+        *depth,
+    );
+    Ok(find_ancestor_at_level(&key, level, depth))
+}
+
+/// Encode **Quadtree Points** in **Quadtree Keys** at a given level, fails if any point lies
+/// outside of the domain (sequential).
+pub fn encode_points(
+    points: &mut [Point2],
+    level: &u64,
+    depth: &u64,
+    x0: &Point2,
+    r0: &f64,
+) -> Result<(), TreeError> {
+    check_domain(points, x0, r0)?;
+    for point in points.iter_mut() {
+        point.key = key_from_coords(point.x, point.y, level, depth, x0, r0)?;
+    }
+    Ok(())
+}
+
+/// Find the ancestor of a **Quadtree Key** at a given coarser level, found by masking its anchor
+/// to the side length of nodes at that level.
+pub fn find_ancestor_at_level(key: &Key2, level: &u64, depth: &u64) -> Key2 {
+    debug_assert!(*level <= key.2);
+    let shift: u64 = side_shift(depth, level);
+    let mask: u64 = !(shift - 1);
+    Key2(key.0 & mask, key.1 & mask, *level)
+}
+
+/// Find the parent of a **Quadtree Key**, the root is its own parent.
+pub fn find_parent(key: &Key2, depth: &u64) -> Key2 {
+    match key.2 {
+        0 => *key,
+        level => find_ancestor_at_level(key, &(level - 1), depth),
+    }
+}
+
+/// Find all ancestors of a **Quadtree Key**, excludes the key. Ancestors are ordered from the
+/// parent up to the root.
+pub fn find_ancestors(key: &Key2, depth: &u64) -> Keys2 {
+    (0..key.2)
+        .rev()
+        .map(|level| find_ancestor_at_level(key, &level, depth))
+        .collect()
+}
+
+/// Find the siblings of a **Quadtree Key**, without allocating.
+pub fn siblings_array(key: &Key2, depth: &u64) -> [Key2; 4] {
+    let shift: u64 = side_shift(depth, &key.2);
+    let mask: u64 = !((shift << 1).wrapping_sub(1));
+
+    let mut siblings = [*key; 4];
+
+    // Offsets ordered as nested loops over x then y.
+    for (idx, sibling) in siblings.iter_mut().enumerate() {
+        let (i, j) = ((idx >> 1) as u64, (idx & 1) as u64);
+        sibling.0 = (key.0 & mask) + shift * i;
+        sibling.1 = (key.1 & mask) + shift * j;
+    }
+    siblings
+}
+
+/// Find the siblings of a **Quadtree Key**.
+pub fn find_siblings(key: &Key2, depth: &u64) -> Keys2 {
+    siblings_array(key, depth).to_vec()
+}
+
+/// Find the children of a **Quadtree Key**, without allocating.
+pub fn children_array(key: &Key2, depth: &u64) -> [Key2; 4] {
+    siblings_array(&Key2(key.0, key.1, key.2 + 1), depth)
+}
+
+/// Find the children of a **Quadtree Key**.
+pub fn find_children(key: &Key2, depth: &u64) -> Keys2 {
+    children_array(key, depth).to_vec()
+}
+
+/// Find the finest common ancestor of two **Quadtree Keys**, excluding the keys themselves.
+pub fn find_finest_common_ancestor(a: &Key2, b: &Key2, depth: &u64) -> Key2 {
+    let level = a.2.min(b.2);
+    find_ancestors(&find_ancestor_at_level(a, &level, depth), depth)
+        .into_iter()
+        .chain(std::iter::once(Key2(0, 0, 0)))
+        .find(|ancestor| find_ancestor_at_level(b, &ancestor.2, depth) == *ancestor)
+        .unwrap()
+}
+
+/// The deepest first descendent of a **Quadtree Key**, which shares its anchor.
+pub fn find_deepest_first_descendent(key: &Key2, depth: &u64) -> Key2 {
+    Key2(key.0, key.1, *depth)
+}
+
+/// The deepest last descendent of a **Quadtree Key**, at the far corner of its anchor.
+pub fn find_deepest_last_descendent(key: &Key2, depth: &u64) -> Key2 {
+    let shift = side_shift(depth, &key.2);
+    Key2(key.0 + shift - 1, key.1 + shift - 1, *depth)
+}
+
+/// Construct a minimal quadtree between two nodes, excluding the two nodes, as `complete_region`
+/// does for octrees (sequential).
+pub fn complete_region(a: &Key2, b: &Key2, depth: &u64) -> Keys2 {
+    let ancestors_a = find_ancestors(a, depth);
+    let ancestors_b = find_ancestors(b, depth);
+    let na = find_finest_common_ancestor(a, b, depth);
+
+    let mut working_list: Keys2 = find_children(&na, depth);
+    let mut minimal_tree: Keys2 = Vec::new();
+
+    while let Some(w) = working_list.pop() {
+        if ((*a < w) & (w < *b)) & !ancestors_b.contains(&w) {
+            minimal_tree.push(w);
+        } else if ancestors_a.contains(&w) | ancestors_b.contains(&w) {
+            working_list.extend(children_array(&w, depth).iter());
+        }
+    }
+
+    minimal_tree.sort();
+    minimal_tree
+}
+
+/// Find the unique **Quadtree Leaves** of **Quadtree Points** sorted in Morton order, counting the
+/// points in each, all in the root **Block** (sequential).
+pub fn unique_leaves(points: &[Point2]) -> Leaves2 {
+    let mut unique: Leaves2 = Vec::new();
+
+    for point in points.iter() {
+        match unique.last_mut() {
+            Some(leaf) if leaf.key == point.key => leaf.npoints += 1,
+            _ => unique.push(Leaf2 {
+                key: point.key,
+                block: Key2::default(),
+                npoints: 1,
+            }),
+        }
+    }
+    unique
+}
+
+/// Split **Quadtree Blocks** to satisfy a maximum of `ncrit` points per block, as `split_blocks`
+/// does for octrees. Blocks at the maximum depth can't be split further, so fail if one of them
+/// is over capacity (sequential).
+pub fn split_blocks(leaves: &[Leaf2], depth: &u64, ncrit: &usize) -> Result<Tree2, TreeError> {
+    let mut blocks: Tree2 = HashMap::new();
+    for &leaf in leaves.iter() {
+        blocks.entry(leaf.block).or_default().push(leaf);
+    }
+
+    let mut working_list: Keys2 = blocks.keys().copied().collect();
+
+    while let Some(block) = working_list.pop() {
+        let npoints: usize = blocks[&block].iter().map(|l| l.npoints).sum();
+        if npoints <= *ncrit {
+            continue;
+        }
+        if block.2 >= *depth {
+            return Err(TreeError::QuadtreeNcrit {
+                block,
+                npoints,
+                ncrit: *ncrit,
+            });
+        }
+
+        let level = block.2 + 1;
+        for mut leaf in blocks.remove(&block).unwrap() {
+            leaf.block = find_ancestor_at_level(&leaf.key, &level, depth);
+            blocks.entry(leaf.block).or_default().push(leaf);
+        }
+        working_list.extend(
+            children_array(&block, depth)
+                .iter()
+                .filter(|child| blocks.contains_key(child)),
+        );
+    }
+    Ok(blocks)
+}
+
+/// Build a quadtree over **Quadtree Points**, grouping their unique **Leaves** at the maximum depth
+/// by **Blocks** holding at most `ncrit` points. Fails if a point lies outside of the domain, or
+/// if the points can't be split to satisfy `ncrit`. The tree is built at a single process, so its
+/// coarse blocktree is the root, distributed construction is only implemented for octrees
+/// (sequential).
+pub fn unbalanced_tree(
+    points: &mut [Point2],
+    depth: &u64,
+    ncrit: &usize,
+    x0: &Point2,
+    r0: &f64,
+) -> Result<Tree2, TreeError> {
+    check_level(depth, &0)?;
+    encode_points(points, depth, depth, x0, r0)?;
+    points.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let leaves = unique_leaves(points);
+    split_blocks(&leaves, depth, ncrit)
+}
+
+mod tests {
+    use super::*;
+
+    use crate::data::random;
+
+    #[test]
+    fn test_find_parent() {
+        let depth = 3;
+        let child = Key2(3, 3, 3);
+        assert_eq!(find_parent(&child, &depth), Key2(2, 2, 2));
+
+        let child = Key2(2, 2, 2);
+        assert_eq!(find_parent(&child, &depth), Key2(0, 0, 1));
+
+        let child = Key2(0, 0, 1);
+        assert_eq!(find_parent(&child, &depth), Key2(0, 0, 0));
+
+        let child = Key2(0, 0, 0);
+        assert_eq!(find_parent(&child, &depth), Key2(0, 0, 0));
+    }
+
+    #[test]
+    fn test_key_from_coords() {
+        let depth = 3;
+        let x0 = Point2::new(0.5, 0.5);
+        let r0 = 0.5;
+
+        // Test that coordinates are encoded at the finest level, and masked to coarser ones
+        assert_eq!(
+            key_from_coords(0.3, 0.9, &depth, &depth, &x0, &r0),
+            Ok(Key2(2, 7, 3))
+        );
+        assert_eq!(
+            key_from_coords(0.3, 0.9, &1, &depth, &x0, &r0),
+            Ok(Key2(0, 4, 1))
+        );
+
+        // Test that coordinates outside of the domain fail, rather than clamping
+        for &(x, y) in [(-0.1, 0.5), (0.5, 1.0)].iter() {
+            assert_eq!(
+                key_from_coords(x, y, &depth, &depth, &x0, &r0),
+                Err(TreeError::OutOfDomain { x, y, z: 0. })
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_children() {
+        let key = Key2(0, 0, 0);
+        let depth = 5;
+        let shift = 1 << (depth - (key.2 + 1));
+        let mut expected: Keys2 = vec![
+            Key2(0, 0, 1),
+            Key2(shift, 0, 1),
+            Key2(0, shift, 1),
+            Key2(shift, shift, 1),
+        ];
+        expected.sort();
+
+        let result = find_children(&key, &depth);
+        assert_eq!(result, expected);
+
+        // Test that every child's parent is the key
+        assert!(result.iter().all(|c| find_parent(c, &depth) == key));
+    }
+
+    #[test]
+    fn test_find_siblings() {
+        let depth = 3;
+        let key = Key2(6, 2, 2);
+        let shift = 1 << (depth - key.2);
+        let mut expected: Keys2 = vec![
+            Key2(4, 0, 2),
+            Key2(4 + shift, 0, 2),
+            Key2(4, shift, 2),
+            Key2(4 + shift, shift, 2),
+        ];
+        expected.sort();
+
+        let mut result = find_siblings(&key, &depth);
+        result.sort();
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_sorting() {
+        let depth = 3;
+
+        // Test that the ordering matches the Z index, at every level
+        let mut keys: Keys2 = Vec::new();
+        for level in 0..=depth {
+            let shift = 1 << (depth - level);
+            for x in 0..(1 << level) {
+                for y in 0..(1 << level) {
+                    keys.push(Key2(x * shift, y * shift, level));
+                }
+            }
+        }
+
+        let mut expected = keys.clone();
+        expected.sort_by_key(|k| z_index(k, &depth));
+        keys.sort();
+        assert_eq!(keys, expected);
+
+        // Test that children are greater than their parent, and bounded by its deepest descendents
+        let parent = Key2(4, 0, 1);
+        let dfd = find_deepest_first_descendent(&parent, &depth);
+        let dld = find_deepest_last_descendent(&parent, &depth);
+        for child in find_children(&parent, &depth) {
+            assert!(parent < child);
+            assert!(dfd <= find_deepest_first_descendent(&child, &depth));
+            assert!(find_deepest_last_descendent(&child, &depth) <= dld);
+        }
+    }
+
+    #[test]
+    fn test_complete_region() {
+        let depth = 3;
+        let a = Key2(0, 0, 3);
+        let b = Key2(7, 7, 3);
+
+        let region = complete_region(&a, &b, &depth);
+
+        // Test that the region is sorted, lies between the keys, and covers the domain with them
+        assert!(region.windows(2).all(|w| w[0] < w[1]));
+        assert!(region.iter().all(|k| (a < *k) & (*k < b)));
+
+        let area: u64 = region
+            .iter()
+            .chain([a, b].iter())
+            .map(|k| side_shift(&depth, &k.2).pow(2))
+            .sum();
+        assert_eq!(area, 1 << (2 * depth));
+
+        // Test that the finest common ancestor of keys in sibling subtrees is their parent
+        let fca = find_finest_common_ancestor(&Key2(0, 2, 3), &Key2(3, 3, 3), &depth);
+        assert_eq!(fca, Key2(0, 0, 1));
+    }
+
+    #[test]
+    fn test_unbalanced_tree() {
+        let depth = 4;
+        let ncrit: usize = 20;
+        let x0 = Point2::new(0.5, 0.5);
+        let r0 = 0.5;
+
+        let mut points: Points2 = random(1000).iter().map(|p| Point2::new(p.x, p.y)).collect();
+        let tree = unbalanced_tree(&mut points, &depth, &ncrit, &x0, &r0).unwrap();
+
+        // Test that no points are lost, and that every block satisfies ncrit
+        let npoints: usize = tree.values().flatten().map(|l| l.npoints).sum();
+        assert_eq!(npoints, points.len());
+        for (block, leaves) in tree.iter() {
+            assert!(leaves.iter().map(|l| l.npoints).sum::<usize>() <= ncrit);
+
+            // Test that leaves lie inside their block, at the maximum depth
+            for leaf in leaves.iter() {
+                assert_eq!(leaf.block, *block);
+                assert_eq!(leaf.key.2, depth);
+                assert_eq!(find_ancestor_at_level(&leaf.key, &block.2, &depth), *block);
+            }
+        }
+
+        // Test that blocks don't overlap, so no block is the ancestor of another
+        let mut blocks: Keys2 = tree.keys().copied().collect();
+        blocks.sort();
+        for pair in blocks.windows(2) {
+            assert!(find_deepest_last_descendent(&pair[0], &depth) < pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_unbalanced_tree_errors() {
+        let depth = 3;
+        let ncrit: usize = 5;
+        let x0 = Point2::new(0.5, 0.5);
+        let r0 = 0.5;
+
+        // Test that a point outside of the domain fails
+        let mut points = vec![Point2::new(0.5, 0.5), Point2::new(1.5, 0.5)];
+        assert_eq!(
+            unbalanced_tree(&mut points, &depth, &ncrit, &x0, &r0),
+            Err(TreeError::OutOfDomain {
+                x: 1.5,
+                y: 0.5,
+                z: 0.
+            })
+        );
+
+        // Test that coincident points which overflow a block at the maximum depth fail
+        let mut points = vec![Point2::new(0.3, 0.3); ncrit + 1];
+        let result = unbalanced_tree(&mut points, &depth, &ncrit, &x0, &r0);
+        assert!(matches!(
+            result,
+            Err(TreeError::QuadtreeNcrit { npoints: 6, .. })
+        ));
+    }
+}
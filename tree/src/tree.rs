@@ -1,26 +1,81 @@
 use std::time::Instant;
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::iter::Sum;
+use std::ops::Add;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::Duration;
 
 use memoffset::offset_of;
 use mpi::{
     collective::SystemOperation,
     datatype::{Equivalence, UncommittedUserDatatype, UserDatatype, Partition, PartitionMut},
-    environment::Universe,
-    topology::{Rank, SystemCommunicator},
+    topology::{Rank, UserCommunicator},
     traits::*,
-    Address, Count
+    Address, Count, Tag
 };
-use rand::{thread_rng, Rng};
-
+#[cfg(feature = "ndarray")]
+use ndarray::{Array1, ArrayView1};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::comm::{
+    agree, all_reduce_bounding_box, barrier_timed, displacements, max_msg_elements,
+    receive_fragmented, scan_exclusive, send_fragmented, TAG_BLOCKS, TAG_LEAVES, TAG_POINTS,
+};
+use crate::error::TreeError;
+#[cfg(feature = "f32")]
+use crate::morton::PackedPoint;
 use crate::morton::{
-    encode_points, find_ancestors, find_children, find_deepest_first_descendent,
-    find_deepest_last_descendent, find_finest_common_ancestor, keys_to_leaves, Key, Keys, Leaf,
-    Leaves, Point, Points,
+    check_domain, check_level, children_array, encode_points, encode_points_chunked,
+    find_ancestor_at_level, find_ancestors, find_children, find_deepest_first_descendent,
+    find_deepest_last_descendent, find_finest_common_ancestor, find_neighbors, find_parent,
+    find_parents, key_from_coords, keys_to_leaves, next, side_shift, Key, Keys, Leaf, Leaves,
+    Point, Points,
 };
 
+/// Evaluate an expression within a `tracing` span named after a phase of construction, when built
+/// with the `tracing` feature.
+#[cfg(feature = "tracing")]
+macro_rules! traced {
+    ($name:expr, $($field:ident = $value:expr),*; $body:expr) => {{
+        let _span = tracing::info_span!($name, $($field = $value),*).entered();
+        $body
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! traced {
+    ($name:expr, $($field:ident = $value:expr),*; $body:expr) => {
+        $body
+    };
+}
+
+/// Emit a `tracing` event recording the sizes of data produced or received in a phase, when built
+/// with the `tracing` feature.
+#[cfg(feature = "tracing")]
+macro_rules! trace_sizes {
+    ($($field:ident = $value:expr),*) => {
+        tracing::info!($($field = $value),*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_sizes {
+    ($($field:ident = $value:expr),*) => {};
+}
+
 /// Sample density for over sampled parallel Sample Sort implementation.
 const K: usize = 10;
 
+/// Maximum distance of each splitter chosen by parallel Histogram Sort from its target position,
+/// as a fraction of the mean number of **Leaves** per process.
+const HISTOGRAM_TOLERANCE: f64 = 0.025;
+
+/// Maximum number of rounds of splitting and balancing in `Octree::refine_until_balanced`.
+const MAX_REFINEMENTS: usize = 64;
+
 /// Null process marker for MPI functions.
 pub const MPI_PROC_NULL: i32 = -1;
 
@@ -30,795 +85,3867 @@ pub type Tree = HashMap<Key, Leaves>;
 /// Type alias for time measurements.
 pub type Times = HashMap<String, u128>;
 
-#[derive(Debug, Copy, Clone)]
-/// **Weight** of a given **Block**. Defined by number of original **Leaf** nodes it contains.
-pub struct Weight(pub u64);
-/// Vector of **Weights**.
-pub type Weights = Vec<Weight>;
+/// Type alias for named field data, one value per local **Leaf** in Morton order.
+#[cfg(feature = "ndarray")]
+pub type Fields = HashMap<String, Vec<f64>>;
+
+#[derive(Debug, Clone)]
+/// **Octree**, the local **Leaves** at a process grouped by their **Block**, bundled with the
+/// global indices of the points in each **Leaf**, the local **Points** in Morton order, any field
+/// data attached to the **Leaves**, and the depth, `ncrit` and domain used to construct them.
+/// Optionally the **Leaves** are also kept contiguously in Morton order, see `keep_sorted_leaves`.
+pub struct Octree {
+    /// Callers editing the **Leaves** directly must call `keep_sorted_leaves` again afterwards if
+    /// sorted leaves are kept.
+    pub tree: Tree,
+    pub(crate) sorted_leaves: Option<Leaves>,
+    pub(crate) coarse_blocks: Keys,
+    pub indices: HashMap<Key, Vec<usize>>,
+    pub points: Points,
+    #[cfg(feature = "ndarray")]
+    pub(crate) fields: Fields,
+    pub depth: u64,
+    pub ncrit: usize,
+    pub x0: Point,
+    pub r0: f64,
+}
 
-unsafe impl Equivalence for Weight {
-    type Out = UserDatatype;
-    fn equivalent_datatype() -> Self::Out {
-        UserDatatype::structured(
-            &[1],
-            &[offset_of!(Weight, 0) as Address],
-            &[UncommittedUserDatatype::contiguous(1, &u64::equivalent_datatype()).as_ref()],
-        )
+impl Octree {
+    /// An **Octree** over the unit cube holding a given tree, without any points, for testing.
+    #[cfg(test)]
+    pub(crate) fn from_tree(tree: Tree, depth: u64) -> Octree {
+        Octree {
+            tree,
+            sorted_leaves: None,
+            coarse_blocks: Vec::new(),
+            indices: HashMap::new(),
+            points: Vec::new(),
+            #[cfg(feature = "ndarray")]
+            fields: HashMap::new(),
+            depth,
+            ncrit: 50,
+            x0: Point::new(0.5, 0.5, 0.5),
+            r0: 0.5,
+        }
     }
-}
 
-/// Adapted from algorithm 3 in [1]. Construct a minimal octree between two octants, excluding the
-/// two octants (sequential).
-pub fn complete_region(a: &Key, b: &Key, depth: &u64) -> Keys {
-    let ancestors_a: HashSet<Key> = find_ancestors(a, depth).into_iter().collect();
-    let ancestors_b: HashSet<Key> = find_ancestors(b, depth).into_iter().collect();
-    let na = find_finest_common_ancestor(a, b, depth);
+    /// Iterate over the local **Leaves** (sequential).
+    pub fn iter_leaves(&self) -> impl Iterator<Item = Leaf> + '_ {
+        self.tree.values().flatten().copied()
+    }
 
-    let mut working_list: HashSet<Key> = find_children(&na, depth).into_iter().collect();
+    /// Number of local **Leaves** (sequential).
+    pub fn n_leaves(&self) -> usize {
+        self.tree.values().map(|leaves| leaves.len()).sum()
+    }
 
-    let mut minimal_tree: Keys = Vec::new();
+    /// Keep a copy of the local **Leaves** sorted in Morton order alongside the **Blocks**, trading
+    /// memory and the cost of keeping it up to date when the tree is refined for contiguous
+    /// iteration with `local_leaves` (sequential).
+    pub fn keep_sorted_leaves(&mut self) {
+        let mut leaves: Leaves = self.iter_leaves().collect();
+        leaves.sort();
+        self.sorted_leaves = Some(leaves);
+    }
 
-    loop {
-        let mut aux_list: HashSet<Key> = HashSet::new();
-        let mut len = 0;
+    /// The local **Leaves** in Morton order as a contiguous slice, `None` unless they are kept
+    /// with `keep_sorted_leaves` (sequential).
+    pub fn local_leaves(&self) -> Option<&[Leaf]> {
+        self.sorted_leaves.as_deref()
+    }
 
-        for w in &working_list {
-            if ((a < w) & (w < b)) & !ancestors_b.contains(w) {
-                aux_list.insert(*w);
-                len += 1;
-            } else if ancestors_a.contains(w) | ancestors_b.contains(w) {
-                for child in find_children(w, depth) {
-                    aux_list.insert(child);
-                }
-            }
-        }
+    /// The coarse **Blocks** of the minimal blocktree completed across processes during
+    /// construction, before they are split into the **Blocks** of the tree, in Morton order. They
+    /// are clipped to the root of a subtree, and follow the **Leaves** they contain when the tree
+    /// is restricted (sequential).
+    pub fn coarse_blocks(&self) -> &[Key] {
+        &self.coarse_blocks
+    }
 
-        if len == working_list.len() {
-            minimal_tree = aux_list.into_iter().collect();
-            break;
-        } else {
-            working_list = aux_list;
+    /// Rebuild the sorted **Leaves** after the **Leaves** change, if they are kept, and drop any
+    /// field data which no longer has a value per leaf.
+    fn leaves_changed(&mut self) {
+        if self.sorted_leaves.is_some() {
+            self.keep_sorted_leaves();
         }
+        #[cfg(feature = "ndarray")]
+        self.fields.clear();
     }
 
-    minimal_tree.sort();
-    minimal_tree
-}
-
-/// Make **Leaves** unique, check that they don't exceed 'ncrit' points per leaf (sequential).
-pub fn unique_leaves(mut leaves: Leaves, ncrit: &usize, sorted: bool) -> Leaves {
-    // Container for result
-    let mut unique: Leaves = Vec::new();
-
-    // Sort leaves
-    if !sorted {
+    /// The field data of the local **Leaves** which satisfy a predicate, keeping their Morton
+    /// order.
+    #[cfg(feature = "ndarray")]
+    fn select_fields(&self, keep: impl Fn(&Leaf) -> bool) -> Fields {
+        let mut leaves: Leaves = self.iter_leaves().collect();
         leaves.sort();
-    }
+        let kept: Vec<bool> = leaves.iter().map(keep).collect();
 
-    // Find number of unique leaves, and their indices
-    let mut curr_idx: usize = 0;
-    let mut curr = leaves[curr_idx].clone();
-    unique.push(curr);
+        self.fields
+            .iter()
+            .map(|(name, values)| {
+                let values = values
+                    .iter()
+                    .zip(kept.iter())
+                    .filter(|(_, &k)| k)
+                    .map(|(&v, _)| v)
+                    .collect();
+                (name.clone(), values)
+            })
+            .collect()
+    }
 
-    for &leaf in leaves.iter().skip(1) {
-        if curr != leaf {
-            unique.push(curr);
-            curr_idx += 1;
-            curr = leaf;
-        } else {
-            let n = unique[curr_idx].npoints + leaf.npoints;
-            if n <= *ncrit {
-                unique[curr_idx].npoints += leaf.npoints;
-            } else {
-                panic!("You are packing too many points into a leaf, increase tree depth!")
+    /// Compress the **Octree**, storing each **Block** once and its **Leaves** without their
+    /// block key. The point indices and **Points** are moved into the compressed form
+    /// (sequential).
+    pub fn compress(self) -> CompressedOctree {
+        let mut blocks: Keys = self.tree.keys().copied().collect();
+        blocks.sort();
+
+        let mut offsets: Vec<usize> = vec![0];
+        let mut keys: Keys = Vec::new();
+        let mut npoints: Vec<usize> = Vec::new();
+
+        for block in blocks.iter() {
+            for leaf in self.tree[block].iter() {
+                keys.push(leaf.key);
+                npoints.push(leaf.npoints);
             }
+            offsets.push(keys.len());
+        }
+
+        CompressedOctree {
+            blocks,
+            offsets,
+            keys,
+            npoints,
+            keeps_sorted_leaves: self.sorted_leaves.is_some(),
+            coarse_blocks: self.coarse_blocks,
+            indices: self.indices,
+            points: self.points,
+            #[cfg(feature = "ndarray")]
+            fields: self.fields,
+            depth: self.depth,
+            ncrit: self.ncrit,
+            x0: self.x0,
+            r0: self.r0,
         }
     }
-    unique
-}
 
-/// Find coarsest **Seeds** at each processor. These are used to seed the construction of a minimal
-/// block octree in Algorithm 4 of [1] (sequential).
-pub fn find_seeds(local_leaves: &[Leaf], depth: &u64) -> Keys {
-    // Find least and greatest leaves on processor
-    let min: Key = local_leaves.iter().min().unwrap().key;
-    let max: Key = local_leaves.iter().max().unwrap().key;
+    /// Apply a mutation to every local **Leaf**, given the key of the **Block** containing it
+    /// (sequential).
+    pub fn apply_mut(&mut self, mut f: impl FnMut(&Key, &mut Leaf)) {
+        for (block, leaves) in self.tree.iter_mut() {
+            for leaf in leaves.iter_mut() {
+                f(block, leaf);
+            }
+        }
+        self.leaves_changed();
+    }
 
-    // Complete region between least and greatest leaves
-    let mut complete = complete_region(&min, &max, depth);
-    complete.push(min);
-    complete.push(max);
+    /// Find the local **Leaf** containing a physical coordinate, encoded in the domain and depth of
+    /// the **Octree**. Coordinates outside of the domain, or owned by another process, give `None`
+    /// (sequential).
+    pub fn leaf_at(&self, x: f64, y: f64, z: f64) -> Option<&Leaf> {
+        check_domain(&[Point::new(x, y, z)], &self.x0, &self.r0).ok()?;
 
-    // Find blocks
-    let levels: Vec<u64> = complete.iter().map(|k| k.3).collect();
+        let depth = &self.depth;
+        let key = key_from_coords(x, y, z, depth, depth, &self.x0, &self.r0);
 
-    let mut coarsest_level = *depth;
+        // The block containing the key is one of its ancestors, or the key itself.
+        let block = (0..=*depth)
+            .map(|level| find_ancestor_at_level(&key, &level, depth))
+            .find(|ancestor| self.tree.contains_key(ancestor))?;
 
-    for l in levels {
-        if l < coarsest_level {
-            coarsest_level = l;
-        }
+        self.tree[&block]
+            .iter()
+            .find(|leaf| find_ancestor_at_level(&key, &leaf.key.3, depth) == leaf.key)
     }
 
-    let mut seed_idxs: Vec<usize> = Vec::new();
+    /// Pair each **Point** with the local **Leaf** and **Block** containing it, and the rank that
+    /// owns them, for debugging where points end up. Points outside of the local **Leaves** are
+    /// skipped (sequential).
+    pub fn export_points_with_owner(
+        &self,
+        points: &[Point],
+        rank: Rank,
+    ) -> Vec<(Point, Key, Key, Rank)> {
+        points
+            .iter()
+            .filter_map(|p| {
+                self.leaf_at(p.x, p.y, p.z)
+                    .map(|leaf| (*p, leaf.key, leaf.block, rank))
+            })
+            .collect()
+    }
 
-    for (i, node) in complete.iter().enumerate() {
-        if node.3 == coarsest_level {
-            seed_idxs.push(i);
-        }
+    /// The local **Points** contained in a **Leaf**, a contiguous range of the points in Morton
+    /// order (sequential).
+    fn leaf_points(&self, leaf: &Key) -> &[Point] {
+        let dld = find_deepest_last_descendent(leaf, &self.depth);
+        let start = self.points.partition_point(|p| p.key < *leaf);
+        let end = self.points.partition_point(|p| p.key <= dld);
+        &self.points[start..end]
     }
 
-    let seeds: Keys = seed_idxs.iter().map(|&i| complete[i as usize]).collect();
+    /// Find the `k` local **Points** closest to a query coordinate, as their global indices and
+    /// distances in order of increasing distance. Searches outwards from the **Leaf** containing
+    /// the query through the siblings of its ancestors, nearest octant first, until the `k`
+    /// closest points found are nearer than anything outside of the octants searched. Queries with
+    /// a coordinate which isn't finite have no closest points (sequential).
+    pub fn closest_points(&self, query: &Point, k: usize) -> Vec<(usize, f64)> {
+        let q = [query.x, query.y, query.z];
+        if (k == 0) | !q.iter().all(|c| c.is_finite()) {
+            return Vec::new();
+        }
 
-    seeds
-}
+        let depth = &self.depth;
+        let side_length = (self.r0 * 2.) / (side_shift(depth, &0) as f64);
+        let origin = [
+            self.x0.x - self.r0,
+            self.x0.y - self.r0,
+            self.x0.z - self.r0,
+        ];
 
-/// Transfer leaves based on **Seeds**. After distributed coarse block octree is found, leaves
-/// smaller than the minimum **Seed** on  a given processor must be handed to its partner from
-/// algorithm 4 of [1] (parallel).
-pub fn transfer_leaves_to_coarse_blocktree(
-    points: &[Point],
-    local_leaves: &[Leaf],
-    received_points: &mut Points,
-    received_leaves: &mut Leaves,
-    seeds: &[Key],
-    rank: Rank,
-    world: SystemCommunicator,
-    size: Rank,
-) {
-    let mut min_seed = Key::default();
-    if rank == 0 {
-        min_seed = local_leaves.iter().min().unwrap().key;
-    } else {
-        min_seed = *seeds.iter().min().unwrap();
-    }
+        // Lower and upper corners of an octant along each axis.
+        let bounds = |key: &Key| -> [(f64, f64); 3] {
+            let width = side_shift(depth, &key.3) as f64 * side_length;
+            let anchor = [key.0, key.1, key.2];
+            let lower = |i: usize| origin[i] + anchor[i] as f64 * side_length;
+            [0, 1, 2].map(|i| (lower(i), lower(i) + width))
+        };
+
+        // Distances are non-negative, so their bit patterns sort in the same order as them.
+        let distance_to_octant = |key: &Key| -> u64 {
+            bounds(key)
+                .iter()
+                .zip(q.iter())
+                .map(|(&(lower, upper), &x)| (lower - x).max(x - upper).max(0.).powi(2))
+                .sum::<f64>()
+                .sqrt()
+                .to_bits()
+        };
+
+        // Distance from a query inside of an octant to the nearest point outside of it.
+        let distance_to_outside = |key: &Key| -> u64 {
+            bounds(key)
+                .iter()
+                .zip(q.iter())
+                .map(|(&(lower, upper), &x)| (x - lower).min(upper - x))
+                .fold(f64::INFINITY, f64::min)
+                .max(0.)
+                .to_bits()
+        };
+
+        let distance_to_point = |p: &Point| -> u64 {
+            ((p.x - q[0]).powi(2) + (p.y - q[1]).powi(2) + (p.z - q[2]).powi(2))
+                .sqrt()
+                .to_bits()
+        };
+
+        // The points in an octant are a contiguous range of the points in Morton order.
+        let range = |key: &Key| {
+            let dld = find_deepest_last_descendent(key, depth);
+            let start = self.points.partition_point(|p| p.key < *key);
+            let end = self.points.partition_point(|p| p.key <= dld);
+            start..end
+        };
+
+        // The k closest candidates so far, the furthest of which bounds the search.
+        let mut candidates: BinaryHeap<(u64, usize)> = BinaryHeap::new();
+        let bound = |candidates: &BinaryHeap<(u64, usize)>| {
+            if candidates.len() < k {
+                f64::INFINITY.to_bits()
+            } else {
+                candidates.peek().unwrap().0
+            }
+        };
 
-    let prev_rank = if rank > 0 { rank - 1 } else {size-1};
-    let next_rank = if rank +1 < size { rank + 1 } else { 0 };
+        // Queries outside of the local leaves start from the root.
+        let mut searched = self
+            .leaf_at(query.x, query.y, query.z)
+            .map_or(Key(0, 0, 0, 0), |leaf| leaf.key);
 
-    if rank > 0 {
-        let msg: Leaves = local_leaves
-            .iter()
-            .filter(|&l| l.key < min_seed)
-            .cloned()
-            .collect();
+        let mut octants: BinaryHeap<Reverse<(u64, Key)>> = BinaryHeap::new();
+        octants.push(Reverse((distance_to_octant(&searched), searched)));
 
-        let msg_size: u32 = msg.len() as u32;
-        world.process_at_rank(prev_rank).send(&msg_size);
-        world.process_at_rank(prev_rank).send(&msg[..]);
-    }
+        loop {
+            // Visit the nearest octants first, splitting any holding more than ncrit points.
+            while let Some(&Reverse((distance, key))) = octants.peek() {
+                if distance > bound(&candidates) {
+                    break;
+                }
+                octants.pop();
+
+                let points = &self.points[range(&key)];
+                if (points.len() <= self.ncrit) | (key.3 == *depth) {
+                    for p in points.iter() {
+                        candidates.push((distance_to_point(p), p.global_idx));
+                        if candidates.len() > k {
+                            candidates.pop();
+                        }
+                    }
+                } else {
+                    for child in find_children(&key, depth).iter() {
+                        if !range(child).is_empty() {
+                            octants.push(Reverse((distance_to_octant(child), *child)));
+                        }
+                    }
+                }
+            }
 
-    if rank < (size - 1) {
-        let mut bufsize = 0;
-        world.process_at_rank(next_rank).receive_into(&mut bufsize);
-        let mut buffer = vec![Leaf::default(); bufsize as usize];
-        world.process_at_rank(next_rank).receive_into(&mut buffer[..]);
-        received_leaves.append(&mut buffer);
-    }
+            // Any closer points must lie outside of the octant searched so far.
+            if (searched.3 == 0) || (bound(&candidates) <= distance_to_outside(&searched)) {
+                break;
+            }
 
-    if rank > 0 {
-        let msg: Points = points
-            .iter()
-            .filter(|&l| l.key < min_seed)
-            .cloned()
-            .collect();
+            let parent = find_parent(&searched, depth);
+            for sibling in find_children(&parent, depth).iter() {
+                if (*sibling != searched) & !range(sibling).is_empty() {
+                    octants.push(Reverse((distance_to_octant(sibling), *sibling)));
+                }
+            }
+            searched = parent;
+        }
 
-        let msg_size: u32 = msg.len() as u32;
-        world.process_at_rank(prev_rank).send(&msg_size);
-        world.process_at_rank(prev_rank).send(&msg[..]);
+        candidates
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(d, idx)| (idx, f64::from_bits(d)))
+            .collect()
     }
 
-    if rank < (size - 1) {
-        let mut bufsize = 0;
-        world.process_at_rank(next_rank).receive_into(&mut bufsize);
-        let mut buffer = vec![Point::default(); bufsize as usize];
-        world.process_at_rank(next_rank).receive_into(&mut buffer[..]);
-        received_points.append(&mut buffer);
+    /// Number of points contained in the local **Leaves** (sequential).
+    pub fn n_points(&self) -> usize {
+        self.tree.values().flatten().map(|l| l.npoints).sum()
     }
 
-    let mut local_leaves: Leaves = local_leaves
-        .iter()
-        .filter(|&l| l.key >= min_seed)
-        .cloned()
-        .collect();
-
-    let mut points: Points = points
-        .iter()
-        .filter(|&l| l.key >= min_seed)
-        .cloned()
-        .collect();
+    /// Axis aligned bounding box of the local **Points** retained in the **Leaves**, as its lower
+    /// and upper corners, rather than the domain cube. `None` if no points are held (sequential).
+    pub fn points_bounding_box(&self) -> Option<(Point, Point)> {
+        let first = self.points.first()?;
+        let mut lower = Point::new(first.x, first.y, first.z);
+        let mut upper = lower;
 
-    received_leaves.append(&mut local_leaves);
-    received_points.append(&mut points);
+        for p in self.points.iter() {
+            lower = Point::new(lower.x.min(p.x), lower.y.min(p.y), lower.z.min(p.z));
+            upper = Point::new(upper.x.max(p.x), upper.y.max(p.y), upper.z.max(p.z));
+        }
 
-    // Re Sort received leaves
-    received_leaves.sort();
-}
+        Some((lower, upper))
+    }
 
-/// Remove overlaps from a list of octants, algorithm 7 in [1], expects input keys to be sorted
-/// (sequential).
-pub fn linearise(keys: &mut Keys, depth: &u64) -> Keys {
-    let mut linearised: Keys = Vec::new();
-    for i in 0..(keys.len() - 1) {
-        let curr = keys[i];
-        let next = keys[i + 1];
-        let ancestors_next: HashSet<Key> = find_ancestors(&next, depth).into_iter().collect();
-        if !ancestors_next.contains(&curr) {
-            linearised.push(curr)
+    /// Attach a named field to the local **Leaves**, one value per leaf in Morton order, replacing
+    /// any field of the same name. Fields are dropped when the **Leaves** are refined, merged or
+    /// mutated (sequential).
+    #[cfg(feature = "ndarray")]
+    pub fn attach_field(&mut self, name: &str, values: Array1<f64>) -> Result<(), TreeError> {
+        let nleaves = self.tree.values().map(|leaves| leaves.len()).sum();
+
+        if values.len() != nleaves {
+            return Err(TreeError::FieldSize {
+                name: name.to_string(),
+                expected: nleaves,
+                found: values.len(),
+            });
         }
+
+        self.fields.insert(name.to_string(), values.to_vec());
+        Ok(())
     }
-    linearised
-}
 
-/// Complete a distributed blocktree from the seed octants, algorithm 4 in [1] (parallel).
-pub fn complete_blocktree(
-    seeds: &mut Keys,
-    depth: &u64,
-    rank: Rank,
-    size: Rank,
-    world: SystemCommunicator,
-) -> Keys {
-    if rank == 0 {
-        let root = Key(0, 0, 0, 0);
-        let dfd_root = find_deepest_first_descendent(&root, depth);
-        let min = seeds.iter().min().unwrap();
-        let na = find_finest_common_ancestor(&dfd_root, min, depth);
-        let mut first_child = na;
-        first_child.3 += 1;
-        seeds.push(first_child);
-        seeds.sort();
+    /// View a named field attached to the local **Leaves**, in Morton order (sequential).
+    #[cfg(feature = "ndarray")]
+    pub fn field(&self, name: &str) -> Option<ArrayView1<f64>> {
+        self.fields
+            .get(name)
+            .map(|values| ArrayView1::from(&values[..]))
     }
 
-    if rank == (size - 1) {
-        let root = Key(0, 0, 0, 0);
-        let dld_root = find_deepest_last_descendent(&root, depth);
-        let max = seeds.iter().max().unwrap();
-        let na = find_finest_common_ancestor(&dld_root, max, depth);
-        let children = find_children(&na, depth);
-        let last_child = *children.iter().max().unwrap();
-        seeds.push(last_child);
+    /// Global indices of the original points contained in a local **Leaf** (sequential).
+    pub fn point_indices(&self, leaf: &Key) -> Option<&[usize]> {
+        self.indices.get(leaf).map(|indices| &indices[..])
     }
 
-    let next_rank = if rank + 1 < size { rank + 1 } else { 0 };
-    let previous_rank = if rank > 0 { rank - 1 } else { size - 1 };
+    /// Split the **Blocks** containing each query point until they are at least `min_level` deep,
+    /// and no deeper than `depth`, while still satisfying `ncrit`. Fails if a query point lies
+    /// outside of the domain (sequential).
+    pub fn refine_around_points(
+        &mut self,
+        queries: &[Point],
+        min_level: u64,
+        depth: &u64,
+        ncrit: &usize,
+    ) -> Result<(), TreeError> {
+        self.debug_assert_depth(depth);
+        check_domain(queries, &self.x0, &self.r0)?;
+        let min_level = min_level.min(*depth);
+
+        // Blocks coarser than the minimum level which contain a query point.
+        let mut targets: HashSet<Key> = HashSet::new();
+        for query in queries.iter() {
+            let key = key_from_coords(query.x, query.y, query.z, depth, depth, &self.x0, &self.r0);
+            for level in 0..min_level {
+                targets.insert(find_ancestor_at_level(&key, &level, depth));
+            }
+        }
 
-    // Send required data to partner process.
-    if rank > 0 {
-        let min = *seeds.iter().min().unwrap();
-        world.process_at_rank(previous_rank).send(&min);
+        // Any block containing a query is over capacity until it is deep enough.
+        let mut leaves: Leaves = self.iter_leaves().collect();
+        self.tree = split_blocks_adaptive(&mut leaves, depth, |block| {
+            if targets.contains(block) {
+                0
+            } else {
+                *ncrit
+            }
+        })?;
+        self.leaves_changed();
+        Ok(())
     }
 
-    if rank < (size - 1) {
-        let mut rec = Key::default();
-        world.process_at_rank(next_rank).receive_into(&mut rec);
-        seeds.push(rec);
+    /// Find the parent of a **Morton Key** at the depth of the **Octree**, so the key can't be
+    /// used with a different depth than it was built with.
+    pub fn parent(&self, key: &Key) -> Key {
+        find_parent(key, &self.depth)
     }
 
-    // Complete region between seeds at each process
-    let mut local_blocktree: Keys = Vec::new();
+    /// Find the children of a **Morton Key** at the depth of the **Octree**, see `Octree::parent`.
+    pub fn children(&self, key: &Key) -> Keys {
+        find_children(key, &self.depth)
+    }
 
-    for i in 0..(seeds.len() - 1) {
-        let a = seeds[i];
-        let b = seeds[i + 1];
+    /// Find the ancestor of a **Morton Key** at a coarser level at the depth of the **Octree**,
+    /// see `Octree::parent`.
+    pub fn ancestor_at_level(&self, key: &Key, level: &u64) -> Key {
+        find_ancestor_at_level(key, level, &self.depth)
+    }
 
-        let mut tmp = complete_region(&a, &b, depth);
-        local_blocktree.push(a);
-        local_blocktree.append(&mut tmp);
+    /// Find all ancestors of a **Morton Key** at the depth of the **Octree**, see
+    /// `Octree::parent`.
+    pub fn ancestors(&self, key: &Key) -> Keys {
+        find_ancestors(key, &self.depth)
     }
 
-    if rank == (size - 1) {
-        local_blocktree.push(*seeds.last().unwrap());
+    /// Check that a depth passed alongside the **Octree** is the depth it was built with, as keys
+    /// are only meaningful at the depth used to construct them.
+    fn debug_assert_depth(&self, depth: &u64) {
+        debug_assert_eq!(
+            *depth, self.depth,
+            "Depth {} doesn't match the depth {} of the Octree",
+            depth, self.depth
+        );
     }
 
-    local_blocktree.sort();
-    local_blocktree
-}
+    /// Find the local **Leaves** neighboring a **Leaf**. Each same-level neighbor is resolved to
+    /// the leaf containing it if that is coarser, or otherwise to the finer leaves inside it which
+    /// abut the key (sequential).
+    pub fn neighbors_of_leaf(&self, key: &Key, depth: &u64) -> Keys {
+        self.debug_assert_depth(depth);
+        let mut keys: Keys = self.iter_leaves().map(|l| l.key).collect();
+        keys.sort();
+        let leaves: HashSet<Key> = keys.iter().copied().collect();
 
-/// Associate a given set of **Blocks** with a given set of **Leaves** (sequential).
-pub fn assign_blocks_to_leaves(local_leaves: &mut Leaves, local_blocktree: &[Key], depth: &u64) {
-    let local_blocktree_set: HashSet<Key> = local_blocktree.iter().cloned().collect();
+        neighbors_among(key, &keys, &leaves, depth)
+    }
 
-    for leaf in local_leaves.iter_mut() {
-        let ancestors = find_ancestors(&leaf.key, depth);
-        for ancestor in ancestors {
-            if local_blocktree_set.contains(&ancestor) {
-                leaf.block = ancestor;
-                break;
-            } else if local_blocktree.contains(&leaf.key) {
-                leaf.block = leaf.key;
-                break;
+    /// Build the adjacency graph of the local **Leaves**, as the sorted leaf keys and an edge list
+    /// of indices into them. Leaves are connected if they share a face, edge or vertex, and each
+    /// edge is listed once with its lesser index first (sequential).
+    pub fn to_adjacency(&self, depth: &u64) -> (Keys, Vec<(usize, usize)>) {
+        self.debug_assert_depth(depth);
+        let mut keys: Keys = self.iter_leaves().map(|l| l.key).collect();
+        keys.sort();
+        let leaves: HashSet<Key> = keys.iter().copied().collect();
+
+        let mut edges: Vec<(usize, usize)> = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            for neighbor in neighbors_among(key, &keys, &leaves, depth).iter() {
+                let j = keys.binary_search(neighbor).unwrap();
+                if i < j {
+                    edges.push((i, j));
+                }
             }
         }
-    }
-}
-
-/// Find the **Weights** of a given set of **Blocks** (sequential).
-pub fn find_block_weights(leaves: &[Leaf], blocktree: &[Key]) -> Weights {
-    let mut weights: Weights = Vec::new();
 
-    for &block in blocktree.iter() {
-        let counts: u64 = leaves.iter().filter(|&l| l.block == block).count() as u64;
-        weights.push(Weight(counts));
+        (keys, edges)
     }
-    weights
-}
 
-/// Transfer **Leaves** to correspond to the final load balanced blocktree (parallel).
-pub fn transfer_leaves_to_final_blocktree(
-    sent_blocks: &[Key],
-    mut local_leaves: Leaves,
-    size: Rank,
-    rank: Rank,
-    world: SystemCommunicator,
-) -> Leaves {
-    let mut received: Leaves = Vec::new();
-    let mut msg: Leaves = Vec::new();
+    /// Extract the local **Leaves** contained in the octant `root` into a standalone **Octree**
+    /// over the same domain. **Leaves** keep their **Blocks**, except that **Blocks** containing
+    /// `root` are clipped to it (sequential).
+    pub fn subtree(&self, root: &Key, depth: &u64) -> Octree {
+        self.debug_assert_depth(depth);
+        let contains = |key: &Key| (key == root) || root.is_ancestor_of(key, depth);
+
+        // Blocks of leaves inside the root either lie inside of it or contain it.
+        let clip = |mut leaf: Leaf| {
+            if !contains(&leaf.block) {
+                leaf.block = *root;
+            }
+            leaf
+        };
 
-    let next_rank = if rank + 1 < size { rank + 1 } else { 0 };
-    let prev_rank = if rank > 0 { rank - 1 } else { size - 1 };
-    let previous_process = world.process_at_rank(prev_rank);
+        let mut tree: Tree = HashMap::new();
+        for leaf in self.iter_leaves().filter(|l| contains(&l.key)).map(clip) {
+            tree.entry(leaf.block).or_default().push(leaf);
+        }
 
-    for &block in sent_blocks.iter() {
-        let mut to_send: Leaves = local_leaves
+        let indices = self
+            .indices
             .iter()
-            .filter(|&l| l.block == block)
-            .cloned()
+            .filter(|(key, _)| contains(key))
+            .map(|(&key, indices)| (key, indices.clone()))
             .collect();
-        msg.append(&mut to_send);
-    }
-
-    // Remove these leaves from the local leaves
-    for &block in sent_blocks.iter() {
-        local_leaves.retain(|l| l.block != block)
-    }
 
-    for r in 0..size {
-        if r == rank {
-            previous_process.send(&msg[..]);
-        }
-        if r == next_rank {
-            let (mut rec, _) = world.any_process().receive_vec::<Leaf>();
+        let points = self
+            .points
+            .iter()
+            .filter(|p| contains(&p.key))
+            .copied()
+            .collect();
 
-            local_leaves.append(&mut rec)
+        let sorted_leaves = self.sorted_leaves.as_ref().map(|leaves| {
+            leaves
+                .iter()
+                .filter(|l| contains(&l.key))
+                .copied()
+                .map(clip)
+                .collect()
+        });
+
+        // Coarse blocks containing the root are clipped to it, others must lie inside of it.
+        let mut coarse_blocks: Keys = self
+            .coarse_blocks
+            .iter()
+            .filter_map(|block| {
+                if contains(block) {
+                    Some(*block)
+                } else if block.is_ancestor_of(root, depth) {
+                    Some(*root)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        coarse_blocks.dedup();
+
+        Octree {
+            tree,
+            sorted_leaves,
+            coarse_blocks,
+            indices,
+            points,
+            #[cfg(feature = "ndarray")]
+            fields: self.select_fields(|l| contains(&l.key)),
+            depth: self.depth,
+            ncrit: self.ncrit,
+            x0: self.x0,
+            r0: self.r0,
         }
     }
 
-    // Append received leaves
-    local_leaves.append(&mut received);
-    local_leaves
-}
+    /// Nodes of the implicit tree formed by the local **Leaves** and all of their ancestors, in
+    /// Morton order (sequential).
+    fn nodes(&self) -> Keys {
+        let mut nodes: HashSet<Key> = HashSet::new();
+        for leaf in self.iter_leaves() {
+            if nodes.insert(leaf.key) && leaf.key.3 > 0 {
+                nodes.extend(find_ancestors(&leaf.key, &self.depth));
+            }
+        }
 
-/// Re-partition the blocks so that amount of computation on each node is balanced. Return mapping
-/// between block and rank to which it was sent (parallel).
-pub fn block_partition(
-    weights: Weights,
-    local_blocktree: &mut Keys,
-    rank: Rank,
-    size: Rank,
-    world: SystemCommunicator,
-) -> Keys {
-    let local_weight = weights.iter().fold(0, |acc, x| acc + x.0);
-    let local_nblocks = local_blocktree.len();
-    let mut cumulative_weight = 0;
-    let mut cumulative_nblocks = 0;
-
-    #[allow(unused_variables)]
-    let mut total_weight = 0;
-    #[allow(unused_variables)]
-    let mut total_nblocks = 0;
-
-    world.scan_into(
-        &local_weight,
-        &mut cumulative_weight,
-        &SystemOperation::sum(),
-    );
-    world.scan_into(
-        &local_nblocks,
-        &mut cumulative_nblocks,
-        &SystemOperation::sum(),
-    );
-
-    // Broadcast total weight from last process
-    let last_rank = size - 1;
-    let last_process = world.process_at_rank(last_rank);
-
-    if rank == last_rank {
-        total_weight = cumulative_weight;
-        total_nblocks = cumulative_nblocks;
-    } else {
-        total_weight = 0;
-        total_nblocks = 0;
+        let mut nodes: Keys = nodes.into_iter().collect();
+        nodes.sort();
+        nodes
     }
 
-    last_process.broadcast_into(&mut total_weight);
-    last_process.broadcast_into(&mut total_nblocks);
-
-    // Maximum weight per process
-    let w: u64 = (total_weight as f64 / size as f64).ceil() as u64;
-    let k: u64 = total_weight % (size as u64);
-
-    let mut local_cumulative_weights = weights.clone();
-    let mut sum = 0;
-    for (i, w) in weights.iter().enumerate() {
-        sum += w.0;
-        local_cumulative_weights[i] = Weight(sum + cumulative_weight - local_weight as u64)
+    /// Iterate depth first over the implicit tree above the local **Leaves**, from the root down,
+    /// yielding each node with its level. Parents are always visited before their children
+    /// (sequential).
+    pub fn iter_depth_first(&self) -> impl Iterator<Item = (Key, u64)> {
+        // Morton order is a pre-order traversal, as ancestors sort before their descendants.
+        self.nodes().into_iter().map(|key| (key, key.3))
     }
 
-    let p: u64 = (rank + 1) as u64;
-    let next_rank = if rank + 1 < size { rank + 1 } else { 0 };
-    let previous_rank = if rank > 0 { rank - 1 } else { size - 1 };
-
-    let mut q: Keys = Vec::new();
+    /// Iterate breadth first over the implicit tree above the local **Leaves**, from the root
+    /// down, yielding each node with its level. Every node at a level is visited before any node at
+    /// the next level (sequential).
+    pub fn iter_breadth_first(&self) -> impl Iterator<Item = (Key, u64)> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|key| key.3);
+        nodes.into_iter().map(|key| (key, key.3))
+    }
 
-    if p <= k {
-        let cond1: u64 = (p - 1) * (w + 1);
-        let cond2: u64 = p * (w + 1);
+    /// Coarsen complete sets of sibling **Blocks** whose points together number at most `ncrit`
+    /// into their parent, until no more can be merged. The inverse of `split_blocks`, the
+    /// **Leaves** within merged blocks are assigned to the parent. Blocks are never merged past
+    /// the coarse blocktree, so every block remains a coarse block or a descendent of one
+    /// (sequential).
+    pub fn merge_small_leaves(&mut self, ncrit: &usize, depth: &u64) {
+        self.debug_assert_depth(depth);
+        let coarse: HashSet<Key> = self.coarse_blocks.iter().copied().collect();
+
+        loop {
+            let blocks: Keys = self.tree.keys().filter(|b| b.3 > 0).copied().collect();
+
+            let mut groups: HashMap<Key, Keys> = HashMap::new();
+            for (&block, parent) in blocks.iter().zip(find_parents(&blocks, depth)) {
+                groups.entry(parent).or_default().push(block);
+            }
 
-        for (i, &block) in local_blocktree.iter().enumerate() {
-            if (cond1 <= local_cumulative_weights[i].0) & (local_cumulative_weights[i].0 < cond2) {
-                q.push(block);
+            let mut merged = false;
+
+            for (parent, siblings) in groups.into_iter() {
+                let npoints: usize = siblings
+                    .iter()
+                    .flat_map(|s| self.tree[s].iter())
+                    .map(|l| l.npoints)
+                    .sum();
+
+                let at_coarse = siblings.iter().any(|s| coarse.contains(s));
+
+                if (siblings.len() == 8) & (npoints <= *ncrit) & !at_coarse {
+                    let mut leaves: Leaves = Vec::new();
+                    for sibling in siblings.iter() {
+                        leaves.extend(self.tree.remove(sibling).unwrap());
+                    }
+                    for leaf in leaves.iter_mut() {
+                        leaf.block = parent;
+                    }
+                    self.tree.insert(parent, leaves);
+                    merged = true;
+                }
             }
-        }
-    } else {
-        let cond1: u64 = (p - 1) * w + k;
-        let cond2: u64 = p * w + k;
 
-        for (i, &block) in local_blocktree.iter().enumerate() {
-            if (cond1 <= local_cumulative_weights[i].0) & (local_cumulative_weights[i].0 < cond2) {
-                q.push(block);
+            if !merged {
+                break;
             }
         }
+        self.leaves_changed();
     }
 
-    // Send receive qs with partner process
-    let previous_process = world.process_at_rank(previous_rank);
-
-    let mut received_blocks: Keys = Vec::new();
+    /// Refine the local **Leaves** until none holds more than `ncrit` points, and neighboring
+    /// leaves differ by at most one level. Splitting and 2:1 balancing can each violate the other,
+    /// so they alternate to a fixed point, returning the number of rounds taken. Leaves are split
+    /// as **Blocks** of the retained **Points** inside them with `split_blocks`, so fails as it
+    /// does, or if a leaf doesn't retain all of its points. Balance is enforced between the leaves
+    /// at this process. Every process iterates until all have converged, and the **Octree** is
+    /// unchanged if refining fails (parallel).
+    pub fn refine_until_balanced<C: Communicator>(
+        &mut self,
+        ncrit: &usize,
+        depth: &u64,
+        world: &C,
+    ) -> Result<usize, TreeError> {
+        self.debug_assert_depth(depth);
+
+        let missing = self.iter_leaves().find_map(|l| {
+            let found = self.leaf_points(&l.key).len();
+            (found != l.npoints).then(|| TreeError::MissingPoints {
+                leaf: l.key,
+                expected: l.npoints,
+                found,
+            })
+        });
+        agree(missing.map_or(Ok(()), Err), world)?;
+
+        // Retained points as leaves at the finest level, in Morton order.
+        let fine = keys_to_leaves(&mut self.points.clone());
+        let fine_inside = |key: &Key| {
+            let dld = find_deepest_last_descendent(key, depth);
+            let start = fine.partition_point(|l| l.key < *key);
+            let end = fine.partition_point(|l| l.key <= dld);
+            &fine[start..end]
+        };
+
+        // Refine a copy of the leaves, so that the tree is unchanged if refining fails.
+        let mut leaves: Leaves = self.iter_leaves().collect();
+        leaves.sort();
 
-    for r in 0..size {
-        if r == rank {
-            previous_process.send(&q[..]);
+        for round in 0..MAX_REFINEMENTS {
+            // Split leaves over capacity, as blocks of the finest leaves inside them. Leaves
+            // without points are kept as they are.
+            let mut inside: Leaves = Vec::new();
+            let mut refined: Leaves = Vec::new();
+            for leaf in leaves.iter() {
+                let fine = fine_inside(&leaf.key);
+                if fine.is_empty() {
+                    refined.push(*leaf);
+                }
+                inside.extend(fine.iter().map(|&f| Leaf {
+                    block: leaf.key,
+                    ..f
+                }));
+            }
+            let split = agree(split_blocks(&mut inside, depth, ncrit), world)?;
+
+            let mut nsplit = 0;
+            for (&key, fine) in split.iter() {
+                let parent = leaves[leaves.partition_point(|l| l.key <= key) - 1];
+                nsplit += (key != parent.key) as u64;
+                refined.push(Leaf {
+                    key,
+                    block: parent.block,
+                    npoints: fine.iter().map(|l| l.npoints).sum(),
+                });
+            }
+            refined.sort();
+
+            // Leaves more than one level coarser than a neighbor are split into their children.
+            let keys: HashSet<Key> = refined.iter().map(|l| l.key).collect();
+            let mut to_split: HashSet<Key> = HashSet::new();
+            for key in keys.iter() {
+                for candidate in find_neighbors(key, depth).iter() {
+                    if let Some(coarser) = find_ancestors(candidate, depth)
+                        .into_iter()
+                        .find(|a| keys.contains(a))
+                    {
+                        if coarser.3 + 1 < key.3 {
+                            to_split.insert(coarser);
+                        }
+                    }
+                }
+            }
+
+            let local = nsplit + to_split.len() as u64;
+            let mut global: u64 = 0;
+            world.all_reduce_into(&local, &mut global, SystemOperation::sum());
+
+            if global == 0 {
+                if round > 0 {
+                    self.tree = HashMap::new();
+                    for &leaf in leaves.iter() {
+                        self.tree.entry(leaf.block).or_default().push(leaf);
+                    }
+                    self.indices = index_points(&leaves, &self.points);
+                    self.leaves_changed();
+                }
+                return Ok(round);
+            }
+
+            leaves = Vec::new();
+            for leaf in refined.into_iter() {
+                if to_split.contains(&leaf.key) {
+                    leaves.extend(children_array(&leaf.key, depth).iter().map(|&key| Leaf {
+                        key,
+                        block: leaf.block,
+                        npoints: fine_inside(&key).iter().map(|l| l.npoints).sum(),
+                    }));
+                } else {
+                    leaves.push(leaf);
+                }
+            }
+            leaves.sort();
+        }
+
+        Err(TreeError::RefinementLimit {
+            rounds: MAX_REFINEMENTS,
+        })
+    }
+
+    /// The level of a uniform **Octree**, one in which every **Leaf** is its own **Block**, and all
+    /// are at the same level. Returns `None` for non uniform, or empty, trees (sequential).
+    pub fn is_uniform(&self) -> Option<u64> {
+        let level = self.tree.keys().next()?.3;
+
+        let uniform = self.tree.iter().all(|(block, leaves)| {
+            (block.3 == level) & (leaves.len() == 1) & (leaves[0].key == *block)
+        });
+
+        if uniform {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    /// Store a uniform **Octree** as its level and the occupied octants at that level, moving the
+    /// point indices and **Points** into the compressed form. Returns the **Octree** unchanged if
+    /// it isn't uniform (sequential).
+    pub fn compress_levels(self) -> Result<UniformOctree, Octree> {
+        let level = match self.is_uniform() {
+            Some(level) => level,
+            None => return Err(self),
+        };
+
+        let mut leaves: Leaves = self.iter_leaves().collect();
+        leaves.sort();
+
+        Ok(UniformOctree {
+            level,
+            leaves,
+            keeps_sorted_leaves: self.sorted_leaves.is_some(),
+            coarse_blocks: self.coarse_blocks,
+            indices: self.indices,
+            points: self.points,
+            #[cfg(feature = "ndarray")]
+            fields: self.fields,
+            depth: self.depth,
+            ncrit: self.ncrit,
+            x0: self.x0,
+            r0: self.r0,
+        })
+    }
+
+    /// Number of **Leaves** contained in each local **Block** (sequential).
+    pub fn leaf_count_per_block(&self) -> HashMap<Key, usize> {
+        self.tree
+            .iter()
+            .map(|(&block, leaves)| (block, leaves.len()))
+            .collect()
+    }
+
+    /// The least and greatest local **Leaf** keys, the interval of the Morton curve owned by this
+    /// process, or `None` if it holds no **Leaves** (sequential).
+    pub fn morton_range(&self) -> Option<(Key, Key)> {
+        let leaves = self.tree.values().flatten();
+        let min = leaves.clone().min()?.key;
+        let max = leaves.max()?.key;
+        Some((min, max))
+    }
+
+    /// Gather the Morton interval owned by each process to the root process, in rank order, for
+    /// visualising the partition. Processes without **Leaves** own no interval (parallel).
+    pub fn gather_morton_ranges<C: Communicator>(
+        &self,
+        world: &C,
+        root_rank: Rank,
+    ) -> Option<Vec<Option<(Key, Key)>>> {
+        let range = self.morton_range();
+        let nonempty = range.is_some() as i32;
+        let (min, max) = range.unwrap_or_default();
+        let local = [min, max];
+        let root_process = world.process_at_rank(root_rank);
+
+        if world.rank() == root_rank {
+            let size = world.size() as usize;
+            let mut nonempties = vec![0i32; size];
+            let mut received = vec![Key::default(); 2 * size];
+            root_process.gather_into_root(&nonempty, &mut nonempties[..]);
+            root_process.gather_into_root(&local[..], &mut received[..]);
+
+            let ranges = received
+                .chunks(2)
+                .zip(nonempties.iter())
+                .map(|(r, &nonempty)| (nonempty == 1).then(|| (r[0], r[1])))
+                .collect();
+            Some(ranges)
+        } else {
+            root_process.gather_into(&nonempty);
+            root_process.gather_into(&local[..]);
+            None
+        }
+    }
+
+    /// Redistribute the **Leaves** of an **Octree** built on `world` onto the processes of a
+    /// sub-communicator, for steps which run on fewer processes. **Blocks** are kept whole, and
+    /// dealt out in Morton order so that each process of `new_world` receives a similar number of
+    /// **Leaves**. Processes outside of `new_world` pass `None`, and are left with an empty
+    /// **Octree**. Fields travel with their **Leaves**, so must be attached under the same names
+    /// on every process (parallel).
+    pub fn restrict_to<C: Communicator>(
+        &self,
+        world: &C,
+        new_world: Option<&UserCommunicator>,
+    ) -> Octree {
+        let size = world.size();
+
+        // Rank of each process in the sub-communicator, or -1 if it's outside of it.
+        let new_rank: Rank = new_world.map_or(-1, |c| c.rank());
+        let mut new_ranks: Vec<Rank> = vec![0; size as usize];
+        world.all_gather_into(&new_rank, &mut new_ranks[..]);
+
+        // World ranks of the sub-communicator's processes, in their sub-communicator rank order.
+        let nactive = new_ranks.iter().filter(|&&r| r >= 0).count();
+        let mut active: Vec<Rank> = vec![0; nactive];
+        for (world_rank, &r) in new_ranks.iter().enumerate() {
+            if r >= 0 {
+                active[r as usize] = world_rank as Rank;
+            }
+        }
+
+        // Blocks are weighted by their number of leaves, and partitioned between the processes of
+        // the sub-communicator, a single process keeps its blocks.
+        let mut blocks: Keys = self.tree.keys().copied().collect();
+        blocks.sort();
+        let weights: Weights = blocks
+            .iter()
+            .map(|b| Weight(self.tree[b].len() as u64))
+            .collect();
+
+        let shares = block_partition(weights, &mut blocks.clone(), nactive as Rank, world);
+        let destinations: HashMap<Key, usize> = blocks
+            .iter()
+            .map(|block| {
+                let share = shares.get(block).copied().unwrap_or(0);
+                (*block, active[share as usize] as usize)
+            })
+            .collect();
+
+        let mut buckets_leaves: Vec<Leaves> = vec![Vec::new(); size as usize];
+        for &block in blocks.iter() {
+            buckets_leaves[destinations[&block]].extend(self.tree[&block].iter());
+        }
+
+        // Points, and their indices, follow the block containing their key.
+        let destination = |key: &Key| {
+            std::iter::once(*key)
+                .chain(find_ancestors(key, &self.depth))
+                .find_map(|a| destinations.get(&a).copied())
+        };
+
+        let mut buckets_keys: Vec<Keys> = vec![Vec::new(); size as usize];
+        let mut buckets_indices: Vec<Vec<usize>> = vec![Vec::new(); size as usize];
+        for (key, indices) in self.indices.iter() {
+            if let Some(destination) = destination(key) {
+                buckets_keys[destination].extend(vec![*key; indices.len()]);
+                buckets_indices[destination].extend(indices.iter());
+            }
+        }
+
+        let mut buckets_points: Vec<Points> = vec![Vec::new(); size as usize];
+        for point in self.points.iter() {
+            if let Some(destination) = destination(&point.key) {
+                buckets_points[destination].push(*point);
+            }
+        }
+
+        // Coarse blocks follow the first block they contain.
+        let mut buckets_coarse: Vec<Keys> = vec![Vec::new(); size as usize];
+        for coarse in self.coarse_blocks.iter() {
+            let first = blocks.partition_point(|b| b < coarse);
+            if let Some(destination) = blocks.get(first).and_then(|b| destination(b)) {
+                buckets_coarse[destination].push(*coarse);
+            }
+        }
+
+        let received_leaves = all_to_all(world, size, buckets_leaves);
+        let received_keys = all_to_all(world, size, buckets_keys);
+        let received_indices = all_to_all(world, size, buckets_indices);
+
+        // Points are received in rank order, from processes holding consecutive blocks.
+        let points = all_to_all(world, size, buckets_points);
+
+        // Fields follow their leaves, and are put back into Morton order once received.
+        #[cfg(feature = "ndarray")]
+        let fields = {
+            let mut sorted: Leaves = self.iter_leaves().collect();
+            sorted.sort();
+            let position: HashMap<Key, usize> =
+                sorted.iter().enumerate().map(|(i, l)| (l.key, i)).collect();
+
+            let mut order: Vec<usize> = (0..received_leaves.len()).collect();
+            order.sort_by_key(|&i| received_leaves[i].key);
+
+            let mut names: Vec<&String> = self.fields.keys().collect();
+            names.sort();
+
+            let mut fields: Fields = HashMap::new();
+            for name in names {
+                let values = &self.fields[name];
+                let mut buckets: Vec<Vec<f64>> = vec![Vec::new(); size as usize];
+                for &block in blocks.iter() {
+                    buckets[destinations[&block]]
+                        .extend(self.tree[&block].iter().map(|l| values[position[&l.key]]));
+                }
+
+                let received = all_to_all(world, size, buckets);
+                fields.insert(name.clone(), order.iter().map(|&i| received[i]).collect());
+            }
+            fields
+        };
+
+        let mut tree: Tree = HashMap::new();
+        for leaf in received_leaves.into_iter() {
+            tree.entry(leaf.block).or_default().push(leaf);
+        }
+
+        let mut indices: HashMap<Key, Vec<usize>> = HashMap::new();
+        for (key, idx) in received_keys.into_iter().zip(received_indices.into_iter()) {
+            indices.entry(key).or_default().push(idx);
+        }
+
+        let mut coarse_blocks = all_to_all(world, size, buckets_coarse);
+        coarse_blocks.sort();
+
+        let mut octree = Octree {
+            tree,
+            sorted_leaves: None,
+            coarse_blocks,
+            indices,
+            points,
+            #[cfg(feature = "ndarray")]
+            fields,
+            depth: self.depth,
+            ncrit: self.ncrit,
+            x0: self.x0,
+            r0: self.r0,
+        };
+
+        if self.sorted_leaves.is_some() {
+            octree.keep_sorted_leaves();
+        }
+        octree
+    }
+
+    /// Number of points contained in the **Leaves** across all processes (parallel).
+    pub fn n_points_global<C: Communicator>(&self, world: &C) -> usize {
+        let local = self.n_points();
+        let mut global: usize = 0;
+        world.all_reduce_into(&local, &mut global, SystemOperation::sum());
+        global
+    }
+
+    /// Assign each local **Leaf** an index in the global Morton order of the **Leaves** across all
+    /// processes, from an exclusive scan over the number held by each process. Processes hold
+    /// consecutive intervals of the Morton curve in rank order, so indices are contiguous, unique
+    /// and reproducible for a given tree (parallel).
+    pub fn global_leaf_index<C: Communicator>(&self, world: &C) -> HashMap<Key, usize> {
+        let mut keys: Keys = self.iter_leaves().map(|l| l.key).collect();
+        keys.sort();
+
+        let offset = scan_exclusive(keys.len() as u64, world) as usize;
+
+        keys.into_iter()
+            .enumerate()
+            .map(|(i, key)| (key, offset + i))
+            .collect()
+    }
+
+    /// Axis aligned bounding box of the **Points** retained across all processes, as its lower and
+    /// upper corners. `None` if no process holds points (parallel).
+    pub fn points_bounding_box_global<C: Communicator>(&self, world: &C) -> Option<(Point, Point)> {
+        let local = self.points.len() as u64;
+        let mut global = 0u64;
+        world.all_reduce_into(&local, &mut global, SystemOperation::sum());
+
+        let bounds = all_reduce_bounding_box(&self.points, world);
+        if global == 0 {
+            None
+        } else {
+            Some(bounds)
+        }
+    }
+
+    /// Summarise the **Leaves** across all processes, see **OctreeStats** (parallel).
+    pub fn stats<C: Communicator>(&self, world: &C) -> OctreeStats {
+        let size = world.size();
+
+        let nleaves = self.iter_leaves().count() as u64;
+        let levels = || self.iter_leaves().map(|l| l.key.3);
+        let npoints = || self.iter_leaves().map(|l| l.npoints as u64);
+
+        // Empty processes contribute the identity of each reduction.
+        let local_sums = [nleaves, npoints().sum(), levels().sum()];
+        let local_mins = [
+            levels().min().unwrap_or(u64::MAX),
+            npoints().min().unwrap_or(u64::MAX),
+        ];
+        let local_maxs = [
+            levels().max().unwrap_or(0),
+            npoints().max().unwrap_or(0),
+            nleaves,
+        ];
+
+        let mut sums = [0u64; 3];
+        let mut mins = [0u64; 2];
+        let mut maxs = [0u64; 3];
+        world.all_reduce_into(&local_sums[..], &mut sums[..], SystemOperation::sum());
+        world.all_reduce_into(&local_mins[..], &mut mins[..], SystemOperation::min());
+        world.all_reduce_into(&local_maxs[..], &mut maxs[..], SystemOperation::max());
+
+        let [nleaves, npoints, levels] = sums;
+        let mean = |total: u64| total as f64 / nleaves.max(1) as f64;
+        let mean_leaves = (nleaves as f64 / size as f64).max(f64::MIN_POSITIVE);
+
+        OctreeStats {
+            nleaves,
+            npoints,
+            min_level: mins[0].min(maxs[0]),
+            mean_level: mean(levels),
+            max_level: maxs[0],
+            min_points: mins[1].min(maxs[1]),
+            mean_points: mean(npoints),
+            max_points: maxs[1],
+            imbalance: (maxs[2] as f64 / mean_leaves).max(1.),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// **OctreeStats**, a summary of the **Leaves** across all processes. The global numbers of leaves
+/// and points, the range and mean of leaf levels and of points per leaf, and the imbalance in the
+/// number of leaves, the ratio of the most held by one process to the mean.
+pub struct OctreeStats {
+    pub nleaves: u64,
+    pub npoints: u64,
+    pub min_level: u64,
+    pub mean_level: f64,
+    pub max_level: u64,
+    pub min_points: u64,
+    pub mean_points: f64,
+    pub max_points: u64,
+    pub imbalance: f64,
+}
+
+#[derive(Debug, Clone)]
+/// **Uniform Octree**, a uniform **Octree** stored as the level of its **Leaves**, and the
+/// occupied octants at that level in Morton order, each its own **Block**. Records whether the
+/// **Octree** kept sorted **Leaves**, so that they are kept again once decompressed.
+pub struct UniformOctree {
+    pub level: u64,
+    pub leaves: Leaves,
+    pub keeps_sorted_leaves: bool,
+    pub coarse_blocks: Keys,
+    pub indices: HashMap<Key, Vec<usize>>,
+    pub points: Points,
+    #[cfg(feature = "ndarray")]
+    pub fields: Fields,
+    pub depth: u64,
+    pub ncrit: usize,
+    pub x0: Point,
+    pub r0: f64,
+}
+
+impl UniformOctree {
+    /// Iterate over the local **Leaves**, in Morton order (sequential).
+    pub fn iter_leaves(&self) -> impl Iterator<Item = Leaf> + '_ {
+        self.leaves.iter().copied()
+    }
+
+    /// Number of local **Leaves** (sequential).
+    pub fn n_leaves(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Number of points contained in the local **Leaves** (sequential).
+    pub fn n_points(&self) -> usize {
+        self.leaves.iter().map(|l| l.npoints).sum()
+    }
+
+    /// Find the local **Leaf** containing a physical coordinate, see `Octree::leaf_at`
+    /// (sequential).
+    pub fn leaf_at(&self, x: f64, y: f64, z: f64) -> Option<&Leaf> {
+        check_domain(&[Point::new(x, y, z)], &self.x0, &self.r0).ok()?;
+
+        let key = key_from_coords(x, y, z, &self.level, &self.depth, &self.x0, &self.r0);
+        let i = self.leaves.binary_search_by_key(&key, |l| l.key).ok()?;
+        Some(&self.leaves[i])
+    }
+
+    /// Global indices of the original points contained in a local **Leaf**, see
+    /// `Octree::point_indices` (sequential).
+    pub fn point_indices(&self, leaf: &Key) -> Option<&[usize]> {
+        self.indices.get(leaf).map(|indices| &indices[..])
+    }
+
+    /// Expand back into an **Octree** (sequential).
+    pub fn decompress(self) -> Octree {
+        Octree {
+            tree: self.iter_leaves().map(|l| (l.key, vec![l])).collect(),
+            sorted_leaves: self.keeps_sorted_leaves.then(|| self.leaves.clone()),
+            coarse_blocks: self.coarse_blocks,
+            indices: self.indices,
+            points: self.points,
+            #[cfg(feature = "ndarray")]
+            fields: self.fields,
+            depth: self.depth,
+            ncrit: self.ncrit,
+            x0: self.x0,
+            r0: self.r0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// **Compressed Octree**, stores each **Block** once. The **Leaves** of the i'th block are stored
+/// without their block key, at positions `offsets[i]..offsets[i+1]` of the leaf data. Records
+/// whether the **Octree** kept sorted **Leaves**, so that they are kept again once decompressed.
+pub struct CompressedOctree {
+    pub blocks: Keys,
+    pub offsets: Vec<usize>,
+    pub keys: Keys,
+    pub npoints: Vec<usize>,
+    pub keeps_sorted_leaves: bool,
+    pub coarse_blocks: Keys,
+    pub indices: HashMap<Key, Vec<usize>>,
+    pub points: Points,
+    #[cfg(feature = "ndarray")]
+    pub fields: Fields,
+    pub depth: u64,
+    pub ncrit: usize,
+    pub x0: Point,
+    pub r0: f64,
+}
+
+impl CompressedOctree {
+    /// Iterate over the local **Leaves**, reconstructing their block keys (sequential).
+    pub fn iter_leaves(&self) -> impl Iterator<Item = Leaf> + '_ {
+        self.blocks.iter().enumerate().flat_map(move |(i, &block)| {
+            (self.offsets[i]..self.offsets[i + 1]).map(move |j| Leaf {
+                key: self.keys[j],
+                block,
+                npoints: self.npoints[j],
+            })
+        })
+    }
+
+    /// Expand back into an **Octree** (sequential).
+    pub fn decompress(self) -> Octree {
+        let mut tree: Tree = HashMap::new();
+        for leaf in self.iter_leaves() {
+            tree.entry(leaf.block).or_default().push(leaf);
+        }
+
+        let mut octree = Octree {
+            tree,
+            sorted_leaves: None,
+            coarse_blocks: self.coarse_blocks,
+            indices: self.indices,
+            points: self.points,
+            #[cfg(feature = "ndarray")]
+            fields: self.fields,
+            depth: self.depth,
+            ncrit: self.ncrit,
+            x0: self.x0,
+            r0: self.r0,
+        };
+
+        if self.keeps_sorted_leaves {
+            octree.keep_sorted_leaves();
+        }
+        octree
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+/// **Weight** of a given **Block**. Defined by number of original **Leaf** nodes it contains.
+pub struct Weight(pub u64);
+/// Vector of **Weights**.
+pub type Weights = Vec<Weight>;
+
+impl Add for Weight {
+    type Output = Weight;
+
+    fn add(self, other: Weight) -> Weight {
+        Weight(self.0 + other.0)
+    }
+}
+
+impl Sum for Weight {
+    fn sum<I: Iterator<Item = Weight>>(iter: I) -> Weight {
+        iter.fold(Weight(0), |acc, w| acc + w)
+    }
+}
+
+impl<'a> Sum<&'a Weight> for Weight {
+    fn sum<I: Iterator<Item = &'a Weight>>(iter: I) -> Weight {
+        iter.fold(Weight(0), |acc, &w| acc + w)
+    }
+}
+
+impl From<u64> for Weight {
+    fn from(weight: u64) -> Self {
+        Weight(weight)
+    }
+}
+
+impl From<Weight> for u64 {
+    fn from(weight: Weight) -> Self {
+        weight.0
+    }
+}
+
+unsafe impl Equivalence for Weight {
+    type Out = UserDatatype;
+    fn equivalent_datatype() -> Self::Out {
+        UserDatatype::structured(
+            &[1],
+            &[offset_of!(Weight, 0) as Address],
+            &[UncommittedUserDatatype::contiguous(1, &u64::equivalent_datatype()).as_ref()],
+        )
+    }
+}
+
+/// Adapted from algorithm 3 in [1]. Construct a minimal octree between two octants, excluding the
+/// two octants (sequential).
+pub fn complete_region(a: &Key, b: &Key, depth: &u64) -> Keys {
+    // Successive octants at the same level have no octants between them.
+    if (a.3 == b.3) && (next(a, depth) == Some(*b)) {
+        return Vec::new();
+    }
+
+    let ancestors_a: HashSet<Key> = find_ancestors(a, depth).into_iter().collect();
+    let ancestors_b: HashSet<Key> = find_ancestors(b, depth).into_iter().collect();
+    let na = find_finest_common_ancestor(a, b, depth);
+
+    let mut working_list: HashSet<Key> = children_array(&na, depth).iter().copied().collect();
+
+    let mut minimal_tree: Keys = Vec::new();
+
+    loop {
+        let mut aux_list: HashSet<Key> = HashSet::new();
+        let mut len = 0;
+
+        for w in &working_list {
+            if ((a < w) & (w < b)) & !ancestors_b.contains(w) {
+                aux_list.insert(*w);
+                len += 1;
+            } else if ancestors_a.contains(w) | ancestors_b.contains(w) {
+                aux_list.extend(children_array(w, depth).iter());
+            }
+        }
+
+        if len == working_list.len() {
+            minimal_tree = aux_list.into_iter().collect();
+            break;
+        } else {
+            working_list = aux_list;
+        }
+    }
+
+    minimal_tree.sort();
+    minimal_tree
+}
+
+/// Make **Leaves** unique, check that they don't exceed 'ncrit' points per leaf. The **Points**
+/// at this process are used to tell apart leaves which overflow due to coincident points
+/// (sequential).
+pub fn unique_leaves(
+    mut leaves: Leaves,
+    points: &[Point],
+    ncrit: &usize,
+    sorted: bool,
+) -> Result<Leaves, TreeError> {
+    // Container for result
+    let mut unique: Leaves = Vec::new();
+
+    // Sort leaves
+    if !sorted {
+        leaves.sort();
+    }
+
+    // Find unique leaves, summing the points of duplicates. A process without leaves has none.
+    for &leaf in leaves.iter() {
+        match unique.last_mut() {
+            Some(curr) if *curr == leaf => curr.npoints += leaf.npoints,
+            _ => unique.push(leaf),
+        }
+    }
+
+    // Check that no leaf exceeds 'ncrit' points
+    for leaf in unique.iter() {
+        if leaf.npoints > *ncrit {
+            return Err(leaf_overflow(leaf, points));
+        }
+    }
+
+    Ok(unique)
+}
+
+/// Classify a **Leaf** containing more than 'ncrit' points. If all of its points are coincident
+/// no depth of tree can separate them (sequential).
+fn leaf_overflow(leaf: &Leaf, points: &[Point]) -> TreeError {
+    let mut contained = points.iter().filter(|p| p.key == leaf.key);
+
+    let coincident = match contained.next() {
+        Some(first) => contained.all(|p| p == first),
+        None => false,
+    };
+
+    if coincident {
+        TreeError::IrreducibleDuplicates {
+            key: leaf.key,
+            npoints: leaf.npoints,
+        }
+    } else {
+        TreeError::LeafOverflow {
+            key: leaf.key,
+            npoints: leaf.npoints,
+        }
+    }
+}
+
+/// Find coarsest **Seeds** at each processor. These are used to seed the construction of a minimal
+/// block octree in Algorithm 4 of [1] (sequential).
+pub fn find_seeds(local_leaves: &[Leaf], depth: &u64) -> Result<Keys, TreeError> {
+    // Find least and greatest leaves on processor
+    if local_leaves.is_empty() {
+        return Err(TreeError::EmptyPartition);
+    }
+    let min: Key = local_leaves.iter().min().unwrap().key;
+    let max: Key = local_leaves.iter().max().unwrap().key;
+
+    // Complete region between least and greatest leaves
+    let mut complete = complete_region(&min, &max, depth);
+    complete.push(min);
+    complete.push(max);
+
+    // Find blocks
+    let levels: Vec<u64> = complete.iter().map(|k| k.3).collect();
+
+    let mut coarsest_level = *depth;
+
+    for l in levels {
+        if l < coarsest_level {
+            coarsest_level = l;
+        }
+    }
+
+    let mut seed_idxs: Vec<usize> = Vec::new();
+
+    for (i, node) in complete.iter().enumerate() {
+        if node.3 == coarsest_level {
+            seed_idxs.push(i);
+        }
+    }
+
+    let seeds: Keys = seed_idxs.iter().map(|&i| complete[i as usize]).collect();
+
+    Ok(seeds)
+}
+
+/// Transfer leaves based on **Seeds**. After distributed coarse block octree is found, leaves
+/// smaller than the minimum **Seed** on  a given processor must be handed to its partner from
+/// algorithm 4 of [1]. Fails at every process if any process has no **Leaves** or **Seeds**
+/// (parallel).
+pub fn transfer_leaves_to_coarse_blocktree<C: Communicator>(
+    points: &[Point],
+    local_leaves: &[Leaf],
+    received_points: &mut Points,
+    received_leaves: &mut Leaves,
+    seeds: &[Key],
+    rank: Rank,
+    world: &C,
+    size: Rank,
+) -> Result<(), TreeError> {
+    // Every process must find its least seed before any leaves are passed on.
+    let min_seed = if rank == 0 {
+        local_leaves.iter().min().map(|leaf| leaf.key)
+    } else {
+        seeds.iter().min().copied()
+    };
+    let min_seed = agree(min_seed.ok_or(TreeError::EmptyPartition), world)?;
+
+    let prev_rank = if rank > 0 { rank - 1 } else {size-1};
+    let next_rank = if rank +1 < size { rank + 1 } else { 0 };
+    let previous_process = world.process_at_rank(prev_rank);
+    let next_process = world.process_at_rank(next_rank);
+
+    if rank > 0 {
+        let msg: Leaves = local_leaves
+            .iter()
+            .filter(|&l| l.key < min_seed)
+            .cloned()
+            .collect();
+
+        send_fragmented(&previous_process, &msg[..], TAG_LEAVES, max_msg_elements());
+    }
+
+    if rank < (size - 1) {
+        let mut buffer = receive_fragmented(&next_process, TAG_LEAVES, max_msg_elements());
+        received_leaves.append(&mut buffer);
+    }
+
+    if rank > 0 {
+        let msg: Points = points
+            .iter()
+            .filter(|&l| l.key < min_seed)
+            .cloned()
+            .collect();
+
+        send_fragmented(&previous_process, &msg[..], TAG_POINTS, max_msg_elements());
+    }
+
+    if rank < (size - 1) {
+        let mut buffer = receive_fragmented(&next_process, TAG_POINTS, max_msg_elements());
+        received_points.append(&mut buffer);
+    }
+
+    let mut local_leaves: Leaves = local_leaves
+        .iter()
+        .filter(|&l| l.key >= min_seed)
+        .cloned()
+        .collect();
+
+    let mut points: Points = points
+        .iter()
+        .filter(|&l| l.key >= min_seed)
+        .cloned()
+        .collect();
+
+    received_leaves.append(&mut local_leaves);
+    received_points.append(&mut points);
+
+    // Re Sort received leaves
+    received_leaves.sort();
+
+    Ok(())
+}
+
+/// Remove overlaps from a list of octants, algorithm 7 in [1], expects input keys to be sorted
+/// (sequential).
+pub fn linearise(keys: &mut Keys, depth: &u64) -> Keys {
+    let mut linearised: Keys = Vec::new();
+    for w in keys.windows(2) {
+        let curr = w[0];
+        let next = w[1];
+        if !curr.is_ancestor_of(&next, depth) {
+            linearised.push(curr)
+        }
+    }
+
+    // The last octant has no successor to overlap with.
+    linearised.extend(keys.last());
+    linearised
+}
+
+/// Find the least key held by the processes after this one, passing the least key held by this
+/// process, or else by those after it, to the previous process. Keys travel as messages of zero or
+/// one keys, so that no key is reserved to mark processes without keys (parallel).
+fn least_key_after<C: Communicator>(least: Option<Key>, tag: Tag, world: &C) -> Option<Key> {
+    let rank = world.rank();
+    let size = world.size();
+
+    let receive_next = || {
+        if rank + 1 < size {
+            let (rec, _) = world
+                .process_at_rank(rank + 1)
+                .receive_vec_with_tag::<Key>(tag);
+            rec.first().copied()
+        } else {
+            None
+        }
+    };
+    let send_previous = |key: Option<Key>| {
+        if rank > 0 {
+            let msg: Keys = key.into_iter().collect();
+            world.process_at_rank(rank - 1).send_with_tag(&msg[..], tag);
+        }
+    };
+
+    // Processes with keys send first, so only runs of processes without keys wait on each other.
+    match least {
+        Some(least) => {
+            send_previous(Some(least));
+            receive_next()
+        }
+        None => {
+            let next = receive_next();
+            send_previous(next);
+            next
+        }
+    }
+}
+
+/// Remove overlaps from a list of octants distributed in Morton order across processes, expects
+/// the keys at each process to be sorted. After linearising locally the only remaining overlap is
+/// between the last octant at a process and its successor at the next process holding keys, so
+/// each process finds its successor from its neighbours, and a last octant that is an ancestor of
+/// its successor is removed (parallel).
+pub fn linearise_parallel<C: Communicator>(keys: &mut Keys, depth: &u64, world: &C) -> Keys {
+    let mut linearised = linearise(keys, depth);
+
+    let successor = least_key_after(linearised.first().copied(), TAG_BLOCKS, world);
+
+    if let (Some(&last), Some(successor)) = (linearised.last(), successor) {
+        if last.is_ancestor_of(&successor, depth) {
+            linearised.pop();
+        }
+    }
+
+    linearised
+}
+
+/// Complete a distributed blocktree from the seed octants, algorithm 4 in [1]. Processes may hold
+/// any number of seeds, a process without seeds holds no blocks and forwards the least seed of the
+/// following processes to its predecessor, while the first and last processes holding seeds
+/// complete the region to the ends of the domain. Fails on every process if none holds a seed
+/// (parallel).
+pub fn complete_blocktree<C: Communicator>(
+    seeds: &mut Keys,
+    depth: &u64,
+    rank: Rank,
+    size: Rank,
+    world: &C,
+) -> Result<Keys, TreeError> {
+    let sorted = match seeds.windows(2).find(|w| w[0] > w[1]) {
+        Some(w) => Err(TreeError::UnsortedSeeds { a: w[0], b: w[1] }),
+        None => Ok(()),
+    };
+    agree(sorted, world)?;
+
+    // Find whether this process holds the first or last seeds of the Morton curve.
+    let nseeds = seeds.len() as u64;
+    let preceding = scan_exclusive(nseeds, world);
+    let mut total: u64 = 0;
+    world.all_reduce_into(&nseeds, &mut total, SystemOperation::sum());
+
+    if total == 0 {
+        return Err(TreeError::EmptyPartition);
+    }
+
+    let first = (nseeds > 0) & (preceding == 0);
+    let last = (nseeds > 0) & (preceding + nseeds == total);
+
+    if first {
+        let root = Key(0, 0, 0, 0);
+        let dfd_root = find_deepest_first_descendent(&root, depth);
+        let min = seeds.iter().min().unwrap();
+        let na = find_finest_common_ancestor(&dfd_root, min, depth);
+        let mut first_child = na;
+        first_child.3 += 1;
+        seeds.push(first_child);
+    }
+
+    if last {
+        let root = Key(0, 0, 0, 0);
+        let dld_root = find_deepest_last_descendent(&root, depth);
+        let max = seeds.iter().max().unwrap();
+        let na = find_finest_common_ancestor(&dld_root, max, depth);
+        let children = find_children(&na, depth);
+        let last_child = *children.iter().max().unwrap();
+        seeds.push(last_child);
+    }
+
+    seeds.sort();
+    seeds.dedup();
+
+    // Send the least seed to the previous process, processes without seeds forward the least
+    // seed of the processes after them.
+    let next = least_key_after(seeds.first().copied(), TAG_BLOCKS, world);
+
+    if !seeds.is_empty() {
+        seeds.extend(next);
+    }
+
+    // Complete region between seeds at each process
+    let mut local_blocktree: Keys = Vec::new();
+
+    for w in seeds.windows(2) {
+        let (a, b) = (w[0], w[1]);
+
+        let mut tmp = complete_region(&a, &b, depth);
+        local_blocktree.push(a);
+        local_blocktree.append(&mut tmp);
+    }
+
+    if last {
+        local_blocktree.push(*seeds.last().unwrap());
+    }
+
+    local_blocktree.sort();
+    Ok(local_blocktree)
+}
+
+/// Associate a given set of **Blocks** with a given set of **Leaves** (sequential).
+pub fn assign_blocks_to_leaves(local_leaves: &mut Leaves, local_blocktree: &[Key], depth: &u64) {
+    let local_blocktree_set: HashSet<Key> = local_blocktree.iter().cloned().collect();
+
+    for leaf in local_leaves.iter_mut() {
+        if local_blocktree_set.contains(&leaf.key) {
+            leaf.block = leaf.key;
+            continue;
+        }
+
+        // Walk up from the parent without allocating, the finest ancestor in the blocktree wins.
+        let block = (0..leaf.key.3)
+            .rev()
+            .map(|level| find_ancestor_at_level(&leaf.key, &level, depth))
+            .find(|ancestor| local_blocktree_set.contains(ancestor));
+
+        if let Some(block) = block {
+            leaf.block = block;
+        }
+    }
+}
+
+/// Find the **Weights** of a given set of **Blocks**, the number of **Leaves** in each, counted
+/// in a single pass over the leaves (sequential).
+pub fn find_block_weights(leaves: &[Leaf], blocktree: &[Key]) -> Weights {
+    let mut counts: HashMap<Key, u64> = HashMap::new();
+    for leaf in leaves.iter() {
+        *counts.entry(leaf.block).or_default() += 1;
+    }
+
+    blocktree
+        .iter()
+        .map(|block| Weight(*counts.get(block).unwrap_or(&0)))
+        .collect()
+}
+
+/// Transfer **Leaves** to correspond to the final load balanced blocktree, each **Leaf** is sent to
+/// the rank its **Block** was sent to by `block_partition`. Leaves of unmapped blocks are kept
+/// (parallel).
+pub fn transfer_leaves_to_final_blocktree<C: Communicator>(
+    destinations: &HashMap<Key, Rank>,
+    local_leaves: Leaves,
+    size: Rank,
+    world: &C,
+) -> Leaves {
+    let rank = world.rank();
+    let mut buckets: Vec<Leaves> = vec![Vec::new(); size as usize];
+
+    for leaf in local_leaves.into_iter() {
+        let destination = destinations.get(&leaf.block).copied().unwrap_or(rank);
+        buckets[destination as usize].push(leaf);
+    }
+
+    all_to_all(world, size, buckets)
+}
+
+/// Find the share of each local **Block** in a partition of the global weight into `nshares`
+/// equal shares, from the global cumulative weight at the start of the block. Blocks must be in
+/// Morton order across processes (parallel).
+fn block_shares<C: Communicator>(weights: &[Weight], nshares: u64, world: &C) -> Vec<usize> {
+    let local_weight: u64 = weights.iter().sum::<Weight>().into();
+    let mut total_weight: u64 = 0;
+    world.all_reduce_into(&local_weight, &mut total_weight, SystemOperation::sum());
+
+    let mut preceding_weight = scan_exclusive(local_weight, world);
+
+    weights
+        .iter()
+        .map(|w| {
+            let share = (preceding_weight * nshares) / total_weight.max(1);
+            preceding_weight += w.0;
+            share as usize
+        })
+        .collect()
+}
+
+/// Re-partition the blocks so that amount of computation on each node is balanced. Each block
+/// is sent directly to the rank whose share of the global weight contains it, so a single call
+/// reaches the balanced layout however far blocks must move. Blocks are shared between the first
+/// `size` ranks of `world`. Return mapping between block and rank to which it was sent, a single
+/// process keeps its blocks and sends none (parallel).
+pub fn block_partition<C: Communicator>(
+    weights: Weights,
+    local_blocktree: &mut Keys,
+    size: Rank,
+    world: &C,
+) -> HashMap<Key, Rank> {
+    if world.size() == 1 {
+        return HashMap::new();
+    }
+
+    let shares = block_shares(&weights, size as u64, world);
+
+    let mut destinations: HashMap<Key, Rank> = HashMap::new();
+    let mut buckets: Vec<Keys> = vec![Vec::new(); world.size() as usize];
+
+    for (&block, &share) in local_blocktree.iter().zip(shares.iter()) {
+        destinations.insert(block, share as Rank);
+        buckets[share].push(block);
+    }
+
+    // Blocks are received in rank order, so remain in Morton order.
+    *local_blocktree = all_to_all(world, world.size(), buckets);
+
+    destinations
+}
+
+/// Split **Blocks** to satisfy a maximum of NCRIT particles per node in the final octree
+/// (sequential).
+pub fn split_blocks(
+    local_leaves: &mut Leaves,
+    depth: &u64,
+    ncrit: &usize,
+) -> Result<Tree, TreeError> {
+    split_blocks_adaptive(local_leaves, depth, |_| *ncrit)
+}
+
+/// Split **Blocks** to satisfy a maximum number of particles per node that varies spatially,
+/// `ncrit` gives the capacity of each **Block** from its key. Blocks at the maximum depth can't be
+/// split further, so fail if one of them is over capacity (sequential).
+pub fn split_blocks_adaptive(
+    local_leaves: &mut Leaves,
+    depth: &u64,
+    ncrit: impl Fn(&Key) -> usize,
+) -> Result<Tree, TreeError> {
+    let mut blocks: Tree = HashMap::new();
+
+    for &leaf in local_leaves.iter() {
+        blocks.entry(leaf.block).or_default().push(leaf);
+    }
+
+    loop {
+        let mut to_split: Keys = Vec::new();
+        for (&key, leaves) in blocks.iter() {
+            let capacity = ncrit(&key);
+            let mut npoints = 0;
+            for leaf in leaves {
+                npoints += leaf.npoints;
+                if npoints > capacity {
+                    to_split.push(key);
+                    break;
+                }
+            }
+
+            if (npoints > capacity) & (key.3 >= *depth) {
+                return Err(TreeError::CannotSatisfyNcrit {
+                    block: key,
+                    npoints: leaves.iter().map(|l| l.npoints).sum(),
+                    ncrit: capacity,
+                });
+            }
+        }
+
+        if to_split.len() > 0 {
+            for &key in to_split.iter() {
+                let mut leaves = blocks.get(&key).unwrap().clone();
+                let children = find_children(&key, depth);
+                blocks.remove(&key);
+                assign_blocks_to_leaves(&mut leaves, &children, depth);
+
+                for &leaf in leaves.iter() {
+                    blocks.entry(leaf.block).or_default().push(leaf);
+                }
+            }
+        } else {
+            break;
+        }
+    }
+    Ok(blocks)
+}
+
+/// Select splitters from the samples gathered from every process. Sentinel samples from
+/// processes without leaves are discarded, and every K'th of the remaining samples defines a
+/// bucket (sequential).
+fn find_splitters(mut samples: Leaves) -> Leaves {
+    samples.retain(|s| s.key != Key::default());
+
+    // Ignore first K samples to ensure (nproc-1) splitters
+    samples.sort();
+    samples.iter().skip(K).step_by(K).cloned().collect()
+}
+
+/// Collect 'K' random samples of the local **Leaves** from each process onto all other processes,
+/// and select splitters from them, processes without leaves contribute sentinel samples
+/// (parallel).
+fn sample_splitters<C, R>(local_leaves: &[Leaf], size: Rank, world: &C, rng: &mut R) -> Leaves
+where
+    C: Communicator,
+    R: Rng,
+{
+    let mut received_samples = vec![Leaf::default(); K * (size as usize)];
+    let nleaves = local_leaves.len();
+
+    let mut local_samples: Leaves = vec![Leaf::default(); K];
+
+    if nleaves > 0 {
+        let sample_idxs: Vec<usize> = (0..K).map(|_| rng.gen_range(0..nleaves)).collect();
+
+        for (i, &sample_idx) in sample_idxs.iter().enumerate() {
+            local_samples[i] = local_leaves[sample_idx].clone();
+        }
+    }
+
+    world.all_gather_into(&local_samples[..], &mut received_samples[..]);
+
+    let splitters = find_splitters(received_samples);
+    debug_assert!(splitters.len() < size as usize);
+    splitters
+}
+
+/// Perform parallelised sample sort on a distributed set of **Leaves** (parallel).
+pub fn sample_sort<C: Communicator>(
+    points: &mut Points,
+    size: Rank,
+    world: &C,
+) -> (Leaves, Points) {
+    sample_sort_by(points, size, world, &mut thread_rng(), |buckets| {
+        all_to_all(world, size, buckets)
+    })
+}
+
+/// Perform parallelised sample sort on a distributed set of **Leaves**, drawing the samples of
+/// each process from a generator seeded by the base seed of the root process and its rank. The
+/// splitters, and so the whole sort, are reproducible given the base seed (parallel).
+pub fn sample_sort_seeded<C: Communicator>(
+    points: &mut Points,
+    size: Rank,
+    world: &C,
+    base_seed: u64,
+) -> (Leaves, Points) {
+    let mut base_seed = base_seed;
+    world.process_at_rank(0).broadcast_into(&mut base_seed);
+
+    // Decorrelate the seeds of neighbouring ranks.
+    let seed = base_seed ^ (world.rank() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    sample_sort_by(points, size, world, &mut rng, |buckets| {
+        all_to_all(world, size, buckets)
+    })
+}
+
+/// Perform parallelised sample sort on a distributed set of **Leaves**, sending the coordinates of
+/// **Points** in single precision. Keys and indices are still sent in full, so each point sent is
+/// about 19% smaller, 52 rather than 64 bytes. **Keys**, and so the resulting **Leaves**, are
+/// identical to those of `sample_sort`, but coordinates are only recovered to a relative error of
+/// `f32::EPSILON / 2`, about 6e-8 (parallel).
+#[cfg(feature = "f32")]
+pub fn sample_sort_f32<C: Communicator>(
+    points: &mut Points,
+    size: Rank,
+    world: &C,
+) -> (Leaves, Points) {
+    sample_sort_by(points, size, world, &mut thread_rng(), |buckets| {
+        let packed: Vec<Vec<PackedPoint>> = buckets
+            .iter()
+            .map(|bucket| bucket.iter().map(PackedPoint::from).collect())
+            .collect();
+        all_to_all(world, size, packed)
+            .iter()
+            .map(Point::from)
+            .collect()
+    })
+}
+
+/// Sample sort with the exchange of bucketed **Points** left to the caller, so that they may be
+/// sent in another representation (parallel).
+fn sample_sort_by<C, R, F>(
+    mut points: &mut Points,
+    size: Rank,
+    world: &C,
+    rng: &mut R,
+    exchange_points: F,
+) -> (Leaves, Points)
+where
+    C: Communicator,
+    R: Rng,
+    F: FnOnce(Vec<Points>) -> Points,
+{
+    let local_leaves = keys_to_leaves(&mut points);
+
+    // 1. Select splitters from samples of the leaves at every process.
+    let splitters = sample_splitters(&local_leaves, size, world, rng);
+
+    // 2. Sort local leaves and points into buckets, each bucket follows the splitters before it.
+    // Sentinel samples are discarded, so there may be fewer than (nproc-1) splitters, and a key
+    // equal to a splitter belongs to the bucket which the splitter begins.
+    let bucket = |key: &Key| splitters.partition_point(|s| s.key <= *key);
+
+    let mut buckets_leaves: Vec<Leaves> = vec![Vec::new(); size as usize];
+    for &leaf in local_leaves.iter() {
+        buckets_leaves[bucket(&leaf.key)].push(leaf);
+    }
+
+    let mut buckets_points: Vec<Points> = vec![Vec::new(); size as usize];
+    for &point in points.iter() {
+        buckets_points[bucket(&point.key)].push(point);
+    }
+
+    // 3. Send all local buckets to their matching processor.
+    let mut received_leaves = all_to_all(world, size, buckets_leaves);
+    let received_points = exchange_points(buckets_points);
+
+    // 4. Sort leaves on matching processors.
+    received_leaves.sort();
+    (received_leaves, received_points)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// **SortAlgorithm**, the parallel sorts available for distributing **Leaves** in Morton order.
+pub enum SortAlgorithm {
+    /// Splitters selected from random samples of every process, see `sample_sort`.
+    SampleSort,
+    /// Splitters refined until every bucket is within a tolerance of the mean, see
+    /// `histogram_sort`.
+    HistogramSort,
+    /// Sample sort sending coordinates in single precision, see `sample_sort_f32`.
+    #[cfg(feature = "f32")]
+    SampleSortF32,
+}
+
+impl Default for SortAlgorithm {
+    fn default() -> Self {
+        SortAlgorithm::SampleSort
+    }
+}
+
+/// Sort a distributed set of **Points** into **Leaves** with a given algorithm (parallel).
+pub fn parallel_sort<C: Communicator>(
+    algorithm: SortAlgorithm,
+    points: &mut Points,
+    size: Rank,
+    world: &C,
+) -> (Leaves, Points) {
+    match algorithm {
+        SortAlgorithm::SampleSort => sample_sort(points, size, world),
+        SortAlgorithm::HistogramSort => histogram_sort(points, size, world),
+        #[cfg(feature = "f32")]
+        SortAlgorithm::SampleSortF32 => sample_sort_f32(points, size, world),
+    }
+}
+
+/// Perform parallelised histogram sort on a distributed set of **Leaves**. Each splitter is
+/// refined by a parallel median of medians, taking the weighted median of the medians of the
+/// leaves between its bounds at each process, until a global histogram places it within
+/// `HISTOGRAM_TOLERANCE` of its target. Unlike sample sort every process receives close to the
+/// mean number of leaves, however they are distributed (parallel).
+pub fn histogram_sort<C: Communicator>(
+    mut points: &mut Points,
+    size: Rank,
+    world: &C,
+) -> (Leaves, Points) {
+    let local_leaves = keys_to_leaves(&mut points);
+    let nleaves = local_leaves.len();
+    let nsplitters = (size - 1) as usize;
+
+    let mut total: u64 = 0;
+    world.all_reduce_into(&(nleaves as u64), &mut total, SystemOperation::sum());
+
+    let tolerance = (HISTOGRAM_TOLERANCE * (total as f64) / (size as f64)) as u64;
+    let targets: Vec<u64> = (1..=nsplitters as u64)
+        .map(|i| (i * total) / (size as u64))
+        .collect();
+
+    // Each unresolved splitter lies strictly between its bounds, missing bounds are unbounded.
+    let mut lower: Vec<Option<Key>> = vec![None; nsplitters];
+    let mut upper: Vec<Option<Key>> = vec![None; nsplitters];
+    let mut best: Vec<Option<(Key, u64)>> = vec![None; nsplitters];
+    let mut splitters: Vec<Option<Key>> = vec![None; nsplitters];
+
+    // 1. Refine splitters, every process resolves the same splitters at each iteration.
+    while splitters.iter().any(|s| s.is_none()) {
+        // Local median of the leaves between the bounds of each splitter, weighted by their number.
+        let mut medians: Keys = vec![Key::default(); nsplitters];
+        let mut counts: Vec<u64> = vec![0; nsplitters];
+
+        for i in (0..nsplitters).filter(|&i| splitters[i].is_none()) {
+            let start = lower[i].map_or(0, |l| local_leaves.partition_point(|leaf| leaf.key <= l));
+            let end = upper[i].map_or(nleaves, |u| {
+                local_leaves.partition_point(|leaf| leaf.key < u)
+            });
+
+            if start < end {
+                medians[i] = local_leaves[(start + end) / 2].key;
+                counts[i] = (end - start) as u64;
+            }
+        }
+
+        let mut received_medians = vec![Key::default(); nsplitters * (size as usize)];
+        let mut received_counts = vec![0u64; nsplitters * (size as usize)];
+        world.all_gather_into(&medians[..], &mut received_medians[..]);
+        world.all_gather_into(&counts[..], &mut received_counts[..]);
+
+        // Candidate splitters are the weighted medians of the local medians.
+        let mut candidates: Keys = vec![Key::default(); nsplitters];
+        let pending: Vec<usize> = (0..nsplitters)
+            .filter(|&i| splitters[i].is_none())
+            .collect();
+        for &i in pending.iter() {
+            let mut weighted: Vec<(Key, u64)> = (0..size as usize)
+                .map(|p| p * nsplitters + i)
+                .map(|j| (received_medians[j], received_counts[j]))
+                .filter(|&(_, count)| count > 0)
+                .collect();
+            weighted.sort();
+
+            // No leaves remain between the bounds, so settle for the closest candidate so far.
+            if weighted.is_empty() {
+                splitters[i] = Some(best[i].map_or(Key::default(), |(key, _)| key));
+                continue;
+            }
+
+            let half = (weighted.iter().map(|&(_, count)| count).sum::<u64>() + 1) / 2;
+            let mut cumulative = 0;
+            for &(key, count) in weighted.iter() {
+                cumulative += count;
+                if cumulative >= half {
+                    candidates[i] = key;
+                    break;
+                }
+            }
+        }
+
+        // Global histogram of the number of leaves preceding each candidate.
+        let local_positions: Vec<u64> = candidates
+            .iter()
+            .map(|c| local_leaves.partition_point(|leaf| leaf.key < *c) as u64)
+            .collect();
+        let mut positions = vec![0u64; nsplitters];
+        world.all_reduce_into(
+            &local_positions[..],
+            &mut positions[..],
+            SystemOperation::sum(),
+        );
+
+        for &i in pending.iter() {
+            let error = if positions[i] > targets[i] {
+                positions[i] - targets[i]
+            } else {
+                targets[i] - positions[i]
+            };
+
+            if best[i].map_or(true, |(_, e)| error < e) {
+                best[i] = Some((candidates[i], error));
+            }
+
+            if error <= tolerance {
+                splitters[i] = Some(candidates[i]);
+            } else if positions[i] < targets[i] {
+                lower[i] = Some(candidates[i]);
+            } else {
+                upper[i] = Some(candidates[i]);
+            }
+        }
+    }
+
+    let mut splitters: Keys = splitters.into_iter().flatten().collect();
+    splitters.sort();
+
+    // 2. Sort local leaves and points into buckets, each bucket follows the splitters before it.
+    let bucket = |key: &Key| splitters.partition_point(|s| s <= key);
+
+    let mut buckets_leaves: Vec<Leaves> = vec![Vec::new(); size as usize];
+    for &leaf in local_leaves.iter() {
+        buckets_leaves[bucket(&leaf.key)].push(leaf);
+    }
+
+    let mut buckets_points: Vec<Points> = vec![Vec::new(); size as usize];
+    for &point in points.iter() {
+        buckets_points[bucket(&point.key)].push(point);
+    }
+
+    // 3. Send all local buckets to their matching processor.
+    let mut received_leaves = all_to_all(world, size, buckets_leaves);
+    let received_points = all_to_all(world, size, buckets_points);
+
+    // 4. Sort leaves on matching processors.
+    received_leaves.sort();
+    (received_leaves, received_points)
+}
+
+/// Send each bucket to the process of the same rank, returning the buckets received concatenated
+/// in rank order. Buckets are sent in rounds of at most `max_msg_elements` elements in total, so
+/// that no count or displacement of a round can exceed the message limit (parallel).
+fn all_to_all<T, C: Communicator>(world: &C, size: Rank, buckets: Vec<Vec<T>>) -> Vec<T>
+where
+    T: Default + Clone + Equivalence,
+{
+    let chunk = (max_msg_elements() / size as usize).max(1);
+
+    // Agree on the number of rounds needed to send the largest bucket of any process.
+    let local_rounds = buckets
+        .iter()
+        .map(|bucket| ((bucket.len() + chunk - 1) / chunk) as u64)
+        .max()
+        .unwrap_or(0);
+    let mut rounds = 0u64;
+    world.all_reduce_into(&local_rounds, &mut rounds, SystemOperation::max());
+
+    if rounds == 1 {
+        return all_to_all_round(world, size, &buckets[..]).0;
+    }
+
+    // Fragments from each process are collected separately, to keep them in rank order.
+    let mut received: Vec<Vec<T>> = vec![Vec::new(); size as usize];
+    for round in 0..(rounds as usize) {
+        let fragments: Vec<&[T]> = buckets
+            .iter()
+            .map(|bucket| {
+                let start = (round * chunk).min(bucket.len());
+                let end = ((round + 1) * chunk).min(bucket.len());
+                &bucket[start..end]
+            })
+            .collect();
+
+        let (flat, counts) = all_to_all_round(world, size, &fragments[..]);
+        let mut flat = &flat[..];
+        for (i, &count) in counts.iter().enumerate() {
+            let (fragment, rest) = flat.split_at(count as usize);
+            received[i].extend_from_slice(fragment);
+            flat = rest;
+        }
+    }
+
+    received.into_iter().flatten().collect()
+}
+
+/// A single round of `all_to_all`, returning the buckets received concatenated in rank order and
+/// the number of elements received from each process (parallel).
+fn all_to_all_round<T, B, C>(world: &C, size: Rank, buckets: &[B]) -> (Vec<T>, Vec<Count>)
+where
+    T: Default + Clone + Equivalence,
+    B: AsRef<[T]>,
+    C: Communicator,
+{
+    let counts_snd: Vec<Count> = buckets.iter().map(|b| b.as_ref().len() as Count).collect();
+
+    // Flatten buckets, after bucketing
+    let buckets_flat: Vec<T> = buckets
+        .iter()
+        .flat_map(|b| b.as_ref().iter().cloned())
+        .collect();
+    let displs_snd = displacements(&counts_snd);
+
+    // All to All for bucket sizes
+    let mut counts_recv: Vec<Count> = vec![0; size as usize];
+    world.all_to_all_into(&counts_snd[..], &mut counts_recv[..]);
+    let displs_recv = displacements(&counts_recv);
+
+    // Allocate a buffer to receive relevant data from all processes.
+    let total: Count = counts_recv.iter().sum();
+    let mut received = vec![T::default(); total as usize];
+    let mut partition_receive =
+        PartitionMut::new(&mut received[..], &counts_recv[..], &displs_recv[..]);
+
+    // Allocate a partition of the data to send to each process
+    let partition_snd = Partition::new(&buckets_flat[..], counts_snd, &displs_snd[..]);
+
+    world.all_to_all_varcount_into(&partition_snd, &mut partition_receive);
+
+    (received, counts_recv)
+}
+
+/// Find the neighbors of a key among a set of **Leaves**, given both sorted and as a set so that
+/// each lookup is a hash or a range search. Finer leaves inside a same-level neighbor are a
+/// contiguous range of the sorted leaves (sequential).
+fn neighbors_among(key: &Key, sorted: &[Key], leaves: &HashSet<Key>, depth: &u64) -> Keys {
+    // Test whether two octants share a face, edge or vertex, or overlap.
+    let touching = |a: &Key, b: &Key| {
+        let (wa, wb) = (side_shift(depth, &a.3), side_shift(depth, &b.3));
+        [(a.0, b.0), (a.1, b.1), (a.2, b.2)]
+            .iter()
+            .all(|&(x, y)| (x <= y + wb) & (y <= x + wa))
+    };
+
+    let mut neighbors: Keys = Vec::new();
+
+    for candidate in find_neighbors(key, depth).iter() {
+        if leaves.contains(candidate) {
+            neighbors.push(*candidate);
+        } else if let Some(&coarser) = find_ancestors(candidate, depth)
+            .iter()
+            .find(|a| leaves.contains(a))
+        {
+            neighbors.push(coarser);
+        } else {
+            let dld = find_deepest_last_descendent(candidate, depth);
+            let start = sorted.partition_point(|l| l < candidate);
+            let end = sorted.partition_point(|l| *l <= dld);
+            neighbors.extend(sorted[start..end].iter().filter(|l| touching(l, key)));
+        }
+    }
+
+    neighbors.sort();
+    neighbors.dedup();
+    neighbors
+}
+
+/// Group the global indices of **Points** by the **Leaf** that contains them (sequential).
+pub fn index_points(leaves: &Leaves, points: &Points) -> HashMap<Key, Vec<usize>> {
+    let mut keys: Keys = leaves.iter().map(|l| l.key).collect();
+    keys.sort();
+
+    let mut indices: HashMap<Key, Vec<usize>> = HashMap::new();
+    for point in points.iter() {
+        // The containing leaf is the greatest leaf that doesn't follow the point's key.
+        let idx = match keys.binary_search(&point.key) {
+            Ok(idx) => idx,
+            Err(0) => continue,
+            Err(idx) => idx - 1,
+        };
+        indices.entry(keys[idx]).or_default().push(point.global_idx);
+    }
+    indices
+}
+
+/// Build the **Leaves** of a distributed unbalanced tree from **Leaves** and **Points** already
+/// distributed in Morton order, returning them grouped by **Block** with the global indices of the
+/// points in each leaf, the local points in Morton order and the coarse blocktree. Shared by the
+/// in-memory and streaming builds (parallel).
+fn build_from_sorted<C: Communicator>(
+    local_leaves: Leaves,
+    points: Points,
+    depth: &u64,
+    ncrit: &usize,
+    world: &C,
+    time: &mut Times,
+) -> Result<(Tree, HashMap<Key, Vec<usize>>, Points, Keys), TreeError> {
+    let rank = world.rank();
+    let size = world.size();
+
+    let start = Instant::now();
+    // 3. Remove duplicates at each processor and remove overlaps if there are any
+    let local_leaves = agree(unique_leaves(local_leaves, &points, ncrit, true), world)?;
+    time.insert("overlap".to_string(), start.elapsed().as_millis());
+
+    let (mut local_leaves, points, local_blocktree) = traced!("blocktree", rank = rank; {
+        // 4.i Complete minimal tree on each process, and find seed octants.
+        let start = Instant::now();
+        let mut seeds = agree(find_seeds(&local_leaves, depth), world)?;
+
+        // 4.ii If leaf is less than the minimum seed in a given process, it needs to be sent to
+        // the previous process
+
+        // Temporary buffers to receive points and leaves to coarse block tree.
+        let mut received_leaves: Leaves = Vec::new();
+        let mut received_points: Points = Vec::new();
+
+        transfer_leaves_to_coarse_blocktree(
+            &points,
+            &local_leaves,
+            &mut received_points,
+            &mut received_leaves,
+            &seeds,
+            rank,
+            world,
+            size,
+        )?;
+        time.insert("seed".to_string(), start.elapsed().as_millis());
+        trace_sizes!(
+            nseeds = seeds.len(),
+            nleaves = received_leaves.len(),
+            npoints = received_points.len()
+        );
+
+        // 5. Complete minimal block-tree across processes
+        let start = Instant::now();
+        let local_blocktree = complete_blocktree(&mut seeds, depth, rank, size, world)?;
+        time.insert("minimal_block_tree".to_string(), start.elapsed().as_millis());
+        trace_sizes!(nblocks = local_blocktree.len());
+
+        (received_leaves, received_points, local_blocktree)
+    });
+
+    let nodes = traced!("block_splitting", rank = rank, nblocks = local_blocktree.len(); {
+        // Associate leaves with blocks
+        let start = Instant::now();
+        assign_blocks_to_leaves(&mut local_leaves, &local_blocktree, depth);
+        time.insert("block_assignment".to_string(), start.elapsed().as_millis());
+
+        // 6. Split blocks into adaptive tree, and pass into Octree structure.
+        let start = Instant::now();
+        let nodes = agree(split_blocks(&mut local_leaves, depth, ncrit), world)?;
+        time.insert("block_splitting".to_string(), start.elapsed().as_millis());
+        trace_sizes!(nnodes = nodes.len());
+
+        nodes
+    });
+
+    // 7. Record the original points contained in each leaf, and retain them in Morton order.
+    let start = Instant::now();
+    let indices = index_points(&local_leaves, &points);
+    let mut points = points;
+    points.par_sort_by(|a, b| a.key.cmp(&b.key));
+    time.insert("point_indexing".to_string(), start.elapsed().as_millis());
+
+    Ok((nodes, indices, points, local_blocktree))
+}
+
+/// Generate a distributed unbalanced tree from a set of distributed points, fails if a point lies
+/// outside of the domain, or if the points can't be split into leaves satisfying `ncrit`. Builds
+/// over the processes of any communicator, so callers may continue to communicate once the tree is
+/// built (parallel).
+pub fn unbalanced_tree<C: Communicator>(
+    depth: &u64,
+    ncrit: &usize,
+    world: &C,
+    points: &mut Points,
+    x0: Point,
+    r0: f64,
+) -> Result<(Octree, Times), TreeError> {
+    unbalanced_tree_sorted_by(
+        SortAlgorithm::default(),
+        depth,
+        ncrit,
+        world,
+        points,
+        x0,
+        r0,
+    )
+}
+
+/// Generate a distributed unbalanced tree as `unbalanced_tree`, distributing the points in Morton
+/// order with a given parallel sort (parallel).
+pub fn unbalanced_tree_sorted_by<C: Communicator>(
+    algorithm: SortAlgorithm,
+    depth: &u64,
+    ncrit: &usize,
+    world: &C,
+    mut points: &mut Points,
+    x0: Point,
+    r0: f64,
+) -> Result<(Octree, Times), TreeError> {
+    let rank = world.rank();
+    let size = world.size();
+
+    let mut time: Times = HashMap::new();
+
+    // 1. Encode points to leaf keys inplace.
+    let sim_start  = Instant::now();
+    traced!("encoding", rank = rank, npoints = points.len(); {
+        // Every process returns if the points at any process lie outside of the domain.
+        agree(check_level(depth, &0).and_then(|_| check_domain(points, &x0, &r0)), world)?;
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+    });
+    time.insert("encoding".to_string(), sim_start.elapsed().as_millis());
+
+    // 2. Perform parallel Morton sort over points
+    let start = Instant::now();
+    let (sorted_leaves, sorted_points) = traced!("sorting", rank = rank; {
+        let sorted = parallel_sort(algorithm, &mut points, size, world);
+        trace_sizes!(nleaves = sorted.0.len(), npoints = sorted.1.len());
+        sorted
+    });
+    time.insert("sorting".to_string(), start.elapsed().as_millis());
+
+    let points = sorted_points;
+    let local_leaves = sorted_leaves;
+
+    let (nodes, indices, points, coarse_blocks) =
+        build_from_sorted(local_leaves, points, depth, ncrit, world, &mut time)?;
+
+    // Record simulation time
+    time.insert("total".to_string(), sim_start.elapsed().as_millis());
+
+    let octree = Octree {
+        tree: nodes,
+        sorted_leaves: None,
+        coarse_blocks,
+        indices,
+        points,
+        #[cfg(feature = "ndarray")]
+        fields: HashMap::new(),
+        depth: *depth,
+        ncrit: *ncrit,
+        x0,
+        r0,
+    };
+
+    Ok((octree, time))
+}
+
+/// Stream encoded chunks of **Points** to the processes owning them, under splitters sampled from
+/// the first chunk at every process, returning the points received and the cumulative time spent
+/// waiting for other processes. Every process takes part in the same number of rounds, and all
+/// return an error if any process fails to produce a chunk (parallel).
+fn stream_to_owners<C: Communicator>(
+    mut chunks: impl Iterator<Item = Result<Points, TreeError>>,
+    world: &C,
+) -> Result<(Points, Duration), TreeError> {
+    let rank = world.rank();
+    let size = world.size();
+
+    let mut received: Points = Vec::new();
+    let mut splitters: Option<Leaves> = None;
+    let mut barrier = Duration::default();
+
+    loop {
+        let chunk = chunks.next();
+
+        // Agree on the first process to fail, if any, and whether any has points left to send.
+        let failed = if let Some(Err(_)) = chunk { rank } else { size };
+        let more = chunk.is_some() as i32;
+        let mut first_failed = size;
+        let mut any_more = 0;
+        world.all_reduce_into(&failed, &mut first_failed, SystemOperation::min());
+        world.all_reduce_into(&more, &mut any_more, SystemOperation::max());
+
+        let mut points = match chunk {
+            Some(Err(e)) => return Err(e),
+            _ if first_failed < size => return Err(TreeError::StreamFailed { rank: first_failed }),
+            _ if any_more == 0 => break,
+            Some(Ok(points)) => points,
+            None => Vec::new(),
+        };
+
+        let splitters = splitters.get_or_insert_with(|| {
+            let local_leaves = keys_to_leaves(&mut points);
+            sample_splitters(&local_leaves, size, world, &mut thread_rng())
+        });
+        let bucket = |key: &Key| splitters.partition_point(|s| s.key <= *key);
+
+        let mut buckets: Vec<Points> = vec![Vec::new(); size as usize];
+        for &point in points.iter() {
+            buckets[bucket(&point.key)].push(point);
+        }
+
+        barrier += barrier_timed(world);
+        received.extend(all_to_all(world, size, buckets));
+    }
+
+    Ok((received, barrier))
+}
+
+/// Generate a distributed unbalanced tree from **Points** read in chunks, for inputs too large to
+/// hold at once. The next chunk is read and encoded on a separate thread while the current one is
+/// sent to the processes owning it, so no process holds its whole input before it is distributed.
+/// Owners are found from splitters sampled from the first chunk at every process, so first chunks
+/// should be representative of the distribution. Builds the same **Leaves** as `unbalanced_tree`
+/// over the same points, and fails in the same ways, or if reading a chunk fails (parallel).
+pub fn build_streaming<I, E, C>(
+    chunks: I,
+    depth: &u64,
+    ncrit: &usize,
+    x0: Point,
+    r0: f64,
+    world: &C,
+) -> Result<(Octree, Times), TreeError>
+where
+    I: IntoIterator<Item = Result<Points, E>>,
+    I::IntoIter: Send,
+    E: Into<TreeError>,
+    C: Communicator,
+{
+    agree(check_level(depth, &0), world)?;
+    let mut time: Times = HashMap::new();
+
+    // 1. Read, encode and distribute chunks of points, reading ahead by one chunk.
+    let sim_start = Instant::now();
+    let encoded = encode_points_chunked(chunks, depth, &x0, &r0);
+
+    let (mut points, barrier) = traced!("streaming", rank = world.rank(); {
+        thread::scope(|scope| {
+            let (sender, receiver) = sync_channel(1);
+            scope.spawn(move || {
+                for chunk in encoded {
+                    // The receiver hangs up once streaming fails at any process.
+                    if sender.send(chunk).is_err() {
+                        break;
+                    }
+                }
+            });
+            stream_to_owners(receiver.iter(), world)
+        })?
+    });
+    time.insert("streaming".to_string(), sim_start.elapsed().as_millis());
+    time.insert("sorting_barrier".to_string(), barrier.as_millis());
+
+    // 2. Sort the received points locally, they are already distributed in Morton order.
+    let start = Instant::now();
+    let local_leaves = keys_to_leaves(&mut points);
+    time.insert("sorting".to_string(), start.elapsed().as_millis());
+
+    let (nodes, indices, points, coarse_blocks) =
+        build_from_sorted(local_leaves, points, depth, ncrit, world, &mut time)?;
+
+    // Record simulation time
+    time.insert("total".to_string(), sim_start.elapsed().as_millis());
+
+    let octree = Octree {
+        tree: nodes,
+        sorted_leaves: None,
+        coarse_blocks,
+        indices,
+        points,
+        #[cfg(feature = "ndarray")]
+        fields: HashMap::new(),
+        depth: *depth,
+        ncrit: *ncrit,
+        x0,
+        r0,
+    };
+
+    Ok((octree, time))
+}
+
+#[derive(Debug, Clone, Default)]
+/// **TreeDiff**, the **Leaves** found only in one of two **Trees**, and the pairs of **Leaves**
+/// found in both whose number of points differ.
+pub struct TreeDiff {
+    pub only_a: Leaves,
+    pub only_b: Leaves,
+    pub npoints: Vec<(Leaf, Leaf)>,
+}
+
+impl TreeDiff {
+    /// Whether the two **Trees** contain identical **Leaves**.
+    pub fn is_empty(&self) -> bool {
+        self.only_a.is_empty() & self.only_b.is_empty() & self.npoints.is_empty()
+    }
+}
+
+/// Find the **Leaves** which differ between two **Trees**, useful for diagnosing non-deterministic
+/// construction (sequential).
+pub fn diff(a: &Tree, b: &Tree) -> TreeDiff {
+    let leaves_a: HashMap<Key, Leaf> = a.values().flatten().map(|&l| (l.key, l)).collect();
+    let leaves_b: HashMap<Key, Leaf> = b.values().flatten().map(|&l| (l.key, l)).collect();
+
+    let mut result = TreeDiff::default();
+
+    for (key, &leaf_a) in leaves_a.iter() {
+        match leaves_b.get(key) {
+            Some(&leaf_b) => {
+                if leaf_a.npoints != leaf_b.npoints {
+                    result.npoints.push((leaf_a, leaf_b));
+                }
+            }
+            None => result.only_a.push(leaf_a),
+        }
+    }
+
+    for (key, &leaf_b) in leaves_b.iter() {
+        if !leaves_a.contains_key(key) {
+            result.only_b.push(leaf_b);
+        }
+    }
+
+    result.only_a.sort();
+    result.only_b.sort();
+    result.npoints.sort();
+    result
+}
+
+/// Sort the **Blocks** of a **Tree** and the **Leaves** within each block into Morton order, so
+/// that trees can be compared independently of the order in which they were built (sequential).
+pub fn canonical(tree: &Tree) -> Vec<(Key, Leaves)> {
+    let mut blocks: Vec<(Key, Leaves)> = tree
+        .iter()
+        .map(|(&block, leaves)| {
+            let mut leaves = leaves.clone();
+            leaves.sort();
+            (block, leaves)
+        })
+        .collect();
+
+    blocks.sort_by(|a, b| a.0.cmp(&b.0));
+    blocks
+}
+
+/// Test whether two **Trees** contain the same **Blocks**, with identical **Leaves** in each,
+/// irrespective of ordering (sequential).
+pub fn trees_equal(a: &Tree, b: &Tree) -> bool {
+    let a = canonical(a);
+    let b = canonical(b);
+
+    // Leaves compare by key alone, so also compare the data they carry.
+    let same_leaf =
+        |x: &Leaf, y: &Leaf| (x.key == y.key) & (x.block == y.block) & (x.npoints == y.npoints);
+
+    (a.len() == b.len())
+        & a.iter().zip(b.iter()).all(|(x, y)| {
+            (x.0 == y.0)
+                & (x.1.len() == y.1.len())
+                & x.1.iter().zip(y.1.iter()).all(|(l, m)| same_leaf(l, m))
+        })
+}
+
+mod tests {
+    use super::*;
+
+    use crate::data::random;
+    use crate::morton::{
+        find_finest_common_ancestor, find_siblings, z_index, LEVEL_BITS, MAX_POINTS,
+    };
+
+    /// Uniform tree of the eight level 1 octants, in a single block.
+    #[cfg(test)]
+    fn level_one_octree(depth: u64) -> Octree {
+        let root = Key(0, 0, 0, 0);
+        let mut tree: Tree = HashMap::new();
+        for &key in find_children(&root, &depth).iter() {
+            tree.entry(root).or_default().push(Leaf {
+                key,
+                block: root,
+                npoints: 1,
+            });
+        }
+        Octree::from_tree(tree, depth)
+    }
+
+    #[test]
+    fn test_complete_region() {
+        let a = Key(0, 0, 0, 2);
+        let b = Key(3, 3, 3, 2);
+        let depth = 2;
+        let mut result = complete_region(&a, &b, &depth);
+        let fca = find_finest_common_ancestor(&a, &b, &depth);
+
+        let min = result.iter().min().unwrap();
+        let max = result.iter().max().unwrap();
+
+        // Test that bounds are satisfied
+        assert!(a <= *min);
+        assert!(b >= *max);
+
+        // Test that FCA is an ancestor of all nodes in the result
+        for node in result.iter() {
+            let ancestors = find_ancestors(&node, &depth);
+            assert!(ancestors.contains(&fca));
+        }
+    }
+
+    #[test]
+    fn test_complete_region_adjacent() {
+        let depth = 3;
+
+        // Every octant at level 2, in Morton order
+        let mut keys: Keys = find_children(&Key(0, 0, 0, 0), &depth)
+            .iter()
+            .flat_map(|k| find_children(k, &depth))
+            .collect();
+        keys.sort();
+
+        // Test that successive octants, siblings or not, have an empty region between them
+        for pair in keys.windows(2) {
+            assert!(complete_region(&pair[0], &pair[1], &depth).is_empty());
+        }
+
+        // Test that octants with a gap between them are still completed
+        let region = complete_region(&keys[0], &keys[2], &depth);
+        assert_eq!(region, vec![keys[1]]);
+
+        // Test successive octants too deep for a packed Morton index
+        let depth = 63;
+        let a = Key(0, 0, 0, depth);
+        let b = Key(0, 0, 1, depth);
+        let c = Key(0, 1, 0, depth);
+        assert!(complete_region(&b, &c, &depth).is_empty());
+        assert_eq!(complete_region(&a, &c, &depth), vec![b]);
+    }
+
+    #[test]
+    fn test_complete_region_mixed_levels() {
+        let depth = 3;
+
+        // Position of the finest octant along the Morton curve.
+        let index = |key: &Key| z_index(key, &depth) >> LEVEL_BITS;
+
+        let pairs = [
+            (Key(0, 0, 0, 1), Key(6, 6, 6, 3)),
+            (Key(1, 0, 0, 3), Key(4, 4, 4, 1)),
+            (Key(2, 2, 2, 3), Key(4, 0, 0, 1)),
+        ];
+
+        for (a, b) in pairs.iter() {
+            let result = complete_region(a, b, &depth);
+
+            // Test that the output is sorted and bounded
+            let mut sorted = result.clone();
+            sorted.sort();
+            assert_eq!(result, sorted);
+            assert!(result.iter().all(|k| (a < k) & (k < b)));
+
+            // Test that there are no gaps between the endpoints
+            let mut region = vec![*a];
+            region.extend(result.iter());
+            region.push(*b);
+            for pair in region.windows(2) {
+                let last = find_deepest_last_descendent(&pair[0], &depth);
+                let first = find_deepest_first_descendent(&pair[1], &depth);
+                assert_eq!(index(&last) + 1, index(&first));
+            }
+        }
+    }
+
+    #[test]
+    fn test_unique() {
+        let mut leaves: Leaves = vec![
+            Leaf {
+                key: Key(0, 0, 0, 1),
+                block: Key::default(),
+                npoints: 11,
+            },
+            Leaf {
+                key: Key(0, 0, 0, 1),
+                block: Key::default(),
+                npoints: 12,
+            },
+            Leaf {
+                key: Key(0, 0, 0, 1),
+                block: Key::default(),
+                npoints: 13,
+            },
+        ];
+
+        let ncrit: usize = 50;
+
+        let unique = unique_leaves(leaves, &[], &ncrit, true).unwrap();
+
+        assert_eq!(unique[0].npoints, 36)
+    }
+
+    #[test]
+    fn test_unique_multiple_keys() {
+        let mut leaves: Leaves = vec![
+            Leaf {
+                key: Key(0, 0, 0, 1),
+                block: Key::default(),
+                npoints: 1,
+            },
+            Leaf {
+                key: Key(0, 0, 0, 1),
+                block: Key::default(),
+                npoints: 2,
+            },
+            Leaf {
+                key: Key(0, 0, 1, 1),
+                block: Key::default(),
+                npoints: 3,
+            },
+            Leaf {
+                key: Key(1, 1, 1, 1),
+                block: Key::default(),
+                npoints: 4,
+            },
+        ];
+
+        let ncrit: usize = 50;
+
+        let unique = unique_leaves(leaves, &[], &ncrit, true).unwrap();
+
+        // Test that every key survives exactly once, with its points merged
+        assert_eq!(unique.len(), 3);
+        assert_eq!(unique[0].npoints, 3);
+        assert_eq!(unique[1].npoints, 3);
+        assert_eq!(unique[2].npoints, 4);
+    }
+
+    #[test]
+    fn test_unique_leaves_empty() {
+        let ncrit: usize = 5;
+
+        // A process which receives no leaves has no unique leaves, and so no seeds
+        let unique = unique_leaves(Vec::new(), &[], &ncrit, true).unwrap();
+        assert!(unique.is_empty());
+        assert_eq!(find_seeds(&unique, &3), Err(TreeError::EmptyPartition));
+    }
+
+    #[test]
+    fn test_unique_overflow() {
+        let depth = 3;
+        let ncrit: usize = 5;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // Coincident points at the maximum depth can't be separated
+        let point = Point::new(0.3, 0.3, 0.3);
+        let mut points: Points = vec![point; ncrit + 1];
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+        let leaves = keys_to_leaves(&mut points);
+
+        let result = unique_leaves(leaves, &points, &ncrit, true);
+        assert_eq!(
+            result,
+            Err(TreeError::IrreducibleDuplicates {
+                key: points[0].key,
+                npoints: ncrit + 1
+            })
+        );
+
+        // Distinct points in the same leaf could be separated by a deeper tree
+        points[0].x = 0.31;
+        let leaves = keys_to_leaves(&mut points);
+        let result = unique_leaves(leaves, &points, &ncrit, true);
+        assert!(matches!(result, Err(TreeError::LeafOverflow { .. })));
+    }
+
+    #[test]
+    fn test_weight_sum() {
+        let weights: Weights = (0..10).map(Weight::from).collect();
+
+        let expected = weights.iter().fold(0, |acc, x| acc + x.0);
+        let result: u64 = weights.iter().sum::<Weight>().into();
+        assert_eq!(result, expected);
+
+        let result: u64 = weights.into_iter().sum::<Weight>().into();
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_split_blocks_at_depth() {
+        let depth = 2;
+        let ncrit: usize = 10;
+
+        // Duplicate points at the maximum depth, which no split can separate.
+        let key = Key(1, 2, 3, depth);
+        let mut leaves: Leaves = vec![Leaf {
+            key,
+            block: Key(0, 0, 0, 0),
+            npoints: ncrit + 1,
+        }];
+
+        let result = split_blocks(&mut leaves, &depth, &ncrit);
+        assert_eq!(
+            result.unwrap_err(),
+            TreeError::CannotSatisfyNcrit {
+                block: key,
+                npoints: ncrit + 1,
+                ncrit
+            }
+        );
+    }
+
+    #[test]
+    fn test_split_blocks_adaptive() {
+        let depth = 2;
+
+        let mut leaves: Leaves = Vec::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    leaves.push(Leaf {
+                        key: Key(i, j, k, depth),
+                        block: Key(0, 0, 0, 0),
+                        npoints: 1,
+                    });
+                }
+            }
+        }
+
+        // Higher capacity in the first level 1 octant.
+        let octant = Key(0, 0, 0, 1);
+        let ncrit = |key: &Key| {
+            if (key.3 >= 1) & (key.0 < 2) & (key.1 < 2) & (key.2 < 2) {
+                100
+            } else {
+                4
+            }
+        };
+
+        let tree = split_blocks_adaptive(&mut leaves, &depth, ncrit).unwrap();
+
+        // Test that the octant is left coarse, while the rest of the domain refines
+        assert_eq!(tree.get(&octant).unwrap().len(), 8);
+        assert_eq!(tree.len(), 1 + 7 * 8);
+        for (block, _) in tree.iter().filter(|(&b, _)| b != octant) {
+            assert_eq!(block.3, depth);
+        }
+    }
+
+    #[test]
+    fn test_leaf_count_per_block() {
+        let depth = 2;
+
+        // Leaves spread over the first two level 1 blocks.
+        let blocktree: Keys = vec![Key(0, 0, 0, 1), Key(0, 0, 2, 1)];
+        let mut leaves: Leaves = Vec::new();
+        for (i, &block) in blocktree.iter().enumerate() {
+            for &child in find_children(&block, &depth).iter().take(3 + i) {
+                leaves.push(Leaf {
+                    key: child,
+                    block,
+                    npoints: 1,
+                });
+            }
+        }
+
+        let mut tree: Tree = HashMap::new();
+        for &leaf in leaves.iter() {
+            tree.entry(leaf.block).or_default().push(leaf);
+        }
+
+        let octree = Octree::from_tree(tree, depth);
+
+        let counts = octree.leaf_count_per_block();
+        let weights = find_block_weights(&leaves, &blocktree);
+
+        for (block, weight) in blocktree.iter().zip(weights.iter()) {
+            assert_eq!(counts[block] as u64, weight.0);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let depth = 1;
+        let block = Key(0, 0, 0, 0);
+
+        let mut tree: Tree = HashMap::new();
+        for &key in find_children(&block, &depth).iter() {
+            tree.entry(block).or_default().push(Leaf {
+                key,
+                block,
+                npoints: 1,
+            });
+        }
+
+        // Test that a tree doesn't differ from itself
+        assert!(diff(&tree, &tree).is_empty());
+
+        // Test that modifications are reported
+        let mut modified = tree.clone();
+        let leaves = modified.get_mut(&block).unwrap();
+        let removed = leaves.pop().unwrap();
+        leaves[0].npoints = 2;
+
+        let result = diff(&tree, &modified);
+        assert_eq!(result.only_a, vec![removed]);
+        assert!(result.only_b.is_empty());
+        assert_eq!(result.npoints.len(), 1);
+        assert_eq!(result.npoints[0].0.npoints, 1);
+        assert_eq!(result.npoints[0].1.npoints, 2);
+    }
+
+    #[test]
+    fn test_compress() {
+        let depth = 2;
+
+        let mut tree: Tree = HashMap::new();
+        for (i, &block) in find_children(&Key(0, 0, 0, 0), &depth).iter().enumerate() {
+            for (j, &key) in find_children(&block, &depth).iter().enumerate().take(i + 1) {
+                tree.entry(block).or_default().push(Leaf {
+                    key,
+                    block,
+                    npoints: i + j,
+                });
+            }
+        }
+
+        let octree = Octree::from_tree(tree, depth);
+
+        let compressed = octree.clone().compress();
+        assert_eq!(compressed.blocks.len(), 8);
+
+        // Test that iterating over the compressed tree yields identical leaf data
+        let mut expected: Vec<(Key, Key, usize)> = octree
+            .iter_leaves()
+            .map(|l| (l.key, l.block, l.npoints))
+            .collect();
+        let mut result: Vec<(Key, Key, usize)> = compressed
+            .iter_leaves()
+            .map(|l| (l.key, l.block, l.npoints))
+            .collect();
+        expected.sort();
+        result.sort();
+        assert_eq!(result, expected);
+
+        let decompressed = compressed.decompress();
+        assert!(diff(&octree.tree, &decompressed.tree).is_empty());
+    }
+
+    #[test]
+    fn test_index_points() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let npoints = 1000;
+        let mut points = random(npoints);
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+        // Leaves at mixed levels, covering the domain
+        let mut leaves: Leaves = Vec::new();
+        for (i, &child) in find_children(&Key(0, 0, 0, 0), &depth).iter().enumerate() {
+            let keys = if i % 2 == 0 {
+                vec![child]
+            } else {
+                find_children(&child, &depth)
+            };
+            for key in keys {
+                leaves.push(Leaf {
+                    key,
+                    block: key,
+                    npoints: 0,
+                });
+            }
+        }
+
+        let indices = index_points(&leaves, &points);
+
+        // Test that each point is indexed by the leaf containing it
+        for (leaf, idxs) in indices.iter() {
+            for &idx in idxs.iter() {
+                let key = points[idx].key;
+                assert_eq!(find_ancestor_at_level(&key, &leaf.3, &depth), *leaf);
+            }
+        }
+
+        // Test that the union of indices is the full input index set
+        let mut result: Vec<usize> = indices.values().flatten().copied().collect();
+        result.sort();
+        let expected: Vec<usize> = (0..npoints as usize).collect();
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_refine_around_points() {
+        let depth = 4;
+        let ncrit: usize = 1000;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let mut points = random(1000);
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+        let mut leaves = keys_to_leaves(&mut points);
+        let root = Key(0, 0, 0, 0);
+        for leaf in leaves.iter_mut() {
+            leaf.block = root;
         }
-        if r == next_rank {
-            let (mut rec, _) = world.any_process().receive_vec::<Key>();
 
-            received_blocks.append(&mut rec)
+        let tree = split_blocks(&mut leaves, &depth, &ncrit).unwrap();
+        assert_eq!(tree.len(), 1);
+
+        let mut octree = Octree::from_tree(tree, depth);
+        let npoints = octree.n_points();
+
+        let min_level = 3;
+        let query = points[0];
+        octree
+            .refine_around_points(&[query], min_level, &depth, &ncrit)
+            .unwrap();
+
+        // Test that the block containing the query is deep enough
+        let containing: Keys = octree
+            .tree
+            .keys()
+            .filter(|b| find_ancestor_at_level(&query.key, &b.3, &depth) == **b)
+            .copied()
+            .collect();
+        assert_eq!(containing.len(), 1);
+        assert!(containing[0].3 >= min_level);
+
+        // Test that no points were lost
+        assert_eq!(octree.n_points(), npoints);
+
+        // Test that a query outside of the domain fails, leaving the tree unchanged
+        let nblocks = octree.tree.len();
+        let outside = Point::new(-0.1, 0.5, 0.5);
+        let result = octree.refine_around_points(&[query, outside], min_level, &depth, &ncrit);
+        assert!(matches!(result, Err(TreeError::OutOfDomain { .. })));
+        assert_eq!(octree.tree.len(), nblocks);
+    }
+
+    #[test]
+    fn test_find_splitters() {
+        let depth = 3;
+        let size = 4;
+
+        // Samples from every process except the last, which is empty
+        let mut rng = thread_rng();
+        let mut samples: Leaves = Vec::new();
+        for _ in 0..(size - 1) * K {
+            let anchor: Vec<u64> = (0..3).map(|_| rng.gen_range(0..8)).collect();
+            samples.push(Leaf {
+                key: Key(anchor[0], anchor[1], anchor[2], depth),
+                block: Key::default(),
+                npoints: 1,
+            });
         }
+        samples.extend(vec![Leaf::default(); K]);
+
+        let splitters = find_splitters(samples);
+
+        // Test that no sentinel keys are selected as splitters
+        assert_eq!(splitters.len(), size - 2);
+        assert!(splitters.iter().all(|s| s.key != Key::default()));
     }
 
-    // Remove sent blocks locally, and append received blocks
-    for sent in &q {
-        local_blocktree
+    #[test]
+    fn test_neighbors_of_leaf() {
+        let depth = 3;
+
+        // Balanced tree with the first level 1 octant refined one level further
+        let root = Key(0, 0, 0, 0);
+        let coarse = find_children(&root, &depth);
+        let fine = find_children(&coarse[0], &depth);
+
+        let mut tree: Tree = HashMap::new();
+        for &key in coarse[1..].iter().chain(fine.iter()) {
+            tree.entry(root).or_default().push(Leaf {
+                key,
+                block: root,
+                npoints: 1,
+            });
+        }
+
+        let octree = Octree::from_tree(tree, depth);
+
+        // Test that a fine leaf finds its coarser face neighbor
+        let key = Key(2, 0, 0, 2);
+        let neighbors = octree.neighbors_of_leaf(&key, &depth);
+        assert!(neighbors.contains(&Key(4, 0, 0, 1)));
+        assert!(!neighbors.contains(&Key(4, 0, 0, 2)));
+        assert!(neighbors.contains(&Key(0, 0, 0, 2)));
+        assert_eq!(neighbors.len(), 7 + 1);
+
+        // Test that the coarse leaf finds only the fine leaves abutting it
+        let neighbors = octree.neighbors_of_leaf(&Key(4, 0, 0, 1), &depth);
+        let finer: Keys = neighbors.iter().filter(|n| n.3 == 2).copied().collect();
+        assert_eq!(finer.len(), 4);
+        assert!(finer.iter().all(|n| n.0 == 2));
+
+        // Test that finer leaves are found beside coarser leaves elsewhere, refining the last fine
+        // octant one level further
+        let finest = find_children(&fine[7], &depth);
+        let mut tree: Tree = HashMap::new();
+        for &key in coarse[1..]
             .iter()
-            .position(|&n| n == *sent)
-            .map(|e| local_blocktree.remove(e));
+            .chain(fine[..7].iter())
+            .chain(finest.iter())
+        {
+            tree.entry(root).or_default().push(Leaf {
+                key,
+                block: root,
+                npoints: 1,
+            });
+        }
+
+        let octree = Octree::from_tree(tree, depth);
+        let neighbors = octree.neighbors_of_leaf(&fine[6], &depth);
+        let finer: Keys = neighbors.iter().filter(|n| n.3 == 3).copied().collect();
+        assert_eq!(finer.len(), 4);
+        assert!(finer.iter().all(|n| finest.contains(n)));
     }
 
-    local_blocktree.extend(&received_blocks);
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "doesn't match the depth")]
+    fn test_mixed_depths() {
+        let depth = 3;
+        let octree = level_one_octree(depth);
 
-    q
-}
+        octree.neighbors_of_leaf(&Key(4, 0, 0, 1), &(depth - 1));
+    }
 
-/// Split **Blocks** to satisfy a maximum of NCRIT particles per node in the final octree
-/// (sequential).
-pub fn split_blocks(local_leaves: &mut Leaves, depth: &u64, ncrit: &usize) -> Tree {
-    let mut blocks: Tree = HashMap::new();
+    #[test]
+    fn test_key_operations_at_tree_depth() {
+        let depth = 3;
+        let octree = level_one_octree(depth);
+
+        // Test that key operations use the depth the tree was built with
+        let key = Key(2, 2, 2, 2);
+        assert_eq!(octree.parent(&key), find_parent(&key, &depth));
+        assert_eq!(octree.children(&key), find_children(&key, &depth));
+        assert_eq!(octree.ancestors(&key), find_ancestors(&key, &depth));
+        assert_eq!(
+            octree.ancestor_at_level(&key, &1),
+            find_ancestor_at_level(&key, &1, &depth)
+        );
+        assert_ne!(octree.parent(&key), find_parent(&key, &(depth - 1)));
+    }
 
-    for &leaf in local_leaves.iter() {
-        blocks.entry(leaf.block).or_default().push(leaf);
+    #[test]
+    fn test_to_adjacency() {
+        let depth = 3;
+        let octree = level_one_octree(depth);
+
+        let (keys, edges) = octree.to_adjacency(&depth);
+        assert_eq!(keys.len(), 8);
+
+        // Test that every pair of octants is connected exactly once
+        assert_eq!(edges.len(), 28);
+        assert!(edges.iter().all(|&(i, j)| i < j));
+
+        // Test that 12 edges connect octants sharing a face, which differ along a single axis
+        let face = edges
+            .iter()
+            .filter(|&&(i, j)| {
+                let (a, b) = (keys[i], keys[j]);
+                [(a.0, b.0), (a.1, b.1), (a.2, b.2)]
+                    .iter()
+                    .filter(|(x, y)| x != y)
+                    .count()
+                    == 1
+            })
+            .count();
+        assert_eq!(face, 12);
     }
 
-    loop {
-        let mut to_split: Keys = Vec::new();
-        for (&key, leaves) in blocks.iter() {
-            let mut npoints = 0;
-            for leaf in leaves {
-                npoints += leaf.npoints;
-                if npoints > *ncrit {
-                    to_split.push(key);
-                    break;
-                }
+    #[test]
+    fn test_apply_mut() {
+        let depth = 2;
+        let mut tree: Tree = HashMap::new();
+        for &block in find_children(&Key(0, 0, 0, 0), &depth).iter() {
+            for &key in find_children(&block, &depth).iter() {
+                tree.entry(block).or_default().push(Leaf {
+                    key,
+                    block,
+                    npoints: 3,
+                });
             }
         }
 
-        if to_split.len() > 0 {
-            for &key in to_split.iter() {
-                let mut leaves = blocks.get(&key).unwrap().clone();
-                let children = find_children(&key, depth);
-                blocks.remove(&key);
-                assign_blocks_to_leaves(&mut leaves, &children, depth);
+        let mut octree = Octree::from_tree(tree, depth);
 
-                for &leaf in leaves.iter() {
-                    blocks.entry(leaf.block).or_default().push(leaf);
+        // Test that every leaf is visited with its block
+        octree.apply_mut(|block, leaf| {
+            assert_eq!(*block, leaf.block);
+            leaf.npoints = 0;
+        });
+        assert!(octree.iter_leaves().all(|l| l.npoints == 0));
+        assert_eq!(octree.iter_leaves().count(), 64);
+    }
+
+    #[test]
+    fn test_subtree() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+
+        // Two levels of leaves below each level 1 block
+        let blocks = find_children(&Key(0, 0, 0, 0), &depth);
+        let mut tree: Tree = HashMap::new();
+        for &block in blocks.iter() {
+            for &child in find_children(&block, &depth).iter() {
+                for &key in find_children(&child, &depth).iter() {
+                    tree.entry(block).or_default().push(Leaf {
+                        key,
+                        block,
+                        npoints: 1,
+                    });
                 }
             }
-        } else {
-            break;
         }
-    }
-    blocks
-}
 
-/// Perform parallelised sample sort on a distributed set of **Leaves** (parallel).
-pub fn sample_sort(
-    mut points: &mut Points,
-    size: Rank,
-    world: SystemCommunicator,
-) -> (Leaves, Points)
-{
-    let local_leaves = keys_to_leaves(&mut points);
+        let mut octree = Octree::from_tree(tree, depth);
+        octree.coarse_blocks = blocks.clone();
+
+        let root = blocks[3];
+        let subtree = octree.subtree(&root, &depth);
+
+        // Test that exactly the leaves within the octant are kept
+        let mut expected: Keys = octree.tree[&root].iter().map(|l| l.key).collect();
+        let mut result: Keys = subtree.iter_leaves().map(|l| l.key).collect();
+        expected.sort();
+        result.sort();
+        assert_eq!(result.len(), 64);
+        assert_eq!(result, expected);
+
+        // Test that the domain is preserved
+        assert_eq!(subtree.x0, octree.x0);
+        assert_eq!(subtree.r0, octree.r0);
+        assert_eq!(subtree.depth, octree.depth);
+
+        // Test that coarse blocks are kept inside of the root, and clipped to it otherwise
+        assert_eq!(subtree.coarse_blocks(), &[root]);
+        let finer = find_children(&root, &depth)[2];
+        let finer_subtree = octree.subtree(&finer, &depth);
+        assert_eq!(finer_subtree.coarse_blocks(), &[finer]);
+
+        // Test that leaves of blocks containing the root are reassigned to the root
+        assert_eq!(finer_subtree.iter_leaves().count(), 8);
+        assert!(finer_subtree.iter_leaves().all(|l| l.block == finer));
+        assert_eq!(finer_subtree.tree.keys().collect::<Vec<_>>(), vec![&finer]);
+    }
 
-    let mut received_samples = vec![Leaf::default(); K * (size as usize)];
-    let nleaves = local_leaves.len();
+    #[test]
+    fn test_find_block_weights() {
+        let depth = 4;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
 
-    // 1. Collect 'K' samples from each process onto all other processes
-    let mut rng = thread_rng();
-    let sample_idxs: Vec<usize> = (0..K).map(|_| rng.gen_range(0..nleaves)).collect();
+        let mut points = random(1000);
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+        let mut leaves = keys_to_leaves(&mut points);
 
-    let mut local_samples: Leaves = vec![Leaf::default(); K];
+        // Blocks at level 2, and a coarser block which no leaf is assigned to
+        let mut blocktree: Keys = find_children(&Key(0, 0, 0, 0), &depth)
+            .iter()
+            .flat_map(|b| find_children(b, &depth))
+            .collect();
+        blocktree.sort();
+        assign_blocks_to_leaves(&mut leaves, &blocktree, &depth);
+        blocktree.push(Key(0, 0, 0, 1));
+
+        // Test that the counts match a scan of the leaves for each block
+        let weights = find_block_weights(&leaves, &blocktree);
+        for (block, weight) in blocktree.iter().zip(weights.iter()) {
+            let expected = leaves.iter().filter(|&l| l.block == *block).count() as u64;
+            assert_eq!(weight.0, expected);
+        }
+        assert_eq!(weights.last().unwrap().0, 0);
 
-    for (i, &sample_idx) in sample_idxs.iter().enumerate() {
-        local_samples[i] = local_leaves[sample_idx].clone();
+        let total: u64 = weights.iter().sum::<Weight>().into();
+        assert_eq!(total, leaves.len() as u64);
     }
 
-    world.all_gather_into(&local_samples[..], &mut received_samples[..]);
-
-    // Ignore first K samples to ensure (nproc-1) splitters
-    received_samples.sort();
+    #[test]
+    fn test_linearise() {
+        let key = Key(0, 0, 0, 1);
+        let depth = 2;
+        let mut children = find_children(&key, &depth);
+        children.insert(0, key);
 
-    received_samples = received_samples[K..].to_vec();
+        let linearised = linearise(&mut children, &depth);
 
-    // Every K'th sample defines a bucket.
-    let splitters: Leaves = received_samples.iter().step_by(K).cloned().collect();
-    let nsplitters = splitters.len();
+        assert!(!linearised.contains(&key));
 
-    // 2. Sort local leaves into buckets
-    let mut buckets_leaves: Vec<Leaves> = vec![Vec::new(); size as usize];
+        // Test that every child is kept, including the last, which has no successor
+        assert_eq!(linearised, find_children(&key, &depth));
+        assert!(linearise(&mut Vec::new(), &depth).is_empty());
+    }
 
-    // Sort local points into corresponding buckets
-    let mut buckets_points: Vec<Points> = vec![Vec::new(); size as usize];
+    #[test]
+    fn test_trees_equal() {
+        use rand::seq::SliceRandom;
 
-    for leaf in local_leaves.iter() {
-        for i in 0..(size as usize) {
-            if i < nsplitters {
-                let s = &splitters[i];
-                if leaf < s {
-                    buckets_leaves[i].push(leaf.clone());
-                    break;
-                }
-            } else {
-                buckets_leaves[i].push(leaf.clone())
+        let depth = 2;
+        let mut tree: Tree = HashMap::new();
+        for &block in find_children(&Key(0, 0, 0, 0), &depth).iter() {
+            for (i, &key) in find_children(&block, &depth).iter().enumerate() {
+                tree.entry(block).or_default().push(Leaf {
+                    key,
+                    block,
+                    npoints: i,
+                });
             }
         }
-    }
 
-    for point in points.iter() {
-        for i in 0..(size as usize) {
-            if i < nsplitters {
-                let s = &splitters[i];
-                if point.key < s.key {
-                    buckets_points[i].push(point.clone());
-                    break;
-                }
-            } else {
-                buckets_points[i].push(point.clone())
-            }
+        // Test that a tree equals a copy with its leaves shuffled
+        let mut rng = thread_rng();
+        let mut shuffled: Tree = HashMap::new();
+        for (&block, leaves) in tree.iter() {
+            let mut leaves = leaves.clone();
+            leaves.shuffle(&mut rng);
+            shuffled.insert(block, leaves);
         }
-    }
+        assert!(trees_equal(&tree, &shuffled));
+        assert_eq!(canonical(&tree).len(), 8);
 
-    // 3. Send all local buckets to their matching processor.
+        // Test that differing leaf data is detected
+        let leaves = shuffled.values_mut().next().unwrap();
+        leaves[0].npoints += 1;
+        assert!(!trees_equal(&tree, &shuffled));
+    }
 
-    let mut received_leaves = all_to_all(world, size, buckets_leaves);
-    let received_points = all_to_all(world, size, buckets_points);
+    #[test]
+    fn test_traversals() {
+        let depth = 3;
+
+        // Refine the first level 1 block, keeping the rest as leaves
+        let blocks = find_children(&Key(0, 0, 0, 0), &depth);
+        let mut tree: Tree = HashMap::new();
+        for &key in find_children(&blocks[0], &depth).iter() {
+            tree.entry(blocks[0]).or_default().push(Leaf {
+                key,
+                block: blocks[0],
+                npoints: 1,
+            });
+        }
+        for &block in blocks[1..].iter() {
+            tree.entry(block).or_default().push(Leaf {
+                key: block,
+                block,
+                npoints: 1,
+            });
+        }
 
-    // 4. Sort leaves on matching processors.
-    received_leaves.sort();
-    (received_leaves, received_points)
-}
+        let octree = Octree::from_tree(tree, depth);
 
-fn all_to_all<T:>(
-    world: SystemCommunicator,
-    size: Rank,
-    buckets: Vec<Vec<T>>) -> Vec<T>
-where T: Default+Clone+Equivalence
-{
+        let dfs: Vec<(Key, u64)> = octree.iter_depth_first().collect();
+        let bfs: Vec<(Key, u64)> = octree.iter_breadth_first().collect();
 
-    let mut counts_snd: Vec<Count> = vec![0; size as usize];
+        // Test that both visit the root, the blocks and the refined leaves exactly once
+        assert_eq!(dfs.len(), 17);
+        assert_eq!(bfs.len(), 17);
+        assert_eq!(dfs[0], (Key(0, 0, 0, 0), 0));
+        assert!(dfs.iter().all(|(key, level)| key.3 == *level));
 
-    for (i, bucket) in buckets.iter().enumerate() {
-        counts_snd[i] = bucket.len() as Count;
-    }
+        // Test that depth first visits a parent before its children
+        for (i, (key, _)) in dfs.iter().enumerate().skip(1) {
+            let parent = find_parent(key, &depth);
+            let position = dfs.iter().position(|(k, _)| *k == parent).unwrap();
+            assert!(position < i);
+        }
 
-    // Flatten buckets, after bucketing
-    let buckets_flat: Vec<T> = buckets.into_iter().flatten().collect();
+        // Test that the children of the refined block directly follow it
+        let position = dfs.iter().position(|(k, _)| *k == blocks[0]).unwrap();
+        assert!(dfs[position + 1..position + 9]
+            .iter()
+            .all(|(k, _)| find_parent(k, &depth) == blocks[0]));
 
-    let displs_snd: Vec<Count> = counts_snd
-        .iter()
-        .scan(0, |acc, &x| {
-            let tmp = *acc;
-            *acc += x;
-            Some(tmp)
-        })
-        .collect();
+        // Test that breadth first visits all nodes at a level before the next level
+        for window in bfs.windows(2) {
+            assert!(window[0].1 <= window[1].1);
+        }
+    }
 
-    // All to All for bucket sizes
-    let mut counts_recv: Vec<Count> = vec![0; size as usize];
+    #[test]
+    fn test_find_seeds_empty_partition() {
+        let depth = 3;
+        assert_eq!(find_seeds(&[], &depth), Err(TreeError::EmptyPartition));
+    }
 
-    world.all_to_all_into(&counts_snd[..], &mut counts_recv[..]);
+    #[test]
+    fn test_leaf_at() {
+        let depth = 3;
+
+        // Refine the first level 1 block, keeping the rest as leaves
+        let blocks = find_children(&Key(0, 0, 0, 0), &depth);
+        let mut tree: Tree = HashMap::new();
+        for &key in find_children(&blocks[0], &depth).iter() {
+            tree.entry(blocks[0]).or_default().push(Leaf {
+                key,
+                block: blocks[0],
+                npoints: 1,
+            });
+        }
+        for &block in blocks[1..].iter() {
+            tree.entry(block).or_default().push(Leaf {
+                key: block,
+                block,
+                npoints: 1,
+            });
+        }
 
-    // displacements
-    let displs_recv: Vec<Count> = counts_recv
-        .iter()
-        .scan(0, |acc, &x| {
-            let tmp = *acc;
-            *acc += x;
-            Some(tmp)
-        })
-        .collect();
+        let octree = Octree::from_tree(tree, depth);
 
-    // Allocate a buffer to receive relevant data from all processes.
-    let total: Count = counts_recv.iter().sum();
-    let mut received = vec![T::default(); total as usize];
-    let mut partition_receive = PartitionMut::new(&mut received[..], counts_recv, &displs_recv[..]);
+        // Test that coordinates resolve to leaves at either level
+        let fine = octree.leaf_at(0.3, 0.1, 0.3).unwrap();
+        assert_eq!(fine.key, Key(2, 0, 2, 2));
+        assert_eq!(fine.block, blocks[0]);
 
-    // Allocate a partition of the data to send to each process
-    let partition_snd = Partition::new(&buckets_flat[..], counts_snd, &displs_snd[..]);
+        let coarse = octree.leaf_at(0.9, 0.9, 0.1).unwrap();
+        assert_eq!(coarse.key, Key(4, 4, 0, 1));
 
-    world.all_to_all_varcount_into(&partition_snd, &mut partition_receive);
+        // Test that coordinates outside of the domain aren't found
+        assert!(octree.leaf_at(1.5, 0.5, 0.5).is_none());
+        assert!(octree.leaf_at(-0.1, 0.5, 0.5).is_none());
+    }
 
-    received
-}
+    #[test]
+    fn test_export_points_with_owner() {
+        let depth = 3;
+        let rank: Rank = 2;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // Refine the first level 1 block, and leave the last to another process
+        let blocks = find_children(&Key(0, 0, 0, 0), &depth);
+        let mut tree: Tree = HashMap::new();
+        for &key in find_children(&blocks[0], &depth).iter() {
+            tree.entry(blocks[0]).or_default().push(Leaf {
+                key,
+                block: blocks[0],
+                npoints: 1,
+            });
+        }
+        for &block in blocks[1..7].iter() {
+            tree.entry(block).or_default().push(Leaf {
+                key: block,
+                block,
+                npoints: 1,
+            });
+        }
 
+        let octree = Octree::from_tree(tree, depth);
 
+        let points = random(1000);
+        let exported = octree.export_points_with_owner(&points, rank);
 
-/// Generate a distributed unbalanced tree from a set of distributed points.
-pub fn unbalanced_tree(
-    depth: &u64,
-    ncrit: &usize,
-    universe: &Universe,
-    mut points: &mut Points,
-    x0: Point,
-    r0: f64,
-) -> (Tree, Times) {
-    let world = universe.world();
-    let rank = world.rank();
-    let size = world.size();
+        // Test that every exported point's leaf is present in the local tree, and contains it
+        for (p, leaf, block, owner) in exported.iter() {
+            assert!(octree.tree[block].iter().any(|l| l.key == *leaf));
+            let key = key_from_coords(p.x, p.y, p.z, &leaf.3, &depth, &x0, &r0);
+            assert_eq!(key, *leaf);
+            assert_eq!(*owner, rank);
+        }
 
-    let mut time: Times = HashMap::new();
+        // Test that exactly the points in the block held elsewhere are skipped
+        let missing = points
+            .iter()
+            .filter(|p| key_from_coords(p.x, p.y, p.z, &1, &depth, &x0, &r0) == blocks[7])
+            .count();
+        assert_eq!(exported.len() + missing, points.len());
+    }
 
-    // 1. Encode points to leaf keys inplace.
-    let sim_start  = Instant::now();
-    encode_points(&mut points, &depth, &depth, &x0, &r0);
-    time.insert("encoding".to_string(), sim_start.elapsed().as_millis());
+    #[test]
+    fn test_points_bounding_box() {
+        let depth = 3;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
 
-    // 2. Perform parallel Morton sort over points
-    let start = Instant::now();
-    let (mut sorted_leaves, mut sorted_points) = sample_sort(
-        &mut points,
-        size,
-        world,
-    );
-    time.insert("sorting".to_string(), start.elapsed().as_millis());
+        let mut points: Points = random(500)
+            .iter()
+            .map(|p| Point::new(0.2 + 0.6 * p.x, 0.2 + 0.6 * p.y, 0.2 + 0.6 * p.z))
+            .collect();
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
 
-    let points = sorted_points;
-    let local_leaves = sorted_leaves;
+        let mut octree = Octree::from_tree(HashMap::new(), depth);
+        octree.points = points;
 
-    let start = Instant::now();
-    // 3. Remove duplicates at each processor and remove overlaps if there are any
-    let local_leaves = unique_leaves(local_leaves, ncrit, true);
-    time.insert("overlap".to_string(), start.elapsed().as_millis());
+        // Test that the box lies within the extent of the points, tighter than the domain cube
+        let (lower, upper) = octree.points_bounding_box().unwrap();
+        for c in [lower.x, lower.y, lower.z, upper.x, upper.y, upper.z].iter() {
+            assert!((0.2..=0.8).contains(c));
+        }
+        assert!((lower.x < upper.x) & (lower.y < upper.y) & (lower.z < upper.z));
 
-    // 4.i Complete minimal tree on each process, and find seed octants.
-    let start = Instant::now();
-    let mut seeds = find_seeds(&local_leaves, depth);
-
-    // 4.ii If leaf is less than the minimum seed in a given process, it needs to be sent to the
-    // previous process
-
-    // Temporary buffers to receive points and leaves to coarse block tree.
-    let mut received_leaves: Leaves = Vec::new();
-    let mut received_points: Points = Vec::new();
-
-    transfer_leaves_to_coarse_blocktree(
-        &points,
-        &local_leaves,
-        &mut received_points,
-        &mut received_leaves,
-        &seeds,
-        rank,
-        world,
-        size,
-    );
-    time.insert("seed".to_string(), start.elapsed().as_millis());
+        // Test that the box contains every point
+        for p in octree.points.iter() {
+            assert!((lower.x <= p.x) & (lower.y <= p.y) & (lower.z <= p.z));
+            assert!((p.x <= upper.x) & (p.y <= upper.y) & (p.z <= upper.z));
+        }
 
-    let mut local_leaves = received_leaves;
-    let points = received_points;
+        octree.points.clear();
+        assert!(octree.points_bounding_box().is_none());
+    }
 
-    // 5. Complete minimal block-tree across processes
-    let start = Instant::now();
-    let mut local_blocktree = complete_blocktree(&mut seeds, depth, rank, size, world);
-    time.insert("minimal_block_tree".to_string(), start.elapsed().as_millis());
+    #[test]
+    fn test_closest_points() {
+        let depth = 3;
+        let k = 5;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        let mut points = random(500);
+        encode_points(&mut points, &depth, &depth, &x0, &r0);
+
+        // Each leaf is its own block, and the points are sorted by leaf
+        let mut tree: Tree = HashMap::new();
+        for leaf in keys_to_leaves(&mut points).iter() {
+            tree.entry(leaf.key).or_default().push(Leaf {
+                block: leaf.key,
+                ..*leaf
+            });
+        }
 
-    // Associate leaves with blocks
-    let start = Instant::now();
-    assign_blocks_to_leaves(&mut local_leaves, &local_blocktree, depth);
-    time.insert("block_assignment".to_string(), start.elapsed().as_millis());
+        let mut octree = Octree::from_tree(tree, depth);
+        octree.points = points.clone();
 
-    // 6. Split blocks into adaptive tree, and pass into Octree structure.
-    let start = Instant::now();
-    let nodes = split_blocks(&mut local_leaves, depth, ncrit);
-    time.insert("block_splitting".to_string(), start.elapsed().as_millis());
+        let distance = |a: &Point, b: &Point| {
+            ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+        };
 
-    // Record simulation time
-    time.insert("total".to_string(), sim_start.elapsed().as_millis());
+        // Test against a brute force search, including queries outside of the domain
+        let queries = [
+            Point::new(0.5, 0.5, 0.5),
+            Point::new(0.01, 0.99, 0.3),
+            Point::new(0.9, 0.1, 0.7),
+            Point::new(1.5, -0.5, 0.5),
+        ];
 
-    (nodes, time)
-}
+        for query in queries.iter() {
+            let mut expected: Vec<(usize, f64)> = points
+                .iter()
+                .map(|p| (p.global_idx, distance(p, query)))
+                .collect();
+            expected.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            expected.truncate(k);
 
-mod tests {
-    use super::*;
+            let result = octree.closest_points(query, k);
+            assert_eq!(result, expected);
+        }
 
-    use crate::data::random;
-    use crate::morton::{find_finest_common_ancestor, find_siblings, Point, MAX_POINTS};
+        // Test that the points are kept through compression
+        let decompressed = octree.clone().compress().decompress();
+        assert_eq!(
+            decompressed.closest_points(&queries[1], k),
+            octree.closest_points(&queries[1], k)
+        );
+
+        // Test that no points are found when none are requested, or for a NaN query
+        assert!(octree.closest_points(&queries[0], 0).is_empty());
+        let nan = Point::new(f64::NAN, 0.5, 0.5);
+        assert!(octree.closest_points(&nan, k).is_empty());
+    }
 
     #[test]
-    fn test_complete_region() {
-        let a = Key(0, 0, 0, 2);
-        let b = Key(3, 3, 3, 2);
-        let depth = 2;
-        let mut result = complete_region(&a, &b, &depth);
-        let fca = find_finest_common_ancestor(&a, &b, &depth);
+    fn test_merge_small_leaves() {
+        let depth = 3;
+        let ncrit = 10;
+
+        // Refine the first two level 1 blocks, keeping the rest as leaves
+        let blocks = find_children(&Key(0, 0, 0, 0), &depth);
+        let mut tree: Tree = HashMap::new();
+        for (i, &parent) in blocks[..2].iter().enumerate() {
+            for &block in find_children(&parent, &depth).iter() {
+                tree.entry(block).or_default().push(Leaf {
+                    key: block,
+                    block,
+                    npoints: i + 1,
+                });
+            }
+        }
+        for &block in blocks[2..].iter() {
+            tree.entry(block).or_default().push(Leaf {
+                key: block,
+                block,
+                npoints: 1,
+            });
+        }
 
-        let min = result.iter().min().unwrap();
-        let max = result.iter().max().unwrap();
+        let mut octree = Octree::from_tree(tree, depth);
+        let nleaves = octree.iter_leaves().count();
 
-        // Test that bounds are satisfied
-        assert!(a <= *min);
-        assert!(b >= *max);
+        octree.merge_small_leaves(&ncrit, &depth);
 
-        // Test that FCA is an ancestor of all nodes in the result
-        for node in result.iter() {
-            let ancestors = find_ancestors(&node, &depth);
-            assert!(ancestors.contains(&fca));
+        // Test that the eight siblings with 8 points merge into their parent
+        assert!(octree.tree.contains_key(&blocks[0]));
+        assert_eq!(octree.tree[&blocks[0]].len(), 8);
+        assert!(octree.tree[&blocks[0]].iter().all(|l| l.block == blocks[0]));
+
+        // Test that the siblings with 16 points, and the incomplete level 1 set, don't merge
+        assert!(!octree.tree.contains_key(&blocks[1]));
+        for child in find_children(&blocks[1], &depth).iter() {
+            assert!(octree.tree.contains_key(child));
         }
+        assert!(!octree.tree.contains_key(&Key(0, 0, 0, 0)));
+        assert_eq!(octree.tree.len(), 1 + 8 + 6);
+        assert_eq!(octree.iter_leaves().count(), nleaves);
     }
 
     #[test]
-    fn test_unique() {
-        let mut leaves: Leaves = vec![
-            Leaf {
-                key: Key(0, 0, 0, 1),
-                block: Key::default(),
-                npoints: 11,
-            },
-            Leaf {
-                key: Key(0, 0, 0, 1),
-                block: Key::default(),
-                npoints: 12,
-            },
-            Leaf {
-                key: Key(0, 0, 0, 1),
-                block: Key::default(),
-                npoints: 13,
-            },
-        ];
+    fn test_merge_small_leaves_coarse_blocks() {
+        let depth = 3;
+        let ncrit = 100;
+
+        // Every level 2 block holds a leaf, below the level 1 coarse blocks
+        let coarse = find_children(&Key(0, 0, 0, 0), &depth);
+        let mut tree: Tree = HashMap::new();
+        for parent in coarse.iter() {
+            for &block in find_children(parent, &depth).iter() {
+                tree.entry(block).or_default().push(Leaf {
+                    key: block,
+                    block,
+                    npoints: 1,
+                });
+            }
+        }
 
-        let ncrit: usize = 50;
+        let mut octree = Octree::from_tree(tree, depth);
+        octree.coarse_blocks = coarse.clone();
 
-        let unique = unique_leaves(leaves, &ncrit, true);
+        octree.merge_small_leaves(&ncrit, &depth);
 
-        assert_eq!(unique[0].npoints, 36)
+        // Test that blocks merge up to the coarse blocks, but not into the root above them
+        let mut blocks: Keys = octree.tree.keys().copied().collect();
+        blocks.sort();
+        assert_eq!(blocks, coarse);
+        assert_eq!(octree.iter_leaves().count(), 64);
     }
 
     #[test]
-    fn test_linearise() {
-        let key = Key(0, 0, 0, 1);
+    fn test_local_leaves() {
+        let depth = 3;
+        let ncrit = 64;
+
+        // Each level 1 block holds its eight children as leaves
+        let mut tree: Tree = HashMap::new();
+        for &block in find_children(&Key(0, 0, 0, 0), &depth).iter() {
+            for &key in find_children(&block, &depth).iter() {
+                tree.entry(block).or_default().push(Leaf {
+                    key,
+                    block,
+                    npoints: 1,
+                });
+            }
+        }
+
+        let mut octree = Octree::from_tree(tree, depth);
+        assert!(octree.local_leaves().is_none());
+        octree.keep_sorted_leaves();
+
+        // Test that the leaves are sorted, and that there is one for each leaf in the blocks
+        let leaves = octree.local_leaves().unwrap();
+        assert!(leaves.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(leaves.len(), octree.n_leaves());
+        assert_eq!(octree.n_leaves(), 64);
+
+        // Test that the sorted leaves follow the blocks when they are merged
+        octree.merge_small_leaves(&ncrit, &depth);
+        let leaves = octree.local_leaves().unwrap();
+        assert!(leaves.windows(2).all(|w| w[0] < w[1]));
+        assert!(leaves.iter().all(|l| l.block == Key(0, 0, 0, 0)));
+
+        // Test that the sorted leaves are carried through subtrees and compression
+        let root = find_children(&Key(0, 0, 0, 0), &depth)[0];
+        let subtree = octree.subtree(&root, &depth);
+        assert_eq!(subtree.local_leaves().unwrap().len(), subtree.n_leaves());
+
+        let decompressed = octree.clone().compress().decompress();
+        assert_eq!(
+            decompressed.local_leaves().unwrap(),
+            octree.local_leaves().unwrap()
+        );
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn test_attach_field() {
         let depth = 2;
-        let mut children = find_children(&key, &depth);
-        children.insert(0, key);
+        let mut tree: Tree = HashMap::new();
+        for &key in find_children(&Key(0, 0, 0, 0), &depth).iter() {
+            tree.entry(key).or_default().push(Leaf {
+                key,
+                block: key,
+                npoints: 1,
+            });
+        }
 
-        let linearised = linearise(&mut children, &depth);
+        let mut octree = Octree::from_tree(tree, depth);
+
+        // Test that a field with a value per leaf is read back unchanged
+        let values = Array1::linspace(0., 7., 8);
+        octree.attach_field("potential", values.clone()).unwrap();
+        assert_eq!(octree.field("potential").unwrap(), values.view());
+        assert!(octree.field("density").is_none());
+
+        // Test that a field without a value per leaf is rejected
+        let result = octree.attach_field("density", Array1::zeros(7));
+        assert_eq!(
+            result,
+            Err(TreeError::FieldSize {
+                name: "density".to_string(),
+                expected: 8,
+                found: 7,
+            })
+        );
+        assert!(octree.field("density").is_none());
+
+        // Test that fields are carried into a subtree, and dropped once the leaves are merged
+        let mut keys: Keys = octree.iter_leaves().map(|l| l.key).collect();
+        keys.sort();
+        let subtree = octree.subtree(&keys[3], &depth);
+        assert_eq!(
+            subtree.field("potential").unwrap(),
+            Array1::from(vec![3.]).view()
+        );
+
+        octree.merge_small_leaves(&8, &depth);
+        assert!(octree.field("potential").is_none());
+    }
 
-        assert!(!linearised.contains(&key));
+    #[test]
+    fn test_compress_levels() {
+        let depth = 4;
+        let level = 2;
+        let x0 = Point::new(0.5, 0.5, 0.5);
+        let r0 = 0.5;
+
+        // A lattice with a point at the centre of every octant at the level
+        let n = 1 << level;
+        let mut points: Points = Vec::new();
+        for i in 0..n {
+            for j in 0..n {
+                for k in 0..n {
+                    let centre = |c: u64| (c as f64 + 0.5) / (n as f64);
+                    points.push(Point::new(centre(i), centre(j), centre(k)));
+                }
+            }
+        }
+        encode_points(&mut points, &level, &depth, &x0, &r0);
+
+        let mut tree: Tree = HashMap::new();
+        for mut leaf in keys_to_leaves(&mut points).into_iter() {
+            leaf.block = leaf.key;
+            tree.insert(leaf.key, vec![leaf]);
+        }
+
+        let octree = Octree::from_tree(tree, depth);
+
+        // Test that the lattice is detected as uniform at its level
+        assert_eq!(octree.is_uniform(), Some(level));
+        let uniform = octree.clone().compress_levels().unwrap();
+        assert_eq!(uniform.n_leaves(), 64);
+
+        // Test that queries agree with the full representation
+        assert_eq!(uniform.n_points(), octree.n_points());
+        let (x, y, z) = (0.3, 0.8, 0.1);
+        assert_eq!(
+            uniform.leaf_at(x, y, z).map(|l| l.key),
+            octree.leaf_at(x, y, z).map(|l| l.key)
+        );
+        assert!(trees_equal(&uniform.decompress().tree, &octree.tree));
+
+        // Test that refining a single leaf makes the tree non uniform
+        let mut refined = octree.clone();
+        let key = Key(0, 0, 0, level);
+        refined.tree.remove(&key);
+        for child in find_children(&key, &depth) {
+            refined.tree.insert(
+                child,
+                vec![Leaf {
+                    key: child,
+                    block: child,
+                    npoints: 1,
+                }],
+            );
+        }
+        assert_eq!(refined.is_uniform(), None);
+        assert!(refined.compress_levels().is_err());
     }
 }